@@ -0,0 +1,98 @@
+// Throughput and round-trip latency over loopback.
+//
+//   cargo bench -p bench
+//   cargo bench -p bench -- latency/turbo
+
+use bench::{block_on, Pair, PAYLOAD_SIZES, PRESETS};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+// Messages written per iteration of the throughput benchmark, enough to keep
+// the send window busy.
+const BATCH: usize = 64;
+
+fn throughput(c: &mut Criterion) {
+  let mut group = c.benchmark_group("throughput");
+  group.measurement_time(Duration::from_secs(3));
+
+  for preset in PRESETS {
+    let pair = Pair::connect(bench::preset(preset));
+    for size in PAYLOAD_SIZES {
+      let payload = vec![7u8; size];
+      group.throughput(Throughput::Bytes((size * BATCH) as u64));
+      group.bench_with_input(BenchmarkId::new(preset, size), &payload, |b, payload| {
+        b.iter(|| block_on(pair.stream(payload, BATCH)))
+      });
+    }
+  }
+  group.finish();
+}
+
+fn latency(c: &mut Criterion) {
+  let mut group = c.benchmark_group("latency");
+  group.measurement_time(Duration::from_secs(3));
+
+  for preset in PRESETS {
+    let pair = Pair::connect(bench::preset(preset));
+    for size in PAYLOAD_SIZES {
+      let payload = vec![7u8; size];
+      group.bench_with_input(BenchmarkId::new(preset, size), &payload, |b, payload| {
+        b.iter(|| block_on(pair.round_trip(payload)))
+      });
+    }
+  }
+  group.finish();
+}
+
+// The same round trip through tokio_kcp directly and through the exported
+// functions, which spawn every call onto the runtime and look the stream up
+// in a manager.
+fn ffi_overhead(c: &mut Criterion) {
+  let mut group = c.benchmark_group("ffi_overhead");
+  let params = bench::preset("turbo");
+  let payload = vec![7u8; 64];
+
+  let rt = Runtime::new().unwrap();
+  let config: KcpConfig = params.clone().into();
+  // The listener ends its sessions when dropped.
+  let (_listener, mut client, mut server) = rt.block_on(async {
+    let mut listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut client = KcpStream::connect(&config, addr).await.unwrap();
+    client.write_all(&[0]).await.unwrap();
+    let (mut server, _) = listener.accept().await.unwrap();
+    server.read_exact(&mut [0]).await.unwrap();
+    (listener, client, server)
+  });
+  group.bench_function("tokio_kcp", |b| {
+    let mut buf = vec![0u8; payload.len()];
+    b.iter(|| {
+      rt.block_on(async {
+        client.write_all(&payload).await.unwrap();
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(&buf).await.unwrap();
+        client.read_exact(&mut buf).await.unwrap();
+      })
+    })
+  });
+
+  let pair = Pair::connect(params);
+  group.bench_function("bindings", |b| {
+    b.iter(|| block_on(pair.round_trip(&payload)))
+  });
+
+  // A call which is spawned but does no I/O, and one which isn't spawned.
+  group.bench_function("flush_stream", |b| {
+    b.iter(|| block_on(bindings::flush_stream(pair.client)).unwrap())
+  });
+  group.bench_function("get_stream_count", |b| {
+    b.iter(|| block_on(bindings::get_stream_count()))
+  });
+  group.finish();
+}
+
+criterion_group!(benches, throughput, latency, ffi_overhead);
+criterion_main!(benches);
@@ -0,0 +1,111 @@
+//! Helpers for the benchmarks in `benches/`.
+//!
+//! Calls go through the exported functions and are driven by a plain
+//! executor, the way the Swift side drives them, so the cost of spawning
+//! every call onto the bindings' runtime is part of what's measured.
+
+use bindings::{KcpConfigParams, StreamId};
+use std::future::Future;
+use std::sync::Once;
+
+/// Presets compared by the benchmarks.
+pub const PRESETS: [&str; 3] = ["default", "fast3", "turbo"];
+
+/// Payload sizes in bytes compared by the benchmarks.
+pub const PAYLOAD_SIZES: [usize; 3] = [64, 1024, 16 * 1024];
+
+pub fn block_on<F: Future>(future: F) -> F::Output {
+  futures::executor::block_on(future)
+}
+
+/// Starts the bindings' runtime once per process.
+pub fn init() {
+  static INIT: Once = Once::new();
+  INIT.call_once(|| block_on(bindings::init_runtime()).unwrap());
+}
+
+pub fn preset(name: &str) -> KcpConfigParams {
+  bindings::kcp_config_preset(name.to_string()).unwrap()
+}
+
+/// A listener on loopback, a stream connected to it and the accepted stream.
+/// Removed on drop.
+pub struct Pair {
+  pub listener: StreamId,
+  pub client: StreamId,
+  pub server: StreamId,
+}
+
+impl Pair {
+  pub fn connect(params: KcpConfigParams) -> Self {
+    init();
+    block_on(async {
+      let listener = bindings::new_listener("127.0.0.1:0".to_string(), params.clone())
+        .await
+        .unwrap();
+      let addr = bindings::local_addr(listener).await.unwrap();
+      let client = bindings::new_stream(addr, params).await.unwrap();
+
+      // The listener learns of a session from its first packet.
+      bindings::write_stream(client, vec![0]).await.unwrap();
+      let server = bindings::accepet(listener).await.unwrap().id;
+      bindings::read_exact_stream(server, 1).await.unwrap();
+
+      Pair {
+        listener,
+        client,
+        server,
+      }
+    })
+  }
+
+  /// Sends `payload` from the client and reads it on the server.
+  pub async fn one_way(&self, payload: &[u8]) {
+    bindings::write_stream(self.client, payload.to_vec())
+      .await
+      .unwrap();
+    bindings::read_exact_stream(self.server, payload.len() as u32)
+      .await
+      .unwrap();
+  }
+
+  /// Sends `payload` `count` times from the client while the server reads.
+  pub async fn stream(&self, payload: &[u8], count: usize) {
+    let write = async {
+      for _ in 0..count {
+        bindings::write_stream(self.client, payload.to_vec())
+          .await
+          .unwrap();
+      }
+    };
+    let read = async {
+      for _ in 0..count {
+        bindings::read_exact_stream(self.server, payload.len() as u32)
+          .await
+          .unwrap();
+      }
+    };
+    futures::join!(write, read);
+  }
+
+  /// Sends `payload` from the client and echoes it back from the server.
+  pub async fn round_trip(&self, payload: &[u8]) {
+    self.one_way(payload).await;
+    bindings::write_stream(self.server, payload.to_vec())
+      .await
+      .unwrap();
+    bindings::read_exact_stream(self.client, payload.len() as u32)
+      .await
+      .unwrap();
+  }
+}
+
+impl Drop for Pair {
+  fn drop(&mut self) {
+    block_on(async {
+      let _ = bindings::remove_stream(self.client).await;
+      let _ = bindings::remove_stream(self.server).await;
+      let _ = bindings::remove_listener(self.listener).await;
+    });
+  }
+}
@@ -0,0 +1,356 @@
+use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_kcp::{KcpConfig, KcpSessionStats};
+
+/// How often the session is sampled, see `AdaptivePacing::sample`.
+pub const SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+// Share of a period writes may wait for kcp's send window before the rate is
+// lowered, a full send queue only adds latency.
+const STALL_HIGH: f64 = 0.5;
+// Share of the segments sent in a period that may be retransmissions before
+// the rate is lowered.
+const RETRANSMIT_HIGH: f64 = 0.05;
+// The rate is lowered when the smoothed RTT grew to this multiple of the
+// smallest one while the send queue is filled up to `QUEUE_HIGH`.
+const RTT_INFLATION_HIGH: f64 = 2.0;
+const QUEUE_HIGH: f64 = 0.5;
+// RTTs below this are counted as this, a few milliseconds of jitter on a
+// local network isn't queueing.
+const RTT_FLOOR_MILLISEC: u32 = 10;
+// Share of a period writes may wait for the pacing rate before it's raised.
+const THROTTLE_HIGH: f64 = 0.1;
+const DECREASE_FACTOR: f64 = 0.85;
+// The rate is raised by this share of the upper bound per period.
+const INCREASE_STEP: f64 = 0.05;
+// The send window is kept at twice the bandwidth-delay product of the rate,
+// and at least this many segments.
+const MIN_SEND_WINDOW: u16 = 32;
+// Extra duplicate ACKs before a fast resend when the RTT varies by more than
+// half of it, reordering then looks like loss.
+const JITTER_RESEND_EXTRA: u32 = 2;
+
+#[derive(uniffi::Enum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AdaptiveDecision {
+  #[default]
+  Hold,
+  Increase,
+  Decrease,
+}
+
+#[derive(uniffi::Record, Debug, Clone, Default)]
+pub struct AdaptiveStats {
+  pub enabled: bool,
+  /// Current upload rate in bytes per second
+  pub pacing_bytes_per_sec: u64,
+  pub min_bytes_per_sec: u64,
+  pub max_bytes_per_sec: u64,
+  /// Current send window in segments
+  pub send_window: u16,
+  /// Current duplicate ACKs before a fast resend, 0 when it's disabled
+  pub fast_resend: u32,
+  pub last_decision: AdaptiveDecision,
+  /// Share of the last period writes waited for kcp's send window
+  pub last_stall_ratio: f64,
+  /// Share of the last period writes waited for the pacing rate
+  pub last_throttle_ratio: f64,
+  /// Share of the segments sent in the last period that were retransmissions
+  pub last_retransmit_ratio: f64,
+  /// Segments waiting in kcp at the end of the last period, relative to the
+  /// send window
+  pub last_queue_ratio: f64,
+  /// Smoothed RTT of the session in milliseconds, 0 before the first sample
+  pub srtt_millisec: u32,
+  pub min_rtt_millisec: u32,
+  /// Periods that raised the rate, a rate held at a bound isn't counted
+  pub increases: u64,
+  /// Periods that lowered the rate
+  pub decreases: u64,
+}
+
+/// What the controller sets on a stream, see `AdaptivePacing::sample`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adjustment {
+  pub bytes_per_sec: u64,
+  pub send_window: u16,
+  pub fast_resend: u32,
+}
+
+struct State {
+  current: Adjustment,
+  mss: usize,
+  period_start: Instant,
+  stalled: Duration,
+  throttled: Duration,
+  // Session counters at the start of the period.
+  segments_sent: u64,
+  retransmits: u64,
+  last_decision: AdaptiveDecision,
+  last_stall_ratio: f64,
+  last_throttle_ratio: f64,
+  last_retransmit_ratio: f64,
+  last_queue_ratio: f64,
+  srtt: u32,
+  min_rtt: u32,
+  increases: u64,
+  decreases: u64,
+}
+
+/// Adapts the upload rate, send window and fast resend threshold of a stream
+/// to its kcp session, AIMD style.
+///
+/// Once per period the session is sampled, whether or not the stream was
+/// written to: retransmissions, RTT growth with a
+/// filled send queue, or writes waiting for the send window lower the rate.
+/// Writes waiting for the rate raise it otherwise. The send window follows
+/// the bandwidth-delay product of the rate so that kcp doesn't queue more than
+/// the path holds, and fast resend waits for more duplicate ACKs on a jittery
+/// path.
+pub struct AdaptivePacing {
+  min: u64,
+  max: u64,
+  // Configured values, the upper bound of the window and the resend
+  // threshold on a steady path.
+  max_window: u16,
+  base_resend: u32,
+  state: StdMutex<State>,
+}
+
+impl AdaptivePacing {
+  /// Starts at `max` and the window of `config`.
+  pub fn new(min: u64, max: u64, config: &KcpConfig) -> Self {
+    let max_window = config.wnd_size.0.max(1);
+    let base_resend = config.nodelay.resend.max(0) as u32;
+    Self {
+      min,
+      max,
+      max_window,
+      base_resend,
+      state: StdMutex::new(State {
+        current: Adjustment {
+          bytes_per_sec: max,
+          send_window: max_window,
+          fast_resend: base_resend,
+        },
+        mss: mss(config.mtu),
+        period_start: Instant::now(),
+        stalled: Duration::ZERO,
+        throttled: Duration::ZERO,
+        segments_sent: 0,
+        retransmits: 0,
+        last_decision: AdaptiveDecision::Hold,
+        last_stall_ratio: 0.0,
+        last_throttle_ratio: 0.0,
+        last_retransmit_ratio: 0.0,
+        last_queue_ratio: 0.0,
+        srtt: 0,
+        min_rtt: 0,
+        increases: 0,
+        decreases: 0,
+      }),
+    }
+  }
+
+  pub fn current(&self) -> Adjustment {
+    self.state.lock().unwrap().current
+  }
+
+  /// The window is computed in segments of `mtu`.
+  pub fn set_mtu(&self, mtu: usize) {
+    self.state.lock().unwrap().mss = mss(mtu);
+  }
+
+  /// Records a write which waited `throttled` for the pacing rate and
+  /// `stalled` for kcp's send window.
+  pub fn record(&self, throttled: Duration, stalled: Duration) {
+    let mut state = self.state.lock().unwrap();
+    state.stalled += stalled;
+    state.throttled += throttled;
+  }
+
+  /// Ends a period with `stats` of the session, called every
+  /// `SAMPLE_PERIOD`. Returns what to set on the stream if anything changed.
+  pub fn sample(&self, stats: KcpSessionStats) -> Option<Adjustment> {
+    self.sample_at(stats, Instant::now())
+  }
+
+  fn sample_at(&self, stats: KcpSessionStats, now: Instant) -> Option<Adjustment> {
+    let mut state = self.state.lock().unwrap();
+    let elapsed = now.saturating_duration_since(state.period_start);
+    if elapsed.is_zero() {
+      return None;
+    }
+
+    let stall_ratio = state.stalled.as_secs_f64() / elapsed.as_secs_f64();
+    let throttle_ratio = state.throttled.as_secs_f64() / elapsed.as_secs_f64();
+    let sent = stats.segments_sent.saturating_sub(state.segments_sent);
+    let retransmits = stats.retransmits.saturating_sub(state.retransmits);
+    let retransmit_ratio = if sent == 0 {
+      0.0
+    } else {
+      retransmits as f64 / sent as f64
+    };
+    let rtt_inflation = if stats.srtt == 0 {
+      1.0
+    } else {
+      stats.srtt as f64 / stats.min_rtt.max(RTT_FLOOR_MILLISEC) as f64
+    };
+    let queue_ratio = stats.wait_snd as f64 / stats.snd_wnd.max(1) as f64;
+
+    let old = state.current;
+    let congested = retransmit_ratio > RETRANSMIT_HIGH
+      || (rtt_inflation > RTT_INFLATION_HIGH && queue_ratio > QUEUE_HIGH)
+      || stall_ratio > STALL_HIGH;
+    let rate = if congested {
+      let rate = (old.bytes_per_sec as f64 * DECREASE_FACTOR) as u64;
+      rate.max(self.min)
+    } else if throttle_ratio > THROTTLE_HIGH {
+      let rate = old.bytes_per_sec + (self.max as f64 * INCREASE_STEP) as u64;
+      rate.min(self.max)
+    } else {
+      old.bytes_per_sec
+    };
+    // A rate already at its bound holds.
+    let decision = match rate.cmp(&old.bytes_per_sec) {
+      std::cmp::Ordering::Greater => AdaptiveDecision::Increase,
+      std::cmp::Ordering::Less => AdaptiveDecision::Decrease,
+      std::cmp::Ordering::Equal => AdaptiveDecision::Hold,
+    };
+
+    let send_window = if stats.srtt == 0 {
+      self.max_window
+    } else {
+      let bdp = rate as f64 * stats.srtt as f64 / 1000.0 / state.mss as f64;
+      let window = (2.0 * bdp).ceil().min(u16::MAX as f64) as u16;
+      window.clamp(MIN_SEND_WINDOW.min(self.max_window), self.max_window)
+    };
+    let jittery = stats.srtt > 0 && stats.rttvar > stats.srtt / 2;
+    let fast_resend = if self.base_resend > 0 && jittery {
+      self.base_resend + JITTER_RESEND_EXTRA
+    } else {
+      self.base_resend
+    };
+
+    match decision {
+      AdaptiveDecision::Increase => state.increases += 1,
+      AdaptiveDecision::Decrease => state.decreases += 1,
+      AdaptiveDecision::Hold => {}
+    }
+    state.current = Adjustment {
+      bytes_per_sec: rate,
+      send_window,
+      fast_resend,
+    };
+    state.last_decision = decision;
+    state.last_stall_ratio = stall_ratio;
+    state.last_throttle_ratio = throttle_ratio;
+    state.last_retransmit_ratio = retransmit_ratio;
+    state.last_queue_ratio = queue_ratio;
+    state.srtt = stats.srtt;
+    state.min_rtt = stats.min_rtt;
+    state.segments_sent = stats.segments_sent;
+    state.retransmits = stats.retransmits;
+    state.period_start = now;
+    state.stalled = Duration::ZERO;
+    state.throttled = Duration::ZERO;
+
+    (state.current != old).then_some(state.current)
+  }
+
+  pub fn stats(&self) -> AdaptiveStats {
+    let state = self.state.lock().unwrap();
+    AdaptiveStats {
+      enabled: true,
+      pacing_bytes_per_sec: state.current.bytes_per_sec,
+      min_bytes_per_sec: self.min,
+      max_bytes_per_sec: self.max,
+      send_window: state.current.send_window,
+      fast_resend: state.current.fast_resend,
+      last_decision: state.last_decision,
+      last_stall_ratio: state.last_stall_ratio,
+      last_throttle_ratio: state.last_throttle_ratio,
+      last_retransmit_ratio: state.last_retransmit_ratio,
+      last_queue_ratio: state.last_queue_ratio,
+      srtt_millisec: state.srtt,
+      min_rtt_millisec: state.min_rtt,
+      increases: state.increases,
+      decreases: state.decreases,
+    }
+  }
+}
+
+fn mss(mtu: usize) -> usize {
+  mtu.saturating_sub(kcp::KCP_OVERHEAD).max(1)
+}
+
+#[test]
+fn test_adaptive_pacing() {
+  let mut config = KcpConfig {
+    mtu: 1024 + kcp::KCP_OVERHEAD,
+    wnd_size: (256, 256),
+    ..Default::default()
+  };
+  config.nodelay.resend = 2;
+  let pacing = AdaptivePacing::new(10_000, 100_000, &config);
+  let start = pacing.state.lock().unwrap().period_start;
+  let at = |ms| start + Duration::from_millis(ms);
+  let steady = KcpSessionStats {
+    srtt: 100,
+    rttvar: 10,
+    min_rtt: 90,
+    segments_sent: 100,
+    snd_wnd: 256,
+    ..Default::default()
+  };
+
+  // Writes wait for the rate, which is already at its upper bound. The window
+  // follows its bandwidth-delay product.
+  pacing.record(Duration::from_millis(300), Duration::ZERO);
+  let adjustment = pacing.sample_at(steady, at(1000)).unwrap();
+  assert_eq!(adjustment.bytes_per_sec, 100_000);
+  assert_eq!(adjustment.send_window, 32);
+  assert_eq!(adjustment.fast_resend, 2);
+  assert_eq!(pacing.stats().last_decision, AdaptiveDecision::Hold);
+  assert_eq!(pacing.stats().increases, 0);
+
+  // 10 of 100 segments were retransmitted, without any write in the period.
+  let lossy = KcpSessionStats {
+    segments_sent: 200,
+    retransmits: 10,
+    ..steady
+  };
+  let adjustment = pacing.sample_at(lossy, at(2000)).unwrap();
+  assert_eq!(adjustment.bytes_per_sec, 85_000);
+  assert_eq!(pacing.stats().last_decision, AdaptiveDecision::Decrease);
+  assert_eq!(pacing.stats().last_retransmit_ratio, 0.1);
+
+  // The RTT tripled while the queue is full.
+  let queued = KcpSessionStats {
+    srtt: 300,
+    wait_snd: 200,
+    ..lossy
+  };
+  let adjustment = pacing.sample_at(queued, at(3000)).unwrap();
+  assert_eq!(adjustment.bytes_per_sec, 72_250);
+  // 72250 * 0.3 / 1024 * 2
+  assert_eq!(adjustment.send_window, 43);
+
+  // A jittery path waits for more duplicate ACKs.
+  let jittery = KcpSessionStats {
+    rttvar: 60,
+    wait_snd: 0,
+    ..steady
+  };
+  let adjustment = pacing.sample_at(jittery, at(4000)).unwrap();
+  assert_eq!(adjustment.bytes_per_sec, 72_250);
+  assert_eq!(adjustment.fast_resend, 4);
+  assert_eq!(pacing.stats().last_decision, AdaptiveDecision::Hold);
+
+  // Below the bound, writes waiting for the rate raise it.
+  pacing.record(Duration::from_millis(300), Duration::ZERO);
+  let adjustment = pacing.sample_at(steady, at(5000)).unwrap();
+  assert_eq!(adjustment.bytes_per_sec, 77_250);
+  assert_eq!(pacing.stats().last_decision, AdaptiveDecision::Increase);
+  assert_eq!(pacing.stats().increases, 1);
+  assert_eq!(pacing.stats().decreases, 2);
+}
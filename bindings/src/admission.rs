@@ -0,0 +1,314 @@
+use crate::error::SwiftKcpError;
+use crate::Result;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::time;
+
+// How long a filter may take to decide, the listener hands no session out
+// meanwhile.
+const FILTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Decides whether a listener hands a new session out.
+#[uniffi::export(callback_interface)]
+pub trait KcpAcceptFilter: Send + Sync {
+  /// Called for every session passing the admission rules and session
+  /// limits, returning false rejects it. Sessions are admitted one at a time,
+  /// a session is rejected if the answer takes longer than a second.
+  fn should_accept(&self, peer_addr: String, conv: u32) -> bool;
+}
+
+#[derive(uniffi::Record, Debug, Default, Clone)]
+pub struct AdmissionParams {
+  /// Sessions of the listener alive at the same time, including accepted ones
+  pub max_sessions: Option<u32>,
+  pub max_sessions_per_ip: Option<u32>,
+  /// CIDRs like "10.0.0.0/8" or plain IPs, empty allows every address
+  pub allow: Vec<String>,
+  /// CIDRs like "10.0.0.0/8" or plain IPs, takes precedence over `allow`
+  pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cidr {
+  net: IpAddr,
+  prefix: u8,
+}
+
+impl FromStr for Cidr {
+  type Err = SwiftKcpError;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let invalid = || SwiftKcpError::InvalidCidr {
+      cidr: s.to_string(),
+    };
+
+    let (ip, prefix) = match s.split_once('/') {
+      Some((ip, prefix)) => (ip, Some(prefix)),
+      None => (s, None),
+    };
+    let net = IpAddr::from_str(ip.trim()).map_err(|_| invalid())?;
+    let max_prefix = if net.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+      Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+      None => max_prefix,
+    };
+    if prefix > max_prefix {
+      return Err(invalid());
+    }
+
+    Ok(Self { net, prefix })
+  }
+}
+
+impl Cidr {
+  fn contains(&self, ip: &IpAddr) -> bool {
+    match (self.net, ip) {
+      (IpAddr::V4(net), IpAddr::V4(ip)) => {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        u32::from(net) & mask == u32::from(*ip) & mask
+      }
+      (IpAddr::V6(net), IpAddr::V6(ip)) => {
+        let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+        u128::from(net) & mask == u128::from(*ip) & mask
+      }
+      _ => false,
+    }
+  }
+}
+
+#[derive(Default)]
+struct Rules {
+  max_sessions: Option<u32>,
+  max_sessions_per_ip: Option<u32>,
+  allow: Vec<Cidr>,
+  deny: Vec<Cidr>,
+}
+
+impl Rules {
+  fn allows(&self, ip: &IpAddr) -> bool {
+    if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+      return false;
+    }
+    self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+  }
+}
+
+#[derive(Default)]
+struct Sessions {
+  total: u32,
+  by_ip: HashMap<IpAddr, u32>,
+}
+
+/// Admission control of a listener, shared by its accept tasks.
+#[derive(Default)]
+pub struct Admission {
+  rules: StdMutex<Rules>,
+  filter: StdMutex<Option<Arc<dyn KcpAcceptFilter>>>,
+  sessions: StdMutex<Sessions>,
+  rejected: AtomicU64,
+}
+
+/// Counts a session against the limits until it's dropped.
+pub struct AdmissionTicket {
+  admission: Arc<Admission>,
+  ip: IpAddr,
+}
+
+impl Drop for AdmissionTicket {
+  fn drop(&mut self) {
+    let mut sessions = self.admission.sessions.lock().unwrap();
+    sessions.total -= 1;
+    if let Some(count) = sessions.by_ip.get_mut(&self.ip) {
+      *count -= 1;
+      if *count == 0 {
+        sessions.by_ip.remove(&self.ip);
+      }
+    }
+  }
+}
+
+impl Admission {
+  pub fn set_params(&self, params: AdmissionParams) -> Result<()> {
+    let parse = |cidrs: &[String]| {
+      cidrs
+        .iter()
+        .map(|cidr| Cidr::from_str(cidr))
+        .collect::<Result<Vec<_>>>()
+    };
+
+    *self.rules.lock().unwrap() = Rules {
+      max_sessions: params.max_sessions,
+      max_sessions_per_ip: params.max_sessions_per_ip,
+      allow: parse(&params.allow)?,
+      deny: parse(&params.deny)?,
+    };
+    Ok(())
+  }
+
+  pub fn set_filter(&self, filter: Option<Arc<dyn KcpAcceptFilter>>) {
+    *self.filter.lock().unwrap() = filter;
+  }
+
+  pub fn rejected_count(&self) -> u64 {
+    self.rejected.load(Ordering::Relaxed)
+  }
+
+  /// Checks a new session against the rules and the filter, the session
+  /// should be dropped if `None` is returned.
+  // Should be called within the tokio runtime.
+  pub async fn admit(self: &Arc<Self>, addr: SocketAddr, conv: u32) -> Option<AdmissionTicket> {
+    let ticket = self.try_admit(addr, conv).await;
+    if ticket.is_none() {
+      self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+    ticket
+  }
+
+  async fn try_admit(self: &Arc<Self>, addr: SocketAddr, conv: u32) -> Option<AdmissionTicket> {
+    let ip = addr.ip();
+    if !self.rules.lock().unwrap().allows(&ip) {
+      return None;
+    }
+
+    // The limits come first, the filter isn't asked about sessions that
+    // would be rejected anyway.
+    let ticket = self.take_slot(ip)?;
+
+    let filter = self.filter.lock().unwrap().clone();
+    if let Some(filter) = filter {
+      let peer_addr = addr.to_string();
+      let decision = tokio::task::spawn_blocking(move || filter.should_accept(peer_addr, conv));
+      match time::timeout(FILTER_TIMEOUT, decision).await {
+        Ok(Ok(true)) => {}
+        Ok(..) => return None,
+        Err(..) => {
+          tracing::warn!(%addr, conv, "accept filter timed out, session rejected");
+          return None;
+        }
+      }
+    }
+
+    Some(ticket)
+  }
+
+  // Counts a session of `ip` against the limits, `None` if it exceeds one.
+  fn take_slot(self: &Arc<Self>, ip: IpAddr) -> Option<AdmissionTicket> {
+    let (max_sessions, max_sessions_per_ip) = {
+      let rules = self.rules.lock().unwrap();
+      (rules.max_sessions, rules.max_sessions_per_ip)
+    };
+    let mut sessions = self.sessions.lock().unwrap();
+    let ip_sessions = sessions.by_ip.get(&ip).copied().unwrap_or(0);
+    if max_sessions.is_some_and(|max| sessions.total >= max)
+      || max_sessions_per_ip.is_some_and(|max| ip_sessions >= max)
+    {
+      return None;
+    }
+    sessions.total += 1;
+    *sessions.by_ip.entry(ip).or_default() += 1;
+
+    Some(AdmissionTicket {
+      admission: self.clone(),
+      ip,
+    })
+  }
+}
+
+#[test]
+fn test_admission_rules() {
+  let admission = Arc::new(Admission::default());
+  admission
+    .set_params(AdmissionParams {
+      max_sessions: Some(2),
+      max_sessions_per_ip: Some(1),
+      allow: vec!["10.0.0.0/8".to_string(), "::1".to_string()],
+      deny: vec!["10.0.0.3/32".to_string()],
+    })
+    .unwrap();
+  assert!(admission
+    .set_params(AdmissionParams {
+      allow: vec!["10.0.0.0/33".to_string()],
+      ..Default::default()
+    })
+    .is_err());
+
+  let rt = tokio::runtime::Runtime::new().unwrap();
+  rt.block_on(async {
+    let addr = |s: &str| SocketAddr::from_str(s).unwrap();
+
+    let first = admission.admit(addr("10.0.0.1:1000"), 1).await;
+    assert!(first.is_some());
+    assert!(admission.admit(addr("10.0.0.1:1001"), 2).await.is_none());
+    assert!(admission.admit(addr("10.0.0.3:1000"), 3).await.is_none());
+    assert!(admission.admit(addr("192.168.0.1:1000"), 4).await.is_none());
+    let second = admission.admit(addr("[::1]:1000"), 5).await;
+    assert!(second.is_some());
+    assert!(admission.admit(addr("10.0.0.2:1000"), 6).await.is_none());
+
+    drop(first);
+    assert!(admission.admit(addr("10.0.0.2:1000"), 7).await.is_some());
+    assert_eq!(admission.rejected_count(), 4);
+  });
+}
+
+#[test]
+fn test_accept_filter() {
+  use std::sync::atomic::AtomicU32;
+
+  struct Filter {
+    calls: AtomicU32,
+    delay: Duration,
+  }
+
+  impl KcpAcceptFilter for Filter {
+    fn should_accept(&self, _peer_addr: String, conv: u32) -> bool {
+      self.calls.fetch_add(1, Ordering::Relaxed);
+      std::thread::sleep(self.delay);
+      conv % 2 == 1
+    }
+  }
+
+  let admission = Arc::new(Admission::default());
+  admission
+    .set_params(AdmissionParams {
+      max_sessions: Some(1),
+      ..Default::default()
+    })
+    .unwrap();
+  let filter = Arc::new(Filter {
+    calls: AtomicU32::new(0),
+    delay: Duration::ZERO,
+  });
+  admission.set_filter(Some(filter.clone()));
+
+  let rt = tokio::runtime::Runtime::new().unwrap();
+  rt.block_on(async {
+    let addr = SocketAddr::from_str("10.0.0.1:1000").unwrap();
+
+    // A refused session doesn't keep its slot.
+    assert!(admission.admit(addr, 2).await.is_none());
+    let first = admission.admit(addr, 1).await;
+    assert!(first.is_some());
+    assert_eq!(filter.calls.load(Ordering::Relaxed), 2);
+
+    // Over the limit, the filter isn't asked.
+    assert!(admission.admit(addr, 3).await.is_none());
+    assert_eq!(filter.calls.load(Ordering::Relaxed), 2);
+    drop(first);
+
+    // A slow filter rejects the session.
+    let slow = Arc::new(Filter {
+      calls: AtomicU32::new(0),
+      delay: FILTER_TIMEOUT * 2,
+    });
+    admission.set_filter(Some(slow));
+    let start = std::time::Instant::now();
+    assert!(admission.admit(addr, 5).await.is_none());
+    assert!(start.elapsed() < FILTER_TIMEOUT * 2);
+    assert_eq!(admission.rejected_count(), 3);
+  });
+}
@@ -0,0 +1,333 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex as StdMutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_SNAPLEN: u32 = 65535;
+// Packets start with an IPv4 or IPv6 header.
+const LINKTYPE_RAW: u32 = 101;
+const IPPROTO_UDP: u8 = 17;
+const TTL: u8 = 64;
+
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_ACK: u8 = 82;
+const KCP_CMD_WASK: u8 = 83;
+const KCP_CMD_WINS: u8 = 84;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+  Sent,
+  Received,
+}
+
+/// One kcp segment header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+  pub conv: u32,
+  pub cmd: u8,
+  pub frg: u8,
+  pub wnd: u16,
+  pub ts: u32,
+  pub sn: u32,
+  pub una: u32,
+  pub len: u32,
+}
+
+fn cmd_name(cmd: u8) -> &'static str {
+  match cmd {
+    KCP_CMD_PUSH => "PUSH",
+    KCP_CMD_ACK => "ACK",
+    KCP_CMD_WASK => "WASK",
+    KCP_CMD_WINS => "WINS",
+    _ => "?",
+  }
+}
+
+/// Splits a datagram into kcp segments. Returns the segments and whether
+/// bytes were left that don't form a segment.
+pub fn decode(mut packet: &[u8]) -> (Vec<Segment>, bool) {
+  let u16_at = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+  let u32_at = |b: &[u8], i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+
+  let mut segments = Vec::new();
+  while packet.len() >= kcp::KCP_OVERHEAD {
+    let segment = Segment {
+      conv: u32_at(packet, 0),
+      cmd: packet[4],
+      frg: packet[5],
+      wnd: u16_at(packet, 6),
+      ts: u32_at(packet, 8),
+      sn: u32_at(packet, 12),
+      una: u32_at(packet, 16),
+      len: u32_at(packet, 20),
+    };
+    let end = kcp::KCP_OVERHEAD + segment.len as usize;
+    if end > packet.len() {
+      return (segments, true);
+    }
+    packet = &packet[end..];
+    segments.push(segment);
+  }
+  (segments, !packet.is_empty())
+}
+
+fn checksum(chunks: &[&[u8]]) -> u16 {
+  let mut sum = 0u32;
+  for chunk in chunks {
+    for pair in chunk.chunks(2) {
+      let word = match pair {
+        [hi, lo] => u16::from_be_bytes([*hi, *lo]),
+        [hi] => u16::from_be_bytes([*hi, 0]),
+        _ => unreachable!(),
+      };
+      sum += word as u32;
+    }
+  }
+  while sum > 0xffff {
+    sum = (sum & 0xffff) + (sum >> 16);
+  }
+  !(sum as u16)
+}
+
+// Both addresses as IPv6 if either is, the way a dual-stack socket sees IPv4
+// peers.
+fn same_family(src: IpAddr, dst: IpAddr) -> (IpAddr, IpAddr) {
+  match (src, dst) {
+    (IpAddr::V4(src), IpAddr::V6(dst)) => (src.to_ipv6_mapped().into(), dst.into()),
+    (IpAddr::V6(src), IpAddr::V4(dst)) => (src.into(), dst.to_ipv6_mapped().into()),
+    _ => (src, dst),
+  }
+}
+
+/// Wraps `payload` in IP and UDP headers, checksums included, so that
+/// Wireshark decodes it like a datagram captured on the wire.
+fn ip_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+  let udp_len = (8 + payload.len()) as u16;
+  let mut udp = Vec::with_capacity(udp_len as usize);
+  udp.extend_from_slice(&src.port().to_be_bytes());
+  udp.extend_from_slice(&dst.port().to_be_bytes());
+  udp.extend_from_slice(&udp_len.to_be_bytes());
+  udp.extend_from_slice(&[0, 0]);
+
+  let mut packet = Vec::new();
+  let udp_checksum = match same_family(src.ip(), dst.ip()) {
+    (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+      let total_len = 20 + udp_len;
+      let mut header = vec![0x45, 0];
+      header.extend_from_slice(&total_len.to_be_bytes());
+      header.extend_from_slice(&[0, 0, 0, 0, TTL, IPPROTO_UDP, 0, 0]);
+      header.extend_from_slice(&src_ip.octets());
+      header.extend_from_slice(&dst_ip.octets());
+      let header_checksum = checksum(&[&header]);
+      header[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+      packet.extend_from_slice(&header);
+
+      let mut pseudo = Vec::with_capacity(12);
+      pseudo.extend_from_slice(&src_ip.octets());
+      pseudo.extend_from_slice(&dst_ip.octets());
+      pseudo.extend_from_slice(&[0, IPPROTO_UDP]);
+      pseudo.extend_from_slice(&udp_len.to_be_bytes());
+      checksum(&[&pseudo, &udp, payload])
+    }
+    (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+      packet.extend_from_slice(&[0x60, 0, 0, 0]);
+      packet.extend_from_slice(&udp_len.to_be_bytes());
+      packet.extend_from_slice(&[IPPROTO_UDP, TTL]);
+      packet.extend_from_slice(&src_ip.octets());
+      packet.extend_from_slice(&dst_ip.octets());
+
+      let mut pseudo = Vec::with_capacity(40);
+      pseudo.extend_from_slice(&src_ip.octets());
+      pseudo.extend_from_slice(&dst_ip.octets());
+      pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+      pseudo.extend_from_slice(&[0, 0, 0, IPPROTO_UDP]);
+      checksum(&[&pseudo, &udp, payload])
+    }
+    _ => unreachable!("same_family returns one family"),
+  };
+  // Zero means "no checksum" in UDP.
+  let udp_checksum = match udp_checksum {
+    0 => 0xffff,
+    sum => sum,
+  };
+  udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+  packet.extend_from_slice(&udp);
+  packet.extend_from_slice(payload);
+  packet
+}
+
+/// The address a socket bound to `bound` sends to `remote` from. That's
+/// `bound` unless it's unspecified, e.g. 0.0.0.0, then the route to `remote`
+/// decides the IP.
+pub fn local_addr_for(bound: SocketAddr, remote: SocketAddr) -> SocketAddr {
+  if !bound.ip().is_unspecified() {
+    return bound;
+  }
+  // Connecting a UDP socket only looks up the route, nothing is sent.
+  let routed = std::net::UdpSocket::bind(SocketAddr::new(bound.ip(), 0)).and_then(|socket| {
+    socket.connect(remote)?;
+    socket.local_addr()
+  });
+  match routed {
+    Ok(addr) => SocketAddr::new(addr.ip(), bound.port()),
+    Err(_) => bound,
+  }
+}
+
+struct Files {
+  pcap: BufWriter<File>,
+  log: BufWriter<File>,
+}
+
+/// Records the datagrams of a session to a pcap file, and their kcp segments
+/// to a text log next to it.
+pub struct Capture {
+  files: StdMutex<Files>,
+}
+
+impl Capture {
+  /// Creates `path` and `path` + ".txt", truncating existing files.
+  pub fn create(path: &str) -> io::Result<Self> {
+    let mut pcap = BufWriter::new(File::create(path)?);
+    let log = BufWriter::new(File::create(format!("{}.txt", path))?);
+
+    pcap.write_all(&PCAP_MAGIC.to_le_bytes())?;
+    pcap.write_all(&2u16.to_le_bytes())?;
+    pcap.write_all(&4u16.to_le_bytes())?;
+    pcap.write_all(&0i32.to_le_bytes())?;
+    pcap.write_all(&0u32.to_le_bytes())?;
+    pcap.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+    pcap.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+    Ok(Self {
+      files: StdMutex::new(Files { pcap, log }),
+    })
+  }
+
+  /// Records a datagram sent from `local` to `remote` or received by `local`
+  /// from `remote`. Write errors are logged and otherwise ignored.
+  pub fn record(
+    &self,
+    direction: Direction,
+    local: SocketAddr,
+    remote: SocketAddr,
+    payload: &[u8],
+  ) {
+    if let Err(e) = self.write(direction, local, remote, payload) {
+      tracing::warn!(error = %e, "capture write failed");
+    }
+  }
+
+  fn write(
+    &self,
+    direction: Direction,
+    local: SocketAddr,
+    remote: SocketAddr,
+    payload: &[u8],
+  ) -> io::Result<()> {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default();
+    let (src, dst) = match direction {
+      Direction::Sent => (local, remote),
+      Direction::Received => (remote, local),
+    };
+    let packet = ip_packet(src, dst, payload);
+    let included = packet.len().min(PCAP_SNAPLEN as usize);
+
+    let mut line = format!(
+      "{}.{:06} {} {} len={}",
+      now.as_secs(),
+      now.subsec_micros(),
+      match direction {
+        Direction::Sent => "send",
+        Direction::Received => "recv",
+      },
+      remote,
+      payload.len()
+    );
+    let (segments, trailing) = decode(payload);
+    for segment in segments {
+      let _ = write!(
+        line,
+        "\n  conv={} cmd={} frg={} sn={} una={} wnd={} ts={} len={}",
+        segment.conv,
+        cmd_name(segment.cmd),
+        segment.frg,
+        segment.sn,
+        segment.una,
+        segment.wnd,
+        segment.ts,
+        segment.len
+      );
+    }
+    if trailing {
+      line.push_str("\n  malformed trailing bytes");
+    }
+    line.push('\n');
+
+    let mut files = self.files.lock().unwrap();
+    files
+      .pcap
+      .write_all(&(now.as_secs() as u32).to_le_bytes())?;
+    files.pcap.write_all(&now.subsec_micros().to_le_bytes())?;
+    files.pcap.write_all(&(included as u32).to_le_bytes())?;
+    files.pcap.write_all(&(packet.len() as u32).to_le_bytes())?;
+    files.pcap.write_all(&packet[..included])?;
+    files.log.write_all(line.as_bytes())
+  }
+
+  pub fn flush(&self) -> io::Result<()> {
+    let mut files = self.files.lock().unwrap();
+    files.pcap.flush()?;
+    files.log.flush()
+  }
+}
+
+impl Drop for Capture {
+  fn drop(&mut self) {
+    let _ = self.flush();
+  }
+}
+
+#[test]
+fn test_decode_and_checksum() {
+  let mut packet = Vec::new();
+  for (cmd, sn, data) in [(KCP_CMD_PUSH, 7u32, &b"hi"[..]), (KCP_CMD_ACK, 3, &[][..])] {
+    packet.extend_from_slice(&42u32.to_le_bytes());
+    packet.extend_from_slice(&[cmd, 0]);
+    packet.extend_from_slice(&128u16.to_le_bytes());
+    packet.extend_from_slice(&1000u32.to_le_bytes());
+    packet.extend_from_slice(&sn.to_le_bytes());
+    packet.extend_from_slice(&5u32.to_le_bytes());
+    packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    packet.extend_from_slice(data);
+  }
+
+  let (segments, trailing) = decode(&packet);
+  assert!(!trailing);
+  assert_eq!(segments.len(), 2);
+  assert_eq!(segments[0].sn, 7);
+  assert_eq!(segments[0].len, 2);
+  assert_eq!(cmd_name(segments[1].cmd), "ACK");
+  assert!(decode(&packet[..30]).1);
+
+  // A valid header sums to zero with its checksum included.
+  let local: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+  let remote: SocketAddr = "10.0.0.2:3100".parse().unwrap();
+  let ip = ip_packet(local, remote, &packet);
+  assert_eq!(checksum(&[&ip[..20]]), 0);
+  assert_eq!(ip.len(), 20 + 8 + packet.len());
+
+  let bound: SocketAddr = "0.0.0.0:4000".parse().unwrap();
+  let loopback: SocketAddr = "127.0.0.1:3100".parse().unwrap();
+  assert_eq!(
+    local_addr_for(bound, loopback),
+    "127.0.0.1:4000".parse().unwrap()
+  );
+  assert_eq!(local_addr_for(local, remote), local);
+}
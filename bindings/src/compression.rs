@@ -0,0 +1,120 @@
+use crate::error::SwiftKcpError;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+
+/// Length of the frame header used in stream mode.
+const FRAME_HEADER_LEN: usize = 4;
+
+/// Writes are compressed in chunks of at most this many bytes. Peers
+/// announcing larger chunks are rejected before anything is allocated.
+pub const MAX_CHUNK_LEN: usize = 1024 * 1024;
+
+/// Largest frame a chunk can be compressed to, with the size lz4 prepends.
+pub const MAX_FRAME_LEN: usize = 4 + lz4_flex::block::get_maximum_output_size(MAX_CHUNK_LEN);
+
+#[derive(uniffi::Enum, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KcpCompression {
+  /// Payloads are sent as is
+  #[default]
+  None,
+  /// LZ4 block compression
+  Lz4,
+}
+
+#[derive(uniffi::Record, Debug, Default, Clone, PartialEq)]
+pub struct CompressionStats {
+  /// Compression mode of the stream
+  pub compression: KcpCompression,
+  /// Bytes passed to `write_stream` before compression
+  pub raw_bytes_written: u64,
+  /// Bytes handed to kcp after compression and framing
+  pub compressed_bytes_written: u64,
+  /// Bytes returned from `read_stream` after decompression
+  pub raw_bytes_read: u64,
+  /// Bytes received from kcp before decompression
+  pub compressed_bytes_read: u64,
+  /// `compressed_bytes_written / raw_bytes_written`, 1.0 if nothing is written
+  pub write_ratio: f64,
+  /// `compressed_bytes_read / raw_bytes_read`, 1.0 if nothing is read
+  pub read_ratio: f64,
+}
+
+pub fn compress(compression: KcpCompression, data: &[u8]) -> Vec<u8> {
+  match compression {
+    KcpCompression::None => data.to_vec(),
+    KcpCompression::Lz4 => lz4_flex::compress_prepend_size(data),
+  }
+}
+
+pub fn decompress(compression: KcpCompression, data: &[u8]) -> Result<Vec<u8>> {
+  match compression {
+    KcpCompression::None => Ok(data.to_vec()),
+    KcpCompression::Lz4 => {
+      let (len, _) = lz4_flex::block::uncompressed_size(data)
+        .map_err(|e| SwiftKcpError::Compression { msg: e.to_string() })?;
+      if len > MAX_CHUNK_LEN {
+        return Err(SwiftKcpError::Protocol {
+          msg: format!(
+            "compressed chunk of {} bytes, at most {} allowed",
+            len, MAX_CHUNK_LEN
+          ),
+        });
+      }
+      lz4_flex::decompress_size_prepended(data)
+        .map_err(|e| SwiftKcpError::Compression { msg: e.to_string() })
+    }
+  }
+}
+
+// Stream mode has no message boundaries, so every compressed chunk is prefixed
+// with its length (u32, big endian).
+pub fn frame(payload: Vec<u8>) -> Vec<u8> {
+  let mut framed = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+  framed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+  framed.extend_from_slice(&payload);
+  framed
+}
+
+pub fn frame_len(header: [u8; FRAME_HEADER_LEN]) -> Result<usize> {
+  let len = u32::from_be_bytes(header) as usize;
+  if len > MAX_FRAME_LEN {
+    return Err(SwiftKcpError::Protocol {
+      msg: format!("frame of {} bytes, at most {} allowed", len, MAX_FRAME_LEN),
+    });
+  }
+  Ok(len)
+}
+
+#[test]
+fn test_compression_round_trip() {
+  let data = br#"{"type":"chat","body":"hello hello hello hello hello"}"#.repeat(16);
+
+  let compressed = compress(KcpCompression::Lz4, &data);
+  assert!(compressed.len() < data.len());
+  assert_eq!(decompress(KcpCompression::Lz4, &compressed).unwrap(), data);
+
+  let framed = frame(compressed.clone());
+  let header = [framed[0], framed[1], framed[2], framed[3]];
+  assert_eq!(frame_len(header).unwrap(), compressed.len());
+  assert_eq!(&framed[FRAME_HEADER_LEN..], &compressed[..]);
+
+  assert!(decompress(KcpCompression::Lz4, &[1, 2, 3]).is_err());
+}
+
+#[test]
+fn test_oversized_chunks_are_rejected() {
+  assert!(matches!(
+    frame_len(u32::MAX.to_be_bytes()),
+    Err(SwiftKcpError::Protocol { .. })
+  ));
+  assert!(frame_len((MAX_FRAME_LEN as u32).to_be_bytes()).is_ok());
+
+  // A few bytes claiming to decompress to 4GB.
+  let mut data = u32::MAX.to_le_bytes().to_vec();
+  data.extend_from_slice(&[0x10, b'a']);
+  assert!(matches!(
+    decompress(KcpCompression::Lz4, &data),
+    Err(SwiftKcpError::Protocol { .. })
+  ));
+}
@@ -0,0 +1,253 @@
+use crate::capture::{self, Capture, Direction};
+#[cfg(feature = "impairment")]
+use crate::impair::Impairment;
+#[cfg(feature = "impairment")]
+use crate::kcp_util::ImpairmentParams;
+use crate::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio_kcp::{KcpConfig, KcpStream};
+
+const PACKET_BUF: usize = 65536;
+
+#[derive(Default)]
+struct Routes {
+  // local relay socket of a session -> remote address
+  remote_by_local: HashMap<SocketAddr, SocketAddr>,
+  // (remote address, conv) -> local relay socket of a session
+  local_by_conv: HashMap<(SocketAddr, u32), SocketAddr>,
+  // local relay socket of a session -> its running capture and the address
+  // the session's datagrams are sent from
+  capture_by_local: HashMap<SocketAddr, (Arc<Capture>, SocketAddr)>,
+  // local relay socket of a session -> (outbound, inbound) impairment
+  #[cfg(feature = "impairment")]
+  impairment_by_local: HashMap<SocketAddr, (Arc<Impairment>, Arc<Impairment>)>,
+}
+
+/// Removes the routes of a session when the session is dropped.
+pub struct EndpointRoute {
+  routes: Arc<StdMutex<Routes>>,
+  local: SocketAddr,
+  // Where the endpoint sends the session's datagrams from, as recorded in
+  // captures.
+  capture_addr: SocketAddr,
+}
+
+impl Drop for EndpointRoute {
+  fn drop(&mut self) {
+    let mut routes = self.routes.lock().unwrap();
+    routes.remote_by_local.remove(&self.local);
+    routes.local_by_conv.retain(|_, local| *local != self.local);
+    routes.capture_by_local.remove(&self.local);
+    #[cfg(feature = "impairment")]
+    routes.impairment_by_local.remove(&self.local);
+  }
+}
+
+impl EndpointRoute {
+  /// Starts recording the datagrams of the session to `capture`, or stops if
+  /// it's `None`. Returns the capture it replaces.
+  pub fn set_capture(&self, capture: Option<Arc<Capture>>) -> Option<Arc<Capture>> {
+    let mut routes = self.routes.lock().unwrap();
+    let replaced = match capture {
+      Some(capture) => routes
+        .capture_by_local
+        .insert(self.local, (capture, self.capture_addr)),
+      None => routes.capture_by_local.remove(&self.local),
+    };
+    replaced.map(|(capture, _)| capture)
+  }
+
+  /// Impairs the datagrams of the session in both directions.
+  #[cfg(feature = "impairment")]
+  pub fn set_impairment(&self, params: ImpairmentParams) {
+    // Each direction gets its own impairment, like a real link.
+    let outbound = Impairment::new(params.clone());
+    let inbound = Impairment::new(ImpairmentParams {
+      seed: params.seed.map(|seed| !seed),
+      ..params
+    });
+
+    self
+      .routes
+      .lock()
+      .unwrap()
+      .impairment_by_local
+      .insert(self.local, (Arc::new(outbound), Arc::new(inbound)));
+  }
+}
+
+#[cfg(feature = "impairment")]
+fn impairment(
+  routes: &StdMutex<Routes>,
+  local: &SocketAddr,
+) -> Option<(Arc<Impairment>, Arc<Impairment>)> {
+  routes
+    .lock()
+    .unwrap()
+    .impairment_by_local
+    .get(local)
+    .cloned()
+}
+
+/// A client side UDP socket shared by several kcp sessions.
+///
+/// tokio_kcp client sessions own their socket, so every session is given a
+/// loopback socket talking to `relay`, and the endpoint forwards packets
+/// between `relay` and `udp`. Packets from remote are dispatched by their
+/// source address and conv.
+///
+/// Listeners of the bindings key sessions by peer address and conv, so they
+/// hold any number of sessions of an endpoint. Servers that key sessions by
+/// peer address only can hold one.
+pub struct ClientEndpoint {
+  udp: Arc<UdpSocket>,
+  relay_addr: SocketAddr,
+  routes: Arc<StdMutex<Routes>>,
+  tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for ClientEndpoint {
+  fn drop(&mut self) {
+    for task in &self.tasks {
+      task.abort();
+    }
+  }
+}
+
+impl ClientEndpoint {
+  // Should be called within the tokio runtime.
+  pub async fn bind(addr: SocketAddr) -> Result<Self> {
+    Self::from_udp(UdpSocket::bind(addr).await?).await
+  }
+
+  // Should be called within the tokio runtime.
+  pub async fn from_udp(udp: UdpSocket) -> Result<Self> {
+    let udp = Arc::new(udp);
+    let relay = Arc::new(UdpSocket::bind("127.0.0.1:0").await?);
+    let relay_addr = relay.local_addr()?;
+    let routes: Arc<StdMutex<Routes>> = Default::default();
+
+    let outbound = {
+      let (udp, relay, routes) = (udp.clone(), relay.clone(), routes.clone());
+      tokio::spawn(async move {
+        let mut buf = [0u8; PACKET_BUF];
+        loop {
+          let (n, local) = match relay.recv_from(&mut buf).await {
+            Ok(ret) => ret,
+            Err(_) => continue,
+          };
+          let packet = &buf[..n];
+
+          let (remote, capture) = {
+            let mut routes = routes.lock().unwrap();
+            let remote = match routes.remote_by_local.get(&local) {
+              Some(remote) => *remote,
+              None => continue,
+            };
+            if packet.len() >= kcp::KCP_OVERHEAD {
+              let conv = kcp::get_conv(packet);
+              routes.local_by_conv.insert((remote, conv), local);
+            }
+            (remote, routes.capture_by_local.get(&local).cloned())
+          };
+
+          #[cfg(feature = "impairment")]
+          if let Some((outbound, _)) = impairment(&routes, &local) {
+            if let Some((capture, capture_addr)) = capture {
+              capture.record(Direction::Sent, capture_addr, remote, packet);
+            }
+            outbound.forward(&udp, packet, remote).await;
+            continue;
+          }
+          if udp.send_to(packet, remote).await.is_ok() {
+            if let Some((capture, capture_addr)) = capture {
+              capture.record(Direction::Sent, capture_addr, remote, packet);
+            }
+          }
+        }
+      })
+    };
+
+    let inbound = {
+      let (udp, relay, routes) = (udp.clone(), relay.clone(), routes.clone());
+      tokio::spawn(async move {
+        let mut buf = [0u8; PACKET_BUF];
+        loop {
+          let (n, remote) = match udp.recv_from(&mut buf).await {
+            Ok(ret) => ret,
+            Err(_) => continue,
+          };
+          let packet = &buf[..n];
+          if packet.len() < kcp::KCP_OVERHEAD {
+            continue;
+          }
+
+          let conv = kcp::get_conv(packet);
+          let (local, capture) = {
+            let routes = routes.lock().unwrap();
+            let local = routes.local_by_conv.get(&(remote, conv)).copied();
+            let capture = local.and_then(|local| routes.capture_by_local.get(&local).cloned());
+            (local, capture)
+          };
+
+          if let Some(local) = local {
+            if let Some((capture, capture_addr)) = capture {
+              capture.record(Direction::Received, capture_addr, remote, packet);
+            }
+            #[cfg(feature = "impairment")]
+            if let Some((_, inbound)) = impairment(&routes, &local) {
+              inbound.forward(&relay, packet, local).await;
+              continue;
+            }
+            let _ = relay.send_to(packet, local).await;
+          }
+        }
+      })
+    };
+
+    Ok(Self {
+      udp,
+      relay_addr,
+      routes,
+      tasks: vec![outbound, inbound],
+    })
+  }
+
+  // Should be called within the tokio runtime.
+  pub async fn connect(
+    &self,
+    config: &KcpConfig,
+    addr: SocketAddr,
+  ) -> Result<(KcpStream, EndpointRoute)> {
+    let socket = UdpSocket::bind("127.0.0.1:0").await?;
+    let local = socket.local_addr()?;
+
+    self
+      .routes
+      .lock()
+      .unwrap()
+      .remote_by_local
+      .insert(local, addr);
+    let route = EndpointRoute {
+      routes: self.routes.clone(),
+      local,
+      capture_addr: capture::local_addr_for(self.udp.local_addr()?, addr),
+    };
+
+    let stream = KcpStream::connect_with_socket(config, socket, self.relay_addr).await?;
+
+    Ok((stream, route))
+  }
+
+  pub fn local_addr(&self) -> Result<SocketAddr> {
+    Ok(self.udp.local_addr()?)
+  }
+
+  pub fn udp(&self) -> Arc<UdpSocket> {
+    self.udp.clone()
+  }
+}
@@ -1,5 +1,3 @@
-use thiserror;
-
 macro_rules! impl_from {
   ($from_type:ty) => {
     impl From<$from_type> for SwiftKcpError {
@@ -16,6 +14,8 @@ impl_from!(std::net::AddrParseError);
 impl_from!(kcp::Error);
 impl_from!(std::io::Error);
 impl_from!(tokio::task::JoinError);
+impl_from!(serde_json::Error);
+impl_from!(toml::ser::Error);
 
 #[derive(Debug, thiserror::Error, uniffi::Error)]
 pub enum SwiftKcpError {
@@ -30,4 +30,72 @@ pub enum SwiftKcpError {
 
   #[error("Listener not found for id {id}")]
   NoListenerForId { id: u64 },
+
+  #[error("Compression failed: {msg}")]
+  Compression { msg: String },
+
+  #[error("Mux session not found for id {id}")]
+  NoMuxForId { id: u64 },
+
+  #[error("Mux sub-stream not found for id {sid}")]
+  NoSubStreamForId { sid: u32 },
+
+  #[error("Mux sub-stream {sid} is closed")]
+  SubStreamClosed { sid: u32 },
+
+  #[error("Mux session is closed")]
+  MuxClosed,
+
+  #[error("Endpoint not found for id {id}")]
+  NoEndpointForId { id: u64 },
+
+  #[error("Failed to resolve {host}: {msg}")]
+  ResolveFailed { host: String, msg: String },
+
+  #[error("Invalid CIDR {cidr}")]
+  InvalidCidr { cidr: String },
+
+  #[error("{field} can't be changed on an open stream: {reason}")]
+  ConfigNotUpdatable { field: String, reason: String },
+
+  #[error("Invalid config {field}: {reason}")]
+  InvalidConfig { field: String, reason: String },
+
+  #[error("Failed to parse config at {path}: {msg}")]
+  ConfigParse { path: String, msg: String },
+
+  #[error("Can't capture stream {id}: {reason}")]
+  CaptureUnavailable { id: u64, reason: String },
+
+  #[error("Protocol violation: {msg}")]
+  Protocol { msg: String },
+
+  #[error("Another global logger is installed: {msg}")]
+  LoggerInstalled { msg: String },
+}
+
+impl SwiftKcpError {
+  /// Name of the variant, used as a metrics label.
+  pub fn kind(&self) -> &'static str {
+    match self {
+      SwiftKcpError::Default { .. } => "Default",
+      SwiftKcpError::RuntimeNotInited => "RuntimeNotInited",
+      SwiftKcpError::NoStreamForId { .. } => "NoStreamForId",
+      SwiftKcpError::NoListenerForId { .. } => "NoListenerForId",
+      SwiftKcpError::Compression { .. } => "Compression",
+      SwiftKcpError::NoMuxForId { .. } => "NoMuxForId",
+      SwiftKcpError::NoSubStreamForId { .. } => "NoSubStreamForId",
+      SwiftKcpError::SubStreamClosed { .. } => "SubStreamClosed",
+      SwiftKcpError::MuxClosed => "MuxClosed",
+      SwiftKcpError::NoEndpointForId { .. } => "NoEndpointForId",
+      SwiftKcpError::ResolveFailed { .. } => "ResolveFailed",
+      SwiftKcpError::InvalidCidr { .. } => "InvalidCidr",
+      SwiftKcpError::ConfigNotUpdatable { .. } => "ConfigNotUpdatable",
+      SwiftKcpError::InvalidConfig { .. } => "InvalidConfig",
+      SwiftKcpError::ConfigParse { .. } => "ConfigParse",
+      SwiftKcpError::CaptureUnavailable { .. } => "CaptureUnavailable",
+      SwiftKcpError::Protocol { .. } => "Protocol",
+      SwiftKcpError::LoggerInstalled { .. } => "LoggerInstalled",
+    }
+  }
 }
@@ -0,0 +1,249 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::time::Instant;
+
+// Events past this many unread ones are dropped.
+const EVENT_BACKLOG: usize = 256;
+
+#[derive(uniffi::Enum, Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+  /// The stream is connected to or accepted from `addr`
+  Connected { addr: String },
+  /// The first read returned data
+  FirstDataReceived,
+  /// Nothing was read or written for `idle_millisec`, see
+  /// `KcpConfigParams.idle_timeout_milisec`. Reported again after the next
+  /// read or write.
+  Idle { idle_millisec: u64 },
+  /// A read hit the end of the session. tokio_kcp ends accepted sessions
+  /// which got nothing for `session_expire_milisec`
+  Expired,
+  /// `shutdown_stream` was called
+  Shutdown,
+  /// A read or write failed
+  Reset { reason: String },
+}
+
+#[derive(uniffi::Enum, Debug, Clone, PartialEq, Eq)]
+pub enum ListenerEvent {
+  /// A new session passed admission and waits for `accepet`
+  SessionAccepted { addr: String, conv: u32 },
+  /// A new session was rejected by admission control
+  SessionRejected { addr: String, conv: u32 },
+  /// An accepted stream hit the end of its session, see `StreamEvent::Expired`
+  SessionExpired { addr: String, conv: u32 },
+}
+
+/// Unread events of a stream or listener. Events are dropped when
+/// `EVENT_BACKLOG` are unread.
+pub struct EventQueue<T> {
+  tx: mpsc::Sender<T>,
+  rx: Mutex<mpsc::Receiver<T>>,
+}
+
+impl<T> Default for EventQueue<T> {
+  fn default() -> Self {
+    let (tx, rx) = mpsc::channel(EVENT_BACKLOG);
+    Self {
+      tx,
+      rx: Mutex::new(rx),
+    }
+  }
+}
+
+impl<T: std::fmt::Debug> EventQueue<T> {
+  pub fn push(&self, event: T) {
+    if let Err(mpsc::error::TrySendError::Full(event)) = self.tx.try_send(event) {
+      tracing::debug!(?event, "event queue full, dropping event");
+    }
+  }
+
+  pub async fn next(&self) -> T {
+    // `self.tx` keeps the channel open.
+    self.rx.lock().await.recv().await.unwrap()
+  }
+}
+
+struct ListenerLink {
+  queue: Arc<EventQueue<ListenerEvent>>,
+  conv: u32,
+}
+
+/// Turns what a stream sees into `StreamEvent`s.
+pub struct StreamEvents {
+  queue: EventQueue<StreamEvent>,
+  created: Instant,
+  // Since `created`.
+  last_activity_nanos: AtomicU64,
+  // 0 disables idle events.
+  idle_timeout_millisec: AtomicU64,
+  idle_reported: AtomicBool,
+  idle_timeout_changed: Notify,
+  first_data: AtomicBool,
+  shut_down: AtomicBool,
+  ended: AtomicBool,
+  remote: StdMutex<Option<SocketAddr>>,
+  listener: Option<ListenerLink>,
+}
+
+impl StreamEvents {
+  pub fn new(idle_timeout_millisec: Option<u32>) -> Self {
+    Self {
+      queue: Default::default(),
+      created: Instant::now(),
+      last_activity_nanos: AtomicU64::new(0),
+      idle_timeout_millisec: AtomicU64::new(idle_timeout_millisec.unwrap_or(0) as u64),
+      idle_reported: AtomicBool::new(false),
+      idle_timeout_changed: Notify::new(),
+      first_data: AtomicBool::new(false),
+      shut_down: AtomicBool::new(false),
+      ended: AtomicBool::new(false),
+      remote: StdMutex::new(None),
+      listener: None,
+    }
+  }
+
+  /// Reports `SessionExpired` to the listener the stream was accepted from.
+  pub fn with_listener(mut self, queue: Arc<EventQueue<ListenerEvent>>, conv: u32) -> Self {
+    self.listener = Some(ListenerLink { queue, conv });
+    self
+  }
+
+  pub fn connected(&self, addr: SocketAddr) {
+    *self.remote.lock().unwrap() = Some(addr);
+    self.queue.push(StreamEvent::Connected {
+      addr: addr.to_string(),
+    });
+  }
+
+  /// Records a read or write of `n` bytes.
+  pub fn activity(&self, n: usize) {
+    if n == 0 {
+      return;
+    }
+    let nanos = self.created.elapsed().as_nanos() as u64;
+    self.last_activity_nanos.store(nanos, Ordering::Relaxed);
+    self.idle_reported.store(false, Ordering::Relaxed);
+  }
+
+  pub fn data_received(&self, n: usize) {
+    self.activity(n);
+    if n > 0 && !self.first_data.swap(true, Ordering::Relaxed) {
+      self.queue.push(StreamEvent::FirstDataReceived);
+    }
+  }
+
+  pub fn end_of_session(&self) {
+    if self.shut_down.load(Ordering::Relaxed) || self.ended.swap(true, Ordering::Relaxed) {
+      return;
+    }
+    self.queue.push(StreamEvent::Expired);
+
+    let remote = *self.remote.lock().unwrap();
+    if let (Some(listener), Some(addr)) = (&self.listener, remote) {
+      listener.queue.push(ListenerEvent::SessionExpired {
+        addr: addr.to_string(),
+        conv: listener.conv,
+      });
+    }
+  }
+
+  pub fn failed(&self, reason: String) {
+    if self.ended.swap(true, Ordering::Relaxed) {
+      return;
+    }
+    self.queue.push(StreamEvent::Reset { reason });
+  }
+
+  pub fn shutdown(&self) {
+    if !self.shut_down.swap(true, Ordering::Relaxed) {
+      self.queue.push(StreamEvent::Shutdown);
+    }
+  }
+
+  pub fn set_idle_timeout(&self, millisec: u32) {
+    self
+      .idle_timeout_millisec
+      .store(millisec as u64, Ordering::Relaxed);
+    self.idle_timeout_changed.notify_waiters();
+  }
+
+  fn idle_deadline(&self) -> Option<Instant> {
+    let timeout = self.idle_timeout_millisec.load(Ordering::Relaxed);
+    if timeout == 0
+      || self.idle_reported.load(Ordering::Relaxed)
+      || self.ended.load(Ordering::Relaxed)
+    {
+      return None;
+    }
+    let last = Duration::from_nanos(self.last_activity_nanos.load(Ordering::Relaxed));
+    Some(self.created + last + Duration::from_millis(timeout))
+  }
+
+  /// Waits for the next event. Idle events are only found while waiting.
+  // Should be called within the tokio runtime.
+  pub async fn next(&self) -> StreamEvent {
+    loop {
+      let deadline = self.idle_deadline();
+      let idle = async {
+        match deadline {
+          Some(deadline) => tokio::time::sleep_until(deadline).await,
+          None => std::future::pending().await,
+        }
+      };
+
+      tokio::select! {
+        event = self.queue.next() => return event,
+        _ = idle => {
+          // Activity may have moved the deadline while sleeping.
+          if self.idle_deadline() == deadline && !self.idle_reported.swap(true, Ordering::Relaxed) {
+            let last = Duration::from_nanos(self.last_activity_nanos.load(Ordering::Relaxed));
+            let idle_millisec = self.created.elapsed().saturating_sub(last).as_millis() as u64;
+            return StreamEvent::Idle { idle_millisec };
+          }
+        }
+        _ = self.idle_timeout_changed.notified() => {}
+      }
+    }
+  }
+}
+
+#[test]
+fn test_stream_events() {
+  let rt = tokio::runtime::Runtime::new().unwrap();
+  let listener_queue: Arc<EventQueue<ListenerEvent>> = Default::default();
+  let events = StreamEvents::new(Some(50)).with_listener(listener_queue.clone(), 7);
+  let addr: SocketAddr = "127.0.0.1:3100".parse().unwrap();
+
+  events.connected(addr);
+  events.data_received(10);
+  events.data_received(10);
+
+  rt.block_on(async {
+    assert_eq!(
+      events.next().await,
+      StreamEvent::Connected {
+        addr: addr.to_string()
+      }
+    );
+    assert_eq!(events.next().await, StreamEvent::FirstDataReceived);
+    assert!(
+      matches!(events.next().await, StreamEvent::Idle { idle_millisec } if idle_millisec >= 50)
+    );
+
+    // Only the first end of a session is reported.
+    events.end_of_session();
+    events.failed("late".to_string());
+    assert_eq!(events.next().await, StreamEvent::Expired);
+    assert_eq!(
+      listener_queue.next().await,
+      ListenerEvent::SessionExpired {
+        addr: addr.to_string(),
+        conv: 7
+      }
+    );
+  });
+}
@@ -0,0 +1,127 @@
+use crate::error::SwiftKcpError;
+use crate::Result;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+use tokio_kcp::KcpSession;
+
+// Delay before the next candidate is tried, as recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+// Kcp has no handshake, window probes are sent until the peer answers one.
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+// Without any answer the first candidate that could be connected is used, the
+// peer may just not answer window probes.
+const RACE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Connects to `candidates` with a staggered start, the first session that
+/// gets an answer to its window probes wins and the others are dropped.
+/// `connect` returns the session of a candidate along with what the caller
+/// needs of it. Should be called within the tokio runtime.
+///
+/// tokio_kcp listeners answer the window probes of sessions they don't know
+/// without creating them, so the losing sessions leave nothing behind on the
+/// peer: no accepted session, no admission limit used up.
+pub async fn race<T, F, Fut>(candidates: Vec<SocketAddr>, connect: F) -> Result<(SocketAddr, T)>
+where
+  T: Send + 'static,
+  F: Fn(SocketAddr) -> Fut,
+  Fut: Future<Output = Result<(T, Arc<KcpSession>)>> + Send + 'static,
+{
+  let deadline = Instant::now() + RACE_TIMEOUT;
+  let mut attempts = JoinSet::new();
+  let mut remaining = candidates.into_iter().enumerate();
+  let mut start_next = |attempts: &mut JoinSet<_>| match remaining.next() {
+    Some((index, addr)) => {
+      let attempt = connect(addr);
+      attempts.spawn(async move { (index, addr, probe(attempt, deadline).await) });
+      true
+    }
+    None => false,
+  };
+
+  start_next(&mut attempts);
+  let mut more = true;
+  // (index, addr, connected) of the first candidate without an answer.
+  let mut fallback: Option<(usize, SocketAddr, T)> = None;
+  let mut last_err = None;
+  let next_attempt = time::sleep(ATTEMPT_DELAY);
+  tokio::pin!(next_attempt);
+
+  loop {
+    tokio::select! {
+      joined = attempts.join_next() => {
+        let Some(joined) = joined else {
+          if !more {
+            break;
+          }
+          // Every attempt failed before the delay, try the next one now.
+          more = start_next(&mut attempts);
+          next_attempt.as_mut().reset(Instant::now() + ATTEMPT_DELAY);
+          continue;
+        };
+        match joined {
+          Ok((_, addr, Ok((connected, true)))) => {
+            tracing::debug!(%addr, "candidate answered first");
+            attempts.abort_all();
+            return Ok((addr, connected));
+          }
+          Ok((index, addr, Ok((connected, false)))) => {
+            if !matches!(fallback, Some((first, ..)) if first <= index) {
+              fallback = Some((index, addr, connected));
+            }
+          }
+          Ok((_, addr, Err(e))) => {
+            tracing::debug!(%addr, error = %e, "candidate failed");
+            last_err = Some(e);
+            if more {
+              more = start_next(&mut attempts);
+              next_attempt.as_mut().reset(Instant::now() + ATTEMPT_DELAY);
+            }
+          }
+          Err(e) => last_err = Some(e.into()),
+        }
+      }
+      _ = &mut next_attempt, if more => {
+        more = start_next(&mut attempts);
+        next_attempt.as_mut().reset(Instant::now() + ATTEMPT_DELAY);
+      }
+    }
+  }
+
+  match (fallback, last_err) {
+    (Some((_, addr, connected)), _) => {
+      tracing::debug!(%addr, "no candidate answered, using the first one");
+      Ok((addr, connected))
+    }
+    (None, Some(e)) => Err(e),
+    (None, None) => Err(SwiftKcpError::ResolveFailed {
+      host: String::new(),
+      msg: "no usable address".to_string(),
+    }),
+  }
+}
+
+// Connects and probes until the peer answers or `deadline` passes. Returns
+// whether the peer answered.
+async fn probe<T>(
+  attempt: impl Future<Output = Result<(T, Arc<KcpSession>)>>,
+  deadline: Instant,
+) -> Result<(T, bool)> {
+  let (connected, session) = attempt.await?;
+
+  loop {
+    session.send_window_probe()?;
+    let wait = deadline.min(Instant::now() + PROBE_INTERVAL);
+    tokio::select! {
+      _ = session.wait_received() => return Ok((connected, true)),
+      _ = time::sleep_until(wait) => {
+        if Instant::now() >= deadline {
+          return Ok((connected, false));
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,166 @@
+use crate::kcp_util::ImpairmentParams;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+// Datagrams are dropped once this much is queued behind the bandwidth cap.
+const QUEUE_LIMIT: Duration = Duration::from_secs(1);
+// Extra delay of reordered datagrams.
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+
+// splitmix64, good enough for coin flips and reproducible from a seed.
+struct Rng(u64);
+
+impl Rng {
+  fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  // Uniform in [0, 1).
+  fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  fn chance(&mut self, percent: f64) -> bool {
+    percent > 0.0 && self.next_f64() * 100.0 < percent
+  }
+}
+
+struct State {
+  rng: Rng,
+  // When the simulated link is done sending what's queued.
+  link_free_at: Instant,
+}
+
+/// Drops, delays, duplicates and reorders datagrams of one direction.
+pub struct Impairment {
+  params: ImpairmentParams,
+  state: StdMutex<State>,
+}
+
+impl Impairment {
+  pub fn new(params: ImpairmentParams) -> Self {
+    let seed = params.seed.unwrap_or_else(|| {
+      SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+    });
+
+    Self {
+      params,
+      state: StdMutex::new(State {
+        rng: Rng(seed),
+        link_free_at: Instant::now(),
+      }),
+    }
+  }
+
+  /// Delays after which the copies of a datagram of `len` bytes are
+  /// delivered, empty if it's lost.
+  pub fn schedule(&self, len: usize) -> Vec<Duration> {
+    self.schedule_at(len, Instant::now())
+  }
+
+  fn schedule_at(&self, len: usize, now: Instant) -> Vec<Duration> {
+    let params = &self.params;
+    let mut state = self.state.lock().unwrap();
+
+    if state.rng.chance(params.loss_percent) {
+      return Vec::new();
+    }
+
+    let mut delay = Duration::ZERO;
+    if params.bandwidth_bytes_per_sec > 0 {
+      let start = state.link_free_at.max(now);
+      if start - now > QUEUE_LIMIT {
+        return Vec::new();
+      }
+      let transmit = Duration::from_secs_f64(len as f64 / params.bandwidth_bytes_per_sec as f64);
+      state.link_free_at = start + transmit;
+      delay = state.link_free_at - now;
+    }
+
+    delay += Duration::from_millis(params.latency_millisec as u64);
+    if params.jitter_millisec > 0 {
+      let jitter = state.rng.next_f64() * params.jitter_millisec as f64;
+      delay += Duration::from_secs_f64(jitter / 1000.0);
+    }
+    if state.rng.chance(params.reorder_percent) {
+      delay += REORDER_DELAY;
+    }
+
+    match state.rng.chance(params.duplicate_percent) {
+      true => vec![delay, delay],
+      false => vec![delay],
+    }
+  }
+
+  /// Sends `packet` to `target` as the impairment decides.
+  // Should be called within the tokio runtime.
+  pub async fn forward(&self, socket: &Arc<UdpSocket>, packet: &[u8], target: SocketAddr) {
+    for delay in self.schedule(packet.len()) {
+      if delay.is_zero() {
+        let _ = socket.send_to(packet, target).await;
+        continue;
+      }
+
+      let (socket, packet) = (socket.clone(), packet.to_vec());
+      tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let _ = socket.send_to(&packet, target).await;
+      });
+    }
+  }
+}
+
+#[test]
+fn test_impairment_schedule() {
+  let params = ImpairmentParams {
+    loss_percent: 10.0,
+    latency_millisec: 50,
+    duplicate_percent: 5.0,
+    seed: Some(42),
+    ..Default::default()
+  };
+  let count = |impairment: &Impairment| {
+    let now = Instant::now();
+    (0..1000)
+      .map(|_| impairment.schedule_at(100, now))
+      .map(|delays| {
+        assert!(delays
+          .iter()
+          .all(|delay| *delay == Duration::from_millis(50)));
+        delays.len()
+      })
+      .collect::<Vec<_>>()
+  };
+
+  // The same seed makes the same decisions.
+  let copies = count(&Impairment::new(params.clone()));
+  assert_eq!(copies, count(&Impairment::new(params)));
+  let lost = copies.iter().filter(|n| **n == 0).count();
+  let duplicated = copies.iter().filter(|n| **n == 2).count();
+  assert!((50..150).contains(&lost), "lost {}", lost);
+  assert!((20..80).contains(&duplicated), "duplicated {}", duplicated);
+
+  // 1000 bytes per second queue up 100 bytes for 100ms each.
+  let capped = Impairment::new(ImpairmentParams {
+    bandwidth_bytes_per_sec: 1000,
+    ..Default::default()
+  });
+  let now = Instant::now();
+  assert_eq!(
+    capped.schedule_at(100, now),
+    vec![Duration::from_millis(100)]
+  );
+  assert_eq!(
+    capped.schedule_at(100, now),
+    vec![Duration::from_millis(200)]
+  );
+}
@@ -1,70 +1,492 @@
+use crate::compression::KcpCompression;
+use crate::error::SwiftKcpError;
+use crate::resolve::IpPreference;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
 use std::time;
 use tokio_kcp::KcpConfig;
 
-#[derive(uniffi::Record, Default)]
+// Unset fields are left out when serialized, and default to unset when
+// deserialized.
+#[derive(uniffi::Record, Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct KcpConfigParams {
   /// Max Transmission Unit
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub mtu: Option<i16>,
   /// Enable nodelay
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub nodelay: Option<bool>,
   /// Internal update interval (ms)
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub nodelay_interval: Option<i32>,
   /// ACK number to enable fast resend
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub nodelay_resend: Option<i32>,
   /// Disable congetion control
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub nodelay_nc: Option<bool>,
   /// Send window size
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub window_size_send: Option<u16>,
-  /// Recv window size
+  /// Recv window size, at least 128
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub window_size_recv: Option<u16>,
   /// Session expire duration, default is 90 seconds
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub session_expire_milisec: Option<u32>,
   /// Flush KCP state immediately after write
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub flush_write: Option<bool>,
   /// Flush ACKs immediately after input
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub flush_acks_input: Option<bool>,
   /// Stream mode
+  #[serde(skip_serializing_if = "Option::is_none")]
   pub stream: Option<bool>,
+  /// Payload compression, both sides should use the same mode
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub compression: Option<KcpCompression>,
+  /// Set SO_REUSEADDR on the UDP socket
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reuse_address: Option<bool>,
+  /// Set SO_REUSEPORT on the UDP socket, unix only
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub reuse_port: Option<bool>,
+  /// UDP socket send buffer size (SO_SNDBUF)
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub send_buffer_size: Option<u32>,
+  /// UDP socket recv buffer size (SO_RCVBUF)
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub recv_buffer_size: Option<u32>,
+  /// IP TOS byte (IPv4) or traffic class (IPv6), DSCP is the upper 6 bits
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ip_tos: Option<u8>,
+  /// IPV6_V6ONLY of IPv6 sockets, set false to also serve IPv4 on "[::]"
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ipv6_only: Option<bool>,
+  /// Address families `new_stream` connects to and the order it tries them
+  /// in, an address literal of an excluded family is refused
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub ip_preference: Option<IpPreference>,
+  /// Upload limit of each stream in bytes per second, 0 means unlimited
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rate_limit_bytes_per_sec: Option<u64>,
+  /// Bytes that may be sent at once after idling, defaults to one second worth
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub rate_limit_burst: Option<u64>,
+  /// Adapt the upload rate, send window and fast resend threshold of each
+  /// stream to its RTT, retransmissions and send queue, see
+  /// `get_stream_adaptive_stats`. `window_size_send` and `nodelay_resend` are
+  /// the upper bound and the base, can't be combined with `rate_limit_bytes_per_sec`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub adaptive_pacing: Option<bool>,
+  /// Lower bound of the adaptive rate in bytes per second, defaults to 1/16 of
+  /// the upper bound
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub adaptive_min_bytes_per_sec: Option<u64>,
+  /// Upper bound and initial value of the adaptive rate in bytes per second
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub adaptive_max_bytes_per_sec: Option<u64>,
+  /// Once connected, probe the path with padded window probes of the session
+  /// and lower `mtu` to the largest size the peer answers unfragmented, see
+  /// `get_stream_path_mtu`. Delays the connection by up to a few seconds if
+  /// the peer doesn't answer. Off by default. Only used by `new_stream`,
+  /// `new_stream_with_local` and `new_stream_from_fd`, and skipped on
+  /// streams relayed for `capturable` or `impairment`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub path_mtu_discovery: Option<bool>,
+  /// Send the datagrams through an in-process relay so that `start_capture`
+  /// can record them, at the cost of a loopback hop per datagram. Listeners
+  /// are relayed as a whole, `start_listener_capture` records all their
+  /// sessions and `start_capture` one accepted stream. Streams of endpoints
+  /// can always be captured
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub capturable: Option<bool>,
+  /// Report `StreamEvent::Idle` after this long without reads or writes, 0
+  /// disables it. Can be changed with `update_stream_config`
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub idle_timeout_milisec: Option<u32>,
+  /// Simulate a bad network on this stream or listener, both directions are
+  /// impaired. For tests only, rejected with `InvalidConfig` unless the
+  /// bindings are built with the `impairment` cargo feature
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub impairment: Option<ImpairmentParams>,
 }
 
-impl Into<KcpConfig> for KcpConfigParams {
-  fn into(self) -> KcpConfig {
+/// Impairments applied to every datagram, each direction on its own. Zero
+/// disables an impairment.
+#[derive(uniffi::Record, Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImpairmentParams {
+  /// Share of datagrams dropped, 0 to 100
+  pub loss_percent: f64,
+  /// Delay added to every datagram
+  pub latency_millisec: u32,
+  /// Random extra delay, up to this much
+  pub jitter_millisec: u32,
+  /// Share of datagrams delivered twice, 0 to 100
+  pub duplicate_percent: f64,
+  /// Share of datagrams held back so that later ones overtake them, 0 to 100
+  pub reorder_percent: f64,
+  /// Link speed, datagrams queue up behind it and are dropped once a second
+  /// of them is queued
+  pub bandwidth_bytes_per_sec: u64,
+  /// Seed of the random decisions, the same seed drops, duplicates and delays
+  /// the same datagrams. Random if unset
+  pub seed: Option<u64>,
+}
+
+// kcp silently raises smaller receive windows to this.
+const MIN_WINDOW_SIZE_RECV: u16 = 128;
+
+fn invalid(field: &str, reason: &str) -> SwiftKcpError {
+  SwiftKcpError::InvalidConfig {
+    field: field.to_string(),
+    reason: reason.to_string(),
+  }
+}
+
+impl KcpConfigParams {
+  /// Rejects values kcp would refuse, or silently clamp or ignore.
+  pub fn validate(&self) -> Result<()> {
+    if self.mtu.is_some_and(|mtu| mtu < 50) {
+      return Err(invalid("mtu", "must be at least 50"));
+    }
+    if self
+      .nodelay_interval
+      .is_some_and(|interval| !(10..=5000).contains(&interval))
+    {
+      return Err(invalid("nodelay_interval", "must be between 10 and 5000"));
+    }
+    if self.nodelay_resend.is_some_and(|resend| resend < 0) {
+      return Err(invalid("nodelay_resend", "must not be negative"));
+    }
+    if self.window_size_send == Some(0) {
+      return Err(invalid("window_size_send", "must be positive"));
+    }
+    if self
+      .window_size_recv
+      .is_some_and(|recv| recv < MIN_WINDOW_SIZE_RECV)
+    {
+      return Err(invalid("window_size_recv", "must be at least 128"));
+    }
+    if self.session_expire_milisec == Some(0) {
+      return Err(invalid("session_expire_milisec", "must be positive"));
+    }
+    if self.send_buffer_size == Some(0) {
+      return Err(invalid("send_buffer_size", "must be positive"));
+    }
+    if self.recv_buffer_size == Some(0) {
+      return Err(invalid("recv_buffer_size", "must be positive"));
+    }
+    if self.rate_limit_burst.is_some() && self.rate_limit_bytes_per_sec.is_none() {
+      return Err(invalid(
+        "rate_limit_burst",
+        "requires rate_limit_bytes_per_sec",
+      ));
+    }
+    if self.adaptive_pacing == Some(true) {
+      let max = match self.adaptive_max_bytes_per_sec {
+        Some(max) if max > 0 => max,
+        _ => {
+          return Err(invalid(
+            "adaptive_max_bytes_per_sec",
+            "must be positive when adaptive_pacing is enabled",
+          ))
+        }
+      };
+      if self
+        .adaptive_min_bytes_per_sec
+        .is_some_and(|min| min == 0 || min > max)
+      {
+        return Err(invalid(
+          "adaptive_min_bytes_per_sec",
+          "must be positive and not above adaptive_max_bytes_per_sec",
+        ));
+      }
+      if self.rate_limit_bytes_per_sec.is_some_and(|rate| rate > 0) {
+        return Err(invalid(
+          "rate_limit_bytes_per_sec",
+          "can't be combined with adaptive_pacing",
+        ));
+      }
+    }
+    if let Some(impairment) = &self.impairment {
+      if !cfg!(feature = "impairment") {
+        return Err(invalid(
+          "impairment",
+          "the bindings are built without the impairment feature",
+        ));
+      }
+      let percents = [
+        ("impairment.loss_percent", impairment.loss_percent),
+        ("impairment.duplicate_percent", impairment.duplicate_percent),
+        ("impairment.reorder_percent", impairment.reorder_percent),
+      ];
+      for (field, percent) in percents {
+        if !(0.0..=100.0).contains(&percent) {
+          return Err(invalid(field, "must be between 0 and 100"));
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Names of the presets, the kcptun modes plus `default`, `turbo` and
+/// `low_bandwidth`.
+pub const PRESET_NAMES: [&str; 7] = [
+  "default",
+  "normal",
+  "fast",
+  "fast2",
+  "fast3",
+  "turbo",
+  "low_bandwidth",
+];
+
+impl KcpConfigParams {
+  /// Kcp parameters of a named preset, other fields are left unset. `default`
+  /// spells out the defaults of tokio_kcp.
+  pub fn preset(name: &str) -> Result<Self> {
+    // (nodelay, interval, resend, nc, send window, recv window)
+    let (nodelay, interval, resend, nc, send, recv) = match name {
+      "default" => (false, 40, 0, false, 256, 256),
+      "normal" => (false, 40, 2, true, 256, 256),
+      "fast" => (false, 30, 2, true, 256, 256),
+      "fast2" => (true, 20, 2, true, 256, 256),
+      "fast3" => (true, 10, 2, true, 256, 256),
+      // fast3 with larger windows for paths with a high bandwidth-delay product
+      "turbo" => (true, 10, 2, true, 1024, 1024),
+      // Keeps congestion control and a small send window to not flood slow links
+      "low_bandwidth" => (false, 40, 2, false, 32, 128),
+      _ => {
+        return Err(invalid(
+          "preset",
+          &format!(
+            "unknown preset {}, expected one of {}",
+            name,
+            PRESET_NAMES.join(", ")
+          ),
+        ))
+      }
+    };
+
+    Ok(Self {
+      mtu: Some(1400),
+      nodelay: Some(nodelay),
+      nodelay_interval: Some(interval),
+      nodelay_resend: Some(resend),
+      nodelay_nc: Some(nc),
+      window_size_send: Some(send),
+      window_size_recv: Some(recv),
+      ..Default::default()
+    })
+  }
+
+  /// Fields set in `overrides` replace the ones of `self`.
+  pub fn with_overrides(self, overrides: KcpConfigParams) -> Self {
+    macro_rules! merge {
+      ($($field:ident),* $(,)?) => {
+        Self {
+          $($field: overrides.$field.or(self.$field),)*
+          impairment: overrides.impairment.or(self.impairment),
+        }
+      };
+    }
+
+    merge!(
+      mtu,
+      nodelay,
+      nodelay_interval,
+      nodelay_resend,
+      nodelay_nc,
+      window_size_send,
+      window_size_recv,
+      session_expire_milisec,
+      flush_write,
+      flush_acks_input,
+      stream,
+      compression,
+      reuse_address,
+      reuse_port,
+      send_buffer_size,
+      recv_buffer_size,
+      ip_tos,
+      ipv6_only,
+      ip_preference,
+      rate_limit_bytes_per_sec,
+      rate_limit_burst,
+      adaptive_pacing,
+      adaptive_min_bytes_per_sec,
+      adaptive_max_bytes_per_sec,
+      path_mtu_discovery,
+      capturable,
+      idle_timeout_milisec,
+    )
+  }
+}
+
+fn parse_error<E: Display>(err: serde_path_to_error::Error<E>) -> SwiftKcpError {
+  SwiftKcpError::ConfigParse {
+    path: err.path().to_string(),
+    msg: err.into_inner().to_string(),
+  }
+}
+
+impl KcpConfigParams {
+  /// Parses and validates a JSON object like `{"mtu": 1200, "stream": true}`.
+  pub fn from_json(json: &str) -> Result<Self> {
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    let params: Self = serde_path_to_error::deserialize(&mut deserializer).map_err(parse_error)?;
+    deserializer.end()?;
+
+    params.validate()?;
+    Ok(params)
+  }
+
+  pub fn to_json(&self) -> Result<String> {
+    Ok(serde_json::to_string_pretty(self)?)
+  }
+
+  /// Parses and validates a TOML table like `mtu = 1200`.
+  pub fn from_toml(toml: &str) -> Result<Self> {
+    let deserializer = toml::Deserializer::new(toml);
+    let params: Self = serde_path_to_error::deserialize(deserializer).map_err(parse_error)?;
+
+    params.validate()?;
+    Ok(params)
+  }
+
+  pub fn to_toml(&self) -> Result<String> {
+    Ok(toml::to_string(self)?)
+  }
+}
+
+impl From<KcpConfigParams> for KcpConfig {
+  fn from(params: KcpConfigParams) -> Self {
     let mut config = KcpConfig::default();
+    params.apply_to(&mut config);
+    config
+  }
+}
 
-    if self.mtu.is_some() {
-      config.mtu = self.mtu.unwrap() as usize;
+impl KcpConfigParams {
+  /// Sets the fields of `config` that are set in `self`.
+  pub fn apply_to(&self, config: &mut KcpConfig) {
+    if let Some(mtu) = self.mtu {
+      config.mtu = mtu as usize;
     }
-    if self.nodelay.is_some() {
-      config.nodelay.nodelay = self.nodelay.unwrap();
+    if let Some(nodelay) = self.nodelay {
+      config.nodelay.nodelay = nodelay;
     }
-    if self.nodelay_interval.is_some() {
-      config.nodelay.interval = self.nodelay_interval.unwrap();
+    if let Some(interval) = self.nodelay_interval {
+      config.nodelay.interval = interval;
     }
-    if self.nodelay_resend.is_some() {
-      config.nodelay.resend = self.nodelay_resend.unwrap();
+    if let Some(resend) = self.nodelay_resend {
+      config.nodelay.resend = resend;
     }
-    if self.nodelay_nc.is_some() {
-      config.nodelay.nc = self.nodelay_nc.unwrap();
+    if let Some(nc) = self.nodelay_nc {
+      config.nodelay.nc = nc;
     }
-    if self.window_size_send.is_some() && self.window_size_recv.is_some() {
-      config.wnd_size = (
-        self.window_size_send.unwrap(),
-        self.window_size_recv.unwrap(),
-      )
+    if let Some(send) = self.window_size_send {
+      config.wnd_size.0 = send;
     }
-    if self.session_expire_milisec.is_some() {
-      let milisec = self.session_expire_milisec.unwrap();
+    if let Some(recv) = self.window_size_recv {
+      config.wnd_size.1 = recv;
+    }
+    if let Some(milisec) = self.session_expire_milisec {
       config.session_expire = time::Duration::from_millis(milisec as u64);
     }
-    if self.flush_write.is_some() {
-      config.flush_write = self.flush_write.unwrap();
+    if let Some(flush_write) = self.flush_write {
+      config.flush_write = flush_write;
     }
-    if self.flush_acks_input.is_some() {
-      config.flush_acks_input = self.flush_acks_input.unwrap();
+    if let Some(flush_acks_input) = self.flush_acks_input {
+      config.flush_acks_input = flush_acks_input;
     }
-    if self.stream.is_some() {
-      config.stream = self.stream.unwrap();
+    if let Some(stream) = self.stream {
+      config.stream = stream;
     }
-    config
   }
 }
+
+#[test]
+fn test_validate_params() {
+  assert!(KcpConfigParams::default().validate().is_ok());
+
+  let params = KcpConfigParams {
+    mtu: Some(-1),
+    ..Default::default()
+  };
+  assert!(matches!(
+    params.validate(),
+    Err(SwiftKcpError::InvalidConfig { field, .. }) if field == "mtu"
+  ));
+
+  let params = KcpConfigParams {
+    nodelay_interval: Some(-10),
+    ..Default::default()
+  };
+  assert!(params.validate().is_err());
+
+  let params = KcpConfigParams {
+    window_size_send: Some(1024),
+    ..Default::default()
+  };
+  assert!(params.validate().is_ok());
+  let config: KcpConfig = params.into();
+  assert_eq!(config.wnd_size.0, 1024);
+  assert_eq!(config.wnd_size.1, KcpConfig::default().wnd_size.1);
+
+  // The field exists in every build, only the feature makes it valid.
+  let params = KcpConfigParams {
+    impairment: Some(ImpairmentParams::default()),
+    ..Default::default()
+  };
+  assert_eq!(params.validate().is_ok(), cfg!(feature = "impairment"));
+}
+
+#[test]
+fn test_presets() {
+  for name in PRESET_NAMES {
+    assert!(KcpConfigParams::preset(name).unwrap().validate().is_ok());
+  }
+  assert!(KcpConfigParams::preset("fastest").is_err());
+
+  let params = KcpConfigParams::preset("fast3")
+    .unwrap()
+    .with_overrides(KcpConfigParams {
+      mtu: Some(1200),
+      stream: Some(true),
+      ..Default::default()
+    });
+  assert_eq!(params.mtu, Some(1200));
+  assert_eq!(params.stream, Some(true));
+  assert_eq!(params.nodelay_interval, Some(10));
+}
+
+#[test]
+fn test_config_files() {
+  let params = KcpConfigParams::preset("fast2")
+    .unwrap()
+    .with_overrides(KcpConfigParams {
+      compression: Some(KcpCompression::Lz4),
+      ip_preference: Some(IpPreference::PreferIpv4),
+      ..Default::default()
+    });
+  let json = params.to_json().unwrap();
+  assert_eq!(KcpConfigParams::from_json(&json).unwrap(), params);
+  let toml = params.to_toml().unwrap();
+  assert_eq!(KcpConfigParams::from_toml(&toml).unwrap(), params);
+
+  let path_of = |err: SwiftKcpError| match err {
+    SwiftKcpError::ConfigParse { path, .. } => path,
+    _ => panic!("unexpected error {}", err),
+  };
+  let err = KcpConfigParams::from_json(r#"{"mtu": 1200, "mtuu": 1}"#).unwrap_err();
+  assert_eq!(path_of(err), "mtuu");
+  let err = KcpConfigParams::from_toml("mtu = 1200\nwindow_size_send = -1").unwrap_err();
+  assert_eq!(path_of(err), "window_size_send");
+}
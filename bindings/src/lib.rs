@@ -1,67 +1,437 @@
 uniffi::include_scaffolding!("bindings");
 
+mod adaptive;
+mod admission;
+mod capture;
+mod compression;
+mod endpoint;
 mod error;
+mod events;
+mod happy_eyeballs;
+#[cfg(feature = "impairment")]
+mod impair;
 mod kcp_util;
+mod listener;
+mod logging;
 mod manager;
+mod memory;
+mod metrics;
+mod mux;
+mod pmtu;
+mod rate_limit;
+mod relay;
+mod resolve;
+mod socket;
+mod stream;
 
-use error::SwiftKcpError;
-pub use kcp_util::KcpConfigParams;
+pub use adaptive::{AdaptiveDecision, AdaptiveStats};
+pub use admission::{AdmissionParams, KcpAcceptFilter};
+use capture::Capture;
+pub use compression::{CompressionStats, KcpCompression};
+use endpoint::{ClientEndpoint, EndpointRoute};
+pub use error::SwiftKcpError;
+pub use events::{ListenerEvent, StreamEvent};
+pub use kcp_util::{ImpairmentParams, KcpConfigParams};
 use lazy_static::lazy_static;
-use manager::{Manager, StreamId};
-use std::net::SocketAddr;
+pub use listener::AddrFamily;
+use listener::SwiftKcpListener;
+pub use logging::{LogLevel, LogRecord, LogSink, SinkLayer};
+use manager::Manager;
+pub use manager::StreamId;
+use memory::MemoryNetwork;
+pub use mux::MuxConfigParams;
+use mux::{MuxSession, SubStreamId};
+pub use pmtu::PathMtu;
+pub use rate_limit::RateLimitStats;
+pub use resolve::{IpPreference, KcpResolver};
+use socket::{LocalSocket, SocketOptions};
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
+use stream::{StreamOptions, SwiftKcpStream};
 use tokio::{
-  io::{AsyncReadExt, AsyncWriteExt},
   runtime::Runtime,
   sync::{Mutex, RwLock},
+  task::JoinHandle,
 };
 use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
 
 type Result<T> = std::result::Result<T, error::SwiftKcpError>;
 
-const READ_BUF: usize = 65535;
-
 lazy_static! {
   static ref RUNTIME: Arc<RwLock<Option<Runtime>>> = Arc::new(RwLock::new(None));
-  static ref STREAM_MANAGER: Arc<Mutex<Manager<KcpStream>>> = Arc::new(Mutex::new(Manager::new()));
-  static ref LISTENER_MANAGER: Arc<Mutex<Manager<KcpListener>>> =
+  static ref STREAM_MANAGER: Arc<Mutex<Manager<SwiftKcpStream>>> =
+    Arc::new(Mutex::new(Manager::new()));
+  static ref LISTENER_MANAGER: Arc<Mutex<Manager<SwiftKcpListener>>> =
     Arc::new(Mutex::new(Manager::new()));
+  static ref MUX_MANAGER: Arc<Mutex<Manager<MuxSession>>> = Arc::new(Mutex::new(Manager::new()));
+  static ref ENDPOINT_MANAGER: Arc<Mutex<Manager<ClientEndpoint>>> =
+    Arc::new(Mutex::new(Manager::new()));
+  static ref RESOLVER: Arc<RwLock<Option<Arc<dyn KcpResolver>>>> = Arc::new(RwLock::new(None));
+  static ref METRICS_SERVER: Arc<Mutex<Option<JoinHandle<()>>>> = Arc::new(Mutex::new(None));
+  static ref MEMORY_NETWORK: Arc<MemoryNetwork> = Default::default();
 }
 
 #[uniffi::export]
-async fn init_runtime() -> Result<()> {
+pub async fn init_runtime() -> Result<()> {
   let rt = Runtime::new()?;
   let mut runtime = RUNTIME.write().await;
   let _ = runtime.insert(rt);
+  metrics::observe_sessions();
+  tracing::info!("runtime started");
 
   Ok(())
 }
 
 #[uniffi::export]
-async fn deinit_runtime() {
+pub async fn deinit_runtime() {
   let rt = {
     let mut runtime = RUNTIME.write().await;
 
     runtime.take()
   };
 
-  if rt.is_some() {
-    rt.unwrap()
-      .shutdown_timeout(std::time::Duration::from_secs(1));
+  if let Some(rt) = rt {
+    rt.shutdown_timeout(std::time::Duration::from_secs(1));
+    tracing::info!("runtime stopped");
+  }
+}
+
+// Metrics in the Prometheus text format: open streams, listeners, mux sessions
+// and endpoints, payload bytes, errors by variant, kcp retransmissions and a
+// histogram of kcp round trip times.
+#[uniffi::export]
+pub async fn metrics_snapshot() -> String {
+  let gauges = metrics::Gauges {
+    streams: STREAM_MANAGER.lock().await.len(),
+    listeners: LISTENER_MANAGER.lock().await.len(),
+    mux_sessions: MUX_MANAGER.lock().await.len(),
+    endpoints: ENDPOINT_MANAGER.lock().await.len(),
+  };
+
+  metrics::render(&gauges)
+}
+
+// Serves `metrics_snapshot()` at `GET /metrics` on `bind_addr_str`, e.g.
+// "127.0.0.1:9100". Returns the bound address. A running server is replaced.
+#[uniffi::export]
+pub async fn start_metrics_server(bind_addr_str: String) -> Result<String> {
+  let addr = SocketAddr::from_str(&bind_addr_str)?;
+
+  let join_handle = {
+    let rt = RUNTIME.read().await;
+    if rt.is_none() {
+      return Err(SwiftKcpError::RuntimeNotInited);
+    }
+    let rt = rt.as_ref().unwrap();
+    rt.spawn(async move { tokio::net::TcpListener::bind(addr).await })
+  };
+
+  let listener = join_handle.await??;
+  let local_addr = listener.local_addr()?;
+
+  let server = {
+    let rt = RUNTIME.read().await;
+    if rt.is_none() {
+      return Err(SwiftKcpError::RuntimeNotInited);
+    }
+    rt.as_ref().unwrap().spawn(metrics::serve(listener))
+  };
+  if let Some(old) = METRICS_SERVER.lock().await.replace(server) {
+    old.abort();
+  }
+  tracing::info!(%local_addr, "metrics server started");
+
+  Ok(local_addr.to_string())
+}
+
+#[uniffi::export]
+pub async fn stop_metrics_server() {
+  if let Some(server) = METRICS_SERVER.lock().await.take() {
+    server.abort();
+    tracing::info!("metrics server stopped");
   }
 }
 
+// Forwards logs of the bindings and tokio_kcp at `level` or above to `sink`.
+// Nothing is logged until a sink is set and `install_log_subscriber` is
+// called, or `SinkLayer` is added to a subscriber of the host.
+#[uniffi::export]
+pub fn set_log_sink(sink: Box<dyn LogSink>) {
+  logging::set_sink(Some(Arc::from(sink)));
+}
+
+#[uniffi::export]
+pub fn clear_log_sink() {
+  logging::set_sink(None);
+}
+
 #[uniffi::export]
-fn default_kcp_config_params() -> KcpConfigParams {
+pub fn set_log_level(level: LogLevel) {
+  logging::set_level(level);
+}
+
+// Opts in to a global tracing subscriber and log crate logger forwarding to
+// the sink. There's one of each per process: fails with `LoggerInstalled`
+// if the host or another library installed one, whose logs are kept.
+#[uniffi::export]
+pub fn install_log_subscriber() -> Result<()> {
+  logging::install()
+}
+
+#[uniffi::export]
+pub fn default_kcp_config_params() -> KcpConfigParams {
   KcpConfigParams::default()
 }
 
+// Kcp parameters of a named preset: "default", "normal", "fast", "fast2",
+// "fast3" (kcptun modes), "turbo" or "low_bandwidth".
 #[uniffi::export]
-async fn new_stream(addr_str: String, params: KcpConfigParams) -> Result<StreamId> {
-  let config: KcpConfig = params.into();
+pub fn kcp_config_preset(name: String) -> Result<KcpConfigParams> {
+  KcpConfigParams::preset(&name)
+}
+
+// A preset with the fields set in `overrides` replaced.
+#[uniffi::export]
+pub fn kcp_config_preset_with_overrides(
+  name: String,
+  overrides: KcpConfigParams,
+) -> Result<KcpConfigParams> {
+  Ok(KcpConfigParams::preset(&name)?.with_overrides(overrides))
+}
+
+#[uniffi::export]
+pub fn kcp_config_preset_names() -> Vec<String> {
+  kcp_util::PRESET_NAMES
+    .iter()
+    .map(|name| name.to_string())
+    .collect()
+}
+
+// Parses a config like `{"mtu": 1200, "stream": true}`, unset fields are
+// left out. Parsed configs are validated.
+#[uniffi::export]
+pub fn kcp_config_from_json(json: String) -> Result<KcpConfigParams> {
+  KcpConfigParams::from_json(&json)
+}
+
+#[uniffi::export]
+pub fn kcp_config_to_json(params: KcpConfigParams) -> Result<String> {
+  params.to_json()
+}
+
+// Same as `kcp_config_from_json` with a TOML table like `mtu = 1200`.
+#[uniffi::export]
+pub fn kcp_config_from_toml(toml: String) -> Result<KcpConfigParams> {
+  KcpConfigParams::from_toml(&toml)
+}
+
+#[uniffi::export]
+pub fn kcp_config_to_toml(params: KcpConfigParams) -> Result<String> {
+  params.to_toml()
+}
+
+// Entry points taking `KcpConfigParams` validate them as well, this reports
+// `InvalidConfig` errors before anything is connected.
+#[uniffi::export]
+pub fn validate_kcp_config_params(params: KcpConfigParams) -> Result<()> {
+  params.validate()
+}
+
+// Connects to `addr_str`, a host name is resolved first. When it resolves to
+// several addresses they are raced as in RFC 8305: a candidate is started
+// every 250ms and the first one whose peer answers a kcp window probe wins.
+#[uniffi::export]
+pub async fn new_stream(addr_str: String, params: KcpConfigParams) -> Result<StreamId> {
+  params.validate()?;
+  let preference = params.ip_preference.unwrap_or_default();
+  let addrs = resolve_addr(addr_str, preference).await?;
+
+  connect_stream(addrs, None, params).await
+}
+
+// Like `new_stream` but binds the local UDP socket to `local_addr_str`, e.g.
+// "192.168.1.2:0" to pick an interface or "0.0.0.0:4000" to fix the port.
+// Candidates aren't raced, there is only the one socket. "[::]" connects to
+// IPv4 addresses too unless `ipv6_only` is set.
+#[uniffi::export]
+pub async fn new_stream_with_local(
+  addr_str: String,
+  local_addr_str: String,
+  params: KcpConfigParams,
+) -> Result<StreamId> {
+  let mut params = params;
+  params.validate()?;
+  let local_addr = SocketAddr::from_str(&local_addr_str)?;
+  // "[::]" reaches IPv4 peers too, unless asked not to.
+  let dual_stack = match local_addr {
+    SocketAddr::V6(v6) => v6.ip().is_unspecified() && params.ipv6_only != Some(true),
+    SocketAddr::V4(..) => false,
+  };
+  let preference = match local_addr {
+    SocketAddr::V4(..) => IpPreference::Ipv4Only,
+    SocketAddr::V6(..) if dual_stack => params.ip_preference.unwrap_or_default(),
+    SocketAddr::V6(..) => IpPreference::Ipv6Only,
+  };
+  let addrs = resolve_addr(addr_str, preference).await?;
+  let mut addr = pick_addr(&addrs);
+  if let (true, IpAddr::V4(ip)) = (dual_stack, addr.ip()) {
+    addr = SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port());
+    params.ipv6_only = Some(false);
+  }
+
+  connect_stream(vec![addr], Some(LocalSocket::Addr(local_addr)), params).await
+}
+
+// Replaces system DNS resolution in `new_stream`.
+#[uniffi::export]
+pub async fn set_resolver(resolver: Box<dyn KcpResolver>) {
+  let _ = RESOLVER.write().await.insert(Arc::from(resolver));
+}
+
+#[uniffi::export]
+pub async fn clear_resolver() {
+  RESOLVER.write().await.take();
+}
+
+async fn resolve_addr(addr_str: String, preference: IpPreference) -> Result<Vec<SocketAddr>> {
+  let resolver = RESOLVER.read().await.clone();
+
+  let join_handle = {
+    let rt = RUNTIME.read().await;
+    if rt.is_none() {
+      return Err(SwiftKcpError::RuntimeNotInited);
+    }
+    let rt = rt.as_ref().unwrap();
+    rt.spawn(async move { resolve::resolve(&addr_str, preference, resolver).await })
+  };
+
+  join_handle.await?
+}
+
+// Used when a single local socket is given, only `new_stream` can race
+// candidates on sockets of their own. Kcp has no handshake, so fall back only
+// past addresses we have no route to.
+fn pick_addr(addrs: &[SocketAddr]) -> SocketAddr {
+  addrs
+    .iter()
+    .find(|addr| resolve::has_route(addr))
+    .unwrap_or(&addrs[0])
+    .to_owned()
+}
+
+// Connects with an already bound UDP socket. The bindings take ownership of
+// `fd` and close it when the stream is removed. A fd that is refused, e.g.
+// because it isn't a bound UDP socket or an option can't be set, stays open.
+// Unix only.
+#[uniffi::export]
+pub async fn new_stream_from_fd(
+  fd: i32,
+  addr_str: String,
+  params: KcpConfigParams,
+) -> Result<StreamId> {
+  params.validate()?;
   let addr = SocketAddr::from_str(&addr_str)?;
 
+  connect_stream(vec![addr], Some(LocalSocket::Fd(fd)), params).await
+}
+
+// How a client stream connects, taken from its params.
+#[derive(Clone)]
+struct ConnectOptions {
+  socket_options: SocketOptions,
+  path_mtu_discovery: bool,
+  capturable: bool,
+  impairment: Option<ImpairmentParams>,
+  config: KcpConfig,
+}
+
+// A connected kcp stream, before it's wrapped.
+struct Connected {
+  stream: KcpStream,
+  relay: Option<(ClientEndpoint, EndpointRoute)>,
+  config: KcpConfig,
+  discovered_mtu: Option<usize>,
+}
+
+// Should be called within the tokio runtime.
+async fn connect_candidate(
+  addr: SocketAddr,
+  local: LocalSocket,
+  options: ConnectOptions,
+) -> Result<Connected> {
+  let config = options.config;
+  let udp = local.into_udp(&options.socket_options)?;
+  if options.capturable || options.impairment.is_some() {
+    let endpoint = ClientEndpoint::from_udp(udp).await?;
+    let (stream, route) = endpoint.connect(&config, addr).await?;
+    #[cfg(feature = "impairment")]
+    if let Some(impairment) = options.impairment {
+      route.set_impairment(impairment);
+    }
+    return Ok(Connected {
+      stream,
+      relay: Some((endpoint, route)),
+      config,
+      discovered_mtu: None,
+    });
+  }
+  let stream = KcpStream::connect_with_socket(&config, udp, addr).await?;
+  Ok(Connected {
+    stream,
+    relay: None,
+    config,
+    discovered_mtu: None,
+  })
+}
+
+// Lowers the mtu of a connected stream to the path MTU, see `pmtu::discover`.
+// Should be called within the tokio runtime.
+async fn discover_path_mtu(addr: SocketAddr, connected: &mut Connected) -> Result<()> {
+  // The session sends to the relay over loopback, the DF bit and the errors of
+  // too big datagrams would stay on that hop.
+  if connected.relay.is_some() {
+    tracing::debug!(%addr, "path mtu discovery skipped on a relayed stream");
+    return Ok(());
+  }
+  let transport = connected
+    .stream
+    .session()
+    .kcp_socket()
+    .lock()
+    .transport()
+    .clone();
+  let Some(udp) = transport.udp_socket() else {
+    return Ok(());
+  };
+  let session = connected.stream.shared_session();
+  connected.discovered_mtu = pmtu::discover(&session, udp, addr, connected.config.mtu).await?;
+
+  if let Some(mtu) = connected.discovered_mtu {
+    tracing::debug!(%addr, mtu, "path mtu discovered");
+    connected.config.mtu = mtu;
+    session.set_config(&connected.config)?;
+  }
+  Ok(())
+}
+
+// Connects to the first of `candidates` that answers, see
+// `happy_eyeballs::race`. Without `local` every candidate gets an unspecified
+// socket of its own, with it there must be a single candidate.
+async fn connect_stream(
+  candidates: Vec<SocketAddr>,
+  local: Option<LocalSocket>,
+  params: KcpConfigParams,
+) -> Result<StreamId> {
+  let options = StreamOptions::from_params(&params);
+  let connect_options = ConnectOptions {
+    socket_options: SocketOptions::from_params(&params),
+    path_mtu_discovery: params.path_mtu_discovery.unwrap_or(false),
+    capturable: params.capturable.unwrap_or(false),
+    impairment: params.impairment.clone(),
+    config: params.into(),
+  };
+
   let join_handle = {
     let rt = RUNTIME.read().await;
     if rt.is_none() {
@@ -69,23 +439,161 @@ async fn new_stream(addr_str: String, params: KcpConfigParams) -> Result<StreamI
     }
     let rt = rt.as_ref().unwrap();
     rt.spawn(async move {
-      let stream = KcpStream::connect(&config, addr).await;
-      stream
+      let path_mtu_discovery = connect_options.path_mtu_discovery;
+      let (addr, mut connected) = match (local, candidates.as_slice()) {
+        (Some(local), [addr]) => Ok((
+          *addr,
+          connect_candidate(*addr, local, connect_options).await?,
+        )),
+        (None, [addr]) => {
+          let local = LocalSocket::Addr(socket::unspecified_addr(addr));
+          Ok((
+            *addr,
+            connect_candidate(*addr, local, connect_options).await?,
+          ))
+        }
+        (None, _) => {
+          happy_eyeballs::race(candidates, |addr| {
+            let local = LocalSocket::Addr(socket::unspecified_addr(&addr));
+            let connect_options = connect_options.clone();
+            async move {
+              let connected = connect_candidate(addr, local, connect_options).await?;
+              let session = connected.stream.shared_session();
+              Ok((connected, session))
+            }
+          })
+          .await
+        }
+        (Some(..), _) => unreachable!("a local socket is given with a single candidate"),
+      }?;
+
+      // Probed in-session once connected, only the chosen candidate is probed.
+      if path_mtu_discovery {
+        discover_path_mtu(addr, &mut connected).await?;
+      }
+      Ok((addr, connected))
     })
   };
 
-  let stream = join_handle.await??;
+  let (
+    addr,
+    Connected {
+      stream,
+      relay,
+      config,
+      discovered_mtu,
+    },
+  ) = join_handle.await?.inspect_err(metrics::record_error)?;
+  let mut stream = SwiftKcpStream::new(stream, &config, options).with_remote_addr(addr);
+  if let Some((endpoint, route)) = relay {
+    stream = stream.with_private_endpoint(endpoint, route);
+  }
+  if let Some(mtu) = discovered_mtu {
+    stream = stream.with_discovered_mtu(mtu);
+  }
 
   let id = {
     let mut manager = STREAM_MANAGER.lock().await;
     manager.insert_stream(stream)
   };
+  tracing::debug!(id, %addr, mtu = config.mtu, "stream connected");
+  metrics::record_connected();
+
+  Ok(id)
+}
+
+// Binds a UDP socket that can be shared by several client streams created with
+// `endpoint_connect`, so they use a single local port (and NAT mapping).
+#[uniffi::export]
+pub async fn new_endpoint(bind_addr_str: String) -> Result<StreamId> {
+  let addr = SocketAddr::from_str(&bind_addr_str)?;
+
+  let join_handle = {
+    let rt = RUNTIME.read().await;
+    if rt.is_none() {
+      return Err(SwiftKcpError::RuntimeNotInited);
+    }
+    let rt = rt.as_ref().unwrap();
+    rt.spawn(async move { ClientEndpoint::bind(addr).await })
+  };
+
+  let endpoint = join_handle.await?.inspect_err(metrics::record_error)?;
+
+  let id = ENDPOINT_MANAGER.lock().await.insert_stream(endpoint);
+
+  Ok(id)
+}
+
+#[uniffi::export]
+pub async fn endpoint_connect(
+  endpoint_id: StreamId,
+  addr_str: String,
+  params: KcpConfigParams,
+) -> Result<StreamId> {
+  params.validate()?;
+  let options = StreamOptions::from_params(&params);
+  #[cfg(feature = "impairment")]
+  let impairment = params.impairment.clone();
+  let config: KcpConfig = params.into();
+  let addr = SocketAddr::from_str(&addr_str)?;
+
+  let endpoint = ENDPOINT_MANAGER.lock().await.get_stream(endpoint_id);
+  if endpoint.is_none() {
+    return Err(SwiftKcpError::NoEndpointForId { id: endpoint_id });
+  }
+  let endpoint = endpoint.unwrap();
+
+  let join_handle = {
+    let rt = RUNTIME.read().await;
+    if rt.is_none() {
+      return Err(SwiftKcpError::RuntimeNotInited);
+    }
+    let rt = rt.as_ref().unwrap();
+    rt.spawn(async move { endpoint.connect(&config, addr).await })
+  };
+
+  let (stream, route) = join_handle.await?.inspect_err(metrics::record_error)?;
+  #[cfg(feature = "impairment")]
+  if let Some(impairment) = impairment {
+    route.set_impairment(impairment);
+  }
+  let stream = SwiftKcpStream::new(stream, &config, options)
+    .with_endpoint_route(route)
+    .with_remote_addr(addr);
+
+  let id = STREAM_MANAGER.lock().await.insert_stream(stream);
+  metrics::record_connected();
 
   Ok(id)
 }
 
+// Streams connected through the endpoint stop receiving data once it's removed.
+#[uniffi::export]
+pub async fn remove_endpoint(id: StreamId) -> Result<()> {
+  let endpoint = ENDPOINT_MANAGER.lock().await.remove_stream(id);
+
+  if endpoint.is_none() {
+    return Err(SwiftKcpError::NoEndpointForId { id });
+  }
+
+  Ok(())
+}
+
+#[uniffi::export]
+pub async fn endpoint_local_addr(id: StreamId) -> Result<String> {
+  let endpoint = ENDPOINT_MANAGER.lock().await.get_stream(id);
+
+  if endpoint.is_none() {
+    return Err(SwiftKcpError::NoEndpointForId { id });
+  }
+
+  let addr = endpoint.unwrap().local_addr()?;
+
+  Ok(addr.to_string())
+}
+
 #[uniffi::export]
-async fn remove_stream(id: StreamId) -> Result<()> {
+pub async fn remove_stream(id: StreamId) -> Result<()> {
   let stream = {
     let mut manager = STREAM_MANAGER.lock().await;
     manager.remove_stream(id)
@@ -94,12 +602,13 @@ async fn remove_stream(id: StreamId) -> Result<()> {
   if stream.is_none() {
     return Err(SwiftKcpError::NoStreamForId { id });
   }
+  tracing::debug!(id, "stream removed");
 
   Ok(())
 }
 
 #[uniffi::export]
-async fn write_stream(id: StreamId, data: Vec<u8>) -> Result<()> {
+pub async fn write_stream(id: StreamId, data: Vec<u8>) -> Result<()> {
   let rt = RUNTIME.read().await;
   if rt.is_none() {
     return Err(SwiftKcpError::RuntimeNotInited);
@@ -109,56 +618,85 @@ async fn write_stream(id: StreamId, data: Vec<u8>) -> Result<()> {
   rt.spawn(async move {
     let stream = {
       let manager = STREAM_MANAGER.lock().await;
-      manager.get_mut_stream(id)
+      manager.get_stream(id)
     };
     if stream.is_none() {
       return Err(SwiftKcpError::NoStreamForId { id });
     }
     let stream = stream.unwrap();
-    stream.lock().await.write_all(&data).await?;
+    tracing::trace!(id, len = data.len(), "write");
+    stream.write(&data).await?;
+    metrics::record_bytes_out(data.len());
 
     Ok(())
   })
-  .await??;
+  .await?
+  .inspect_err(metrics::record_error)?;
 
   Ok(())
 }
 
 #[uniffi::export]
-async fn read_stream(id: StreamId) -> Result<Vec<u8>> {
+pub async fn read_stream(id: StreamId) -> Result<Vec<u8>> {
   let rt = RUNTIME.read().await;
   if rt.is_none() {
     return Err(SwiftKcpError::RuntimeNotInited);
   }
   let rt = rt.as_ref().unwrap();
 
-  let (n, buf) = rt
+  let data = rt
     .spawn(async move {
       let stream = {
         let manager = STREAM_MANAGER.lock().await;
-        manager.get_mut_stream(id)
+        manager.get_stream(id)
       };
       if stream.is_none() {
         return Err(SwiftKcpError::NoStreamForId { id });
       }
       let stream = stream.unwrap();
-      let mut buf: Vec<u8> = vec![0; READ_BUF];
-      let n = stream.lock().await.read(&mut buf).await?;
-      Ok((n, buf))
+      let data = stream.read().await?;
+      tracing::trace!(id, len = data.len(), "read");
+      metrics::record_bytes_in(data.len());
+      Ok(data)
     })
-    .await??;
+    .await?
+    .inspect_err(metrics::record_error)?;
 
-  Ok(buf[0..n].to_vec())
+  Ok(data)
 }
 
 #[uniffi::export]
-async fn get_stream_count() -> u32 {
+pub async fn get_stream_count() -> u32 {
   STREAM_MANAGER.lock().await.len() as u32
 }
 
+// Waits for the next state change of a stream. Events are queued from the
+// moment the stream is created, so `Connected` is always the first one.
+#[uniffi::export]
+pub async fn next_event(id: StreamId) -> Result<StreamEvent> {
+  let rt = RUNTIME.read().await;
+  if rt.is_none() {
+    return Err(SwiftKcpError::RuntimeNotInited);
+  }
+  let rt = rt.as_ref().unwrap();
+
+  let event = rt
+    .spawn(async move {
+      let stream = STREAM_MANAGER.lock().await.get_stream(id);
+      if stream.is_none() {
+        return Err(SwiftKcpError::NoStreamForId { id });
+      }
+      Ok(stream.unwrap().next_event().await)
+    })
+    .await?
+    .inspect_err(metrics::record_error)?;
+
+  Ok(event)
+}
+
 // Shuts down the output stream, ensuring that the value can be dropped cleanly.
 #[uniffi::export]
-async fn shutdown_stream(id: StreamId) -> Result<()> {
+pub async fn shutdown_stream(id: StreamId) -> Result<()> {
   let rt = RUNTIME.read().await;
   if rt.is_none() {
     return Err(SwiftKcpError::RuntimeNotInited);
@@ -168,18 +706,19 @@ async fn shutdown_stream(id: StreamId) -> Result<()> {
   rt.spawn(async move {
     let stream = {
       let manager = STREAM_MANAGER.lock().await;
-      manager.get_mut_stream(id)
+      manager.get_stream(id)
     };
     if stream.is_none() {
       return Err(SwiftKcpError::NoStreamForId { id });
     }
 
     let stream = stream.unwrap();
-    stream.lock().await.shutdown().await?;
+    stream.shutdown().await?;
 
     Ok(())
   })
-  .await??;
+  .await?
+  .inspect_err(metrics::record_error)?;
 
   Ok(())
 }
@@ -187,7 +726,7 @@ async fn shutdown_stream(id: StreamId) -> Result<()> {
 // Call kcp flush behind. Note that this method won't guarantee data is transfered to
 // the remove side.
 #[uniffi::export]
-async fn flush_stream(id: StreamId) -> Result<()> {
+pub async fn flush_stream(id: StreamId) -> Result<()> {
   let rt = RUNTIME.read().await;
   if rt.is_none() {
     return Err(SwiftKcpError::RuntimeNotInited);
@@ -197,25 +736,26 @@ async fn flush_stream(id: StreamId) -> Result<()> {
   rt.spawn(async move {
     let stream = {
       let manager = STREAM_MANAGER.lock().await;
-      manager.get_mut_stream(id)
+      manager.get_stream(id)
     };
     if stream.is_none() {
       return Err(SwiftKcpError::NoStreamForId { id });
     }
 
     let stream = stream.unwrap();
-    stream.lock().await.flush().await?;
+    stream.flush().await?;
 
     Ok(())
   })
-  .await??;
+  .await?
+  .inspect_err(metrics::record_error)?;
 
   Ok(())
 }
 
 // NOTE: Empty operation.
 #[uniffi::export]
-async fn read_exact_stream(id: StreamId, len: u32) -> Result<Vec<u8>> {
+pub async fn read_exact_stream(id: StreamId, len: u32) -> Result<Vec<u8>> {
   let rt = RUNTIME.read().await;
   if rt.is_none() {
     return Err(SwiftKcpError::RuntimeNotInited);
@@ -226,28 +766,115 @@ async fn read_exact_stream(id: StreamId, len: u32) -> Result<Vec<u8>> {
     .spawn(async move {
       let stream = {
         let manager = STREAM_MANAGER.lock().await;
-        manager.get_mut_stream(id)
+        manager.get_stream(id)
       };
       if stream.is_none() {
         return Err(SwiftKcpError::NoStreamForId { id });
       }
 
       let stream = stream.unwrap();
-      let mut data: Vec<u8> = vec![0; len as usize];
-
-      stream.lock().await.read_exact(&mut data).await?;
-
+      let data = stream.read_exact(len as usize).await?;
+      metrics::record_bytes_in(data.len());
       Ok(data)
     })
-    .await??;
+    .await?
+    .inspect_err(metrics::record_error)?;
 
   Ok(data)
 }
 
 #[uniffi::export]
-async fn new_listener(bind_addr_str: String, params: KcpConfigParams) -> Result<StreamId> {
+pub async fn new_listener(bind_addr_str: String, params: KcpConfigParams) -> Result<StreamId> {
+  let addr = SocketAddr::from_str(&bind_addr_str)?;
+
+  bind_listener(vec![LocalSocket::Addr(addr)], params).await
+}
+
+// Binds every address in `bind_addr_strs` and accepts from all of them with one
+// listener id, e.g. ["0.0.0.0:3100", "[::]:3100"] with `ipv6_only` set.
+// Binding "[::]:3100" alone with `ipv6_only` false also serves both families.
+#[uniffi::export]
+pub async fn new_listener_multi(
+  bind_addr_strs: Vec<String>,
+  params: KcpConfigParams,
+) -> Result<StreamId> {
+  let mut locals = Vec::new();
+  for bind_addr_str in bind_addr_strs {
+    locals.push(LocalSocket::Addr(SocketAddr::from_str(&bind_addr_str)?));
+  }
+  if locals.is_empty() {
+    return Err(SwiftKcpError::Default {
+      msg: "no address to bind".to_string(),
+    });
+  }
+
+  bind_listener(locals, params).await
+}
+
+// Listens on an already bound UDP socket. The bindings take ownership of `fd`
+// and close it when the listener is removed, refused fds stay open like in
+// `new_stream_from_fd`. Unix only.
+#[uniffi::export]
+pub async fn new_listener_from_fd(fd: i32, params: KcpConfigParams) -> Result<StreamId> {
+  bind_listener(vec![LocalSocket::Fd(fd)], params).await
+}
+
+async fn bind_listener(locals: Vec<LocalSocket>, params: KcpConfigParams) -> Result<StreamId> {
+  params.validate()?;
+  let options = StreamOptions::from_params(&params);
+  let socket_options = SocketOptions::from_params(&params);
+  let impairment = params.impairment.clone();
+  let relayed = params.capturable == Some(true) || impairment.is_some();
   let config: KcpConfig = params.into();
+
+  let join_handle = {
+    let rt = RUNTIME.read().await;
+    if rt.is_none() {
+      return Err(SwiftKcpError::RuntimeNotInited);
+    }
+    let rt = rt.as_ref().unwrap();
+    rt.spawn(async move {
+      let mut listeners = Vec::new();
+      for local in locals {
+        let udp = local.into_udp(&socket_options)?;
+        if relayed {
+          let inner = tokio::net::UdpSocket::bind("127.0.0.1:0").await?;
+          let peer_idle = config.session_expire * 2;
+          let relay = relay::relay_listener(udp, inner.local_addr()?, peer_idle)?;
+          #[cfg(feature = "impairment")]
+          if let Some(impairment) = impairment.clone() {
+            relay.routes.set_impairment(impairment);
+          }
+          listeners.push((KcpListener::from_socket(config, inner).await?, Some(relay)));
+          continue;
+        }
+        listeners.push((KcpListener::from_socket(config, udp).await?, None));
+      }
+      SwiftKcpListener::new(listeners, config, options)
+    })
+  };
+
+  let listener = join_handle.await?.inspect_err(metrics::record_error)?;
+
+  let id = LISTENER_MANAGER.lock().await.insert_stream(listener);
+  tracing::debug!(id, "listener bound");
+
+  Ok(id)
+}
+
+// Listens at `bind_addr_str` on the in-process memory network, e.g.
+// "10.0.0.1:4000". Only streams of `new_memory_stream` reach it, datagrams
+// never touch the OS network. Meant for tests.
+#[uniffi::export]
+pub async fn new_memory_listener(
+  bind_addr_str: String,
+  params: KcpConfigParams,
+) -> Result<StreamId> {
+  params.validate()?;
+  memory::check_params(&params)?;
   let addr = SocketAddr::from_str(&bind_addr_str)?;
+  let options = StreamOptions::from_params(&params);
+  let config: KcpConfig = params.into();
 
   let join_handle = {
     let rt = RUNTIME.read().await;
@@ -255,35 +882,73 @@ async fn new_listener(bind_addr_str: String, params: KcpConfigParams) -> Result<
       return Err(SwiftKcpError::RuntimeNotInited);
     }
     let rt = rt.as_ref().unwrap();
-    rt.spawn(async move { KcpListener::bind(config, addr).await })
+    rt.spawn(async move {
+      let socket = Arc::new(MEMORY_NETWORK.bind(addr)?);
+      let listener = KcpListener::from_transport(config, socket).await?;
+      SwiftKcpListener::new(vec![(listener, None)], config, options)
+    })
   };
 
-  let listener = join_handle.await??;
+  let listener = join_handle.await?.inspect_err(metrics::record_error)?;
 
   let id = LISTENER_MANAGER.lock().await.insert_stream(listener);
+  tracing::debug!(id, %addr, "memory listener bound");
+
+  Ok(id)
+}
+
+// Connects to a listener of `new_memory_listener` at `addr_str`.
+#[uniffi::export]
+pub async fn new_memory_stream(addr_str: String, params: KcpConfigParams) -> Result<StreamId> {
+  params.validate()?;
+  memory::check_params(&params)?;
+  let addr = SocketAddr::from_str(&addr_str)?;
+  let options = StreamOptions::from_params(&params);
+  let config: KcpConfig = params.into();
+
+  let join_handle = {
+    let rt = RUNTIME.read().await;
+    if rt.is_none() {
+      return Err(SwiftKcpError::RuntimeNotInited);
+    }
+    let rt = rt.as_ref().unwrap();
+    rt.spawn(async move {
+      let socket = Arc::new(MEMORY_NETWORK.bind(memory::client_addr(&addr))?);
+      Ok::<_, SwiftKcpError>(KcpStream::connect_with_transport(&config, socket, addr).await?)
+    })
+  };
+
+  let stream = join_handle.await?.inspect_err(metrics::record_error)?;
+  let stream = SwiftKcpStream::new(stream, &config, options).with_remote_addr(addr);
+
+  let id = STREAM_MANAGER.lock().await.insert_stream(stream);
+  tracing::debug!(id, %addr, "memory stream connected");
+  metrics::record_connected();
 
   Ok(id)
 }
 
 #[uniffi::export]
-async fn remove_listener(id: StreamId) -> Result<()> {
+pub async fn remove_listener(id: StreamId) -> Result<()> {
   let listener = LISTENER_MANAGER.lock().await.remove_stream(id);
 
   if listener.is_none() {
     return Err(SwiftKcpError::NoListenerForId { id });
   }
+  tracing::debug!(id, "listener removed");
 
   Ok(())
 }
 
 #[derive(uniffi::Record)]
-struct IDAddrPair {
-  id: StreamId,
-  addr: String,
+pub struct IDAddrPair {
+  pub id: StreamId,
+  pub addr: String,
+  pub family: AddrFamily,
 }
 
 #[uniffi::export]
-async fn accepet(id: StreamId) -> Result<IDAddrPair> {
+pub async fn accepet(id: StreamId) -> Result<IDAddrPair> {
   let rt = RUNTIME.read().await;
   if rt.is_none() {
     return Err(SwiftKcpError::RuntimeNotInited);
@@ -294,36 +959,511 @@ async fn accepet(id: StreamId) -> Result<IDAddrPair> {
     .spawn(async move {
       let listener = {
         let manager = LISTENER_MANAGER.lock().await;
-        manager.get_mut_stream(id)
+        manager.get_stream(id)
       };
       if listener.is_none() {
         return Err(SwiftKcpError::NoListenerForId { id });
       }
       let listener = listener.unwrap();
 
-      let ret = listener.lock().await.accept().await?;
-
-      Ok(ret)
+      listener.accept().await
     })
-    .await??;
+    .await?
+    .inspect_err(metrics::record_error)?;
 
+  let listener_id = id;
   let id = STREAM_MANAGER.lock().await.insert_stream(stream);
+  tracing::debug!(id, listener_id, %addr, "stream accepted");
+  metrics::record_accepted();
 
   Ok(IDAddrPair {
     id,
     addr: addr.to_string(),
+    family: listener::addr_family(&addr),
   })
 }
 
 #[uniffi::export]
-async fn local_addr(id: StreamId) -> Result<String> {
-  let listener = LISTENER_MANAGER.lock().await.get_mut_stream(id);
+pub async fn local_addr(id: StreamId) -> Result<String> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
 
   if listener.is_none() {
     return Err(SwiftKcpError::NoListenerForId { id });
   }
 
-  let addr = listener.unwrap().lock().await.local_addr()?;
+  let addr = listener.unwrap().local_addr();
 
   Ok(addr.to_string())
 }
+
+// All bound addresses of a listener created with `new_listener_multi`.
+#[uniffi::export]
+pub async fn local_addrs(id: StreamId) -> Result<Vec<String>> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+
+  if listener.is_none() {
+    return Err(SwiftKcpError::NoListenerForId { id });
+  }
+
+  let addrs = listener
+    .unwrap()
+    .local_addrs()
+    .iter()
+    .map(|addr| addr.to_string())
+    .collect();
+
+  Ok(addrs)
+}
+
+// Replaces the admission rules of a listener. Sessions accepted before are
+// counted against the limits but not re-checked.
+#[uniffi::export]
+pub async fn set_listener_admission(id: StreamId, params: AdmissionParams) -> Result<()> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+
+  if listener.is_none() {
+    return Err(SwiftKcpError::NoListenerForId { id });
+  }
+
+  listener.unwrap().admission().set_params(params)
+}
+
+#[uniffi::export]
+pub async fn set_listener_accept_filter(
+  id: StreamId,
+  filter: Box<dyn KcpAcceptFilter>,
+) -> Result<()> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+
+  if listener.is_none() {
+    return Err(SwiftKcpError::NoListenerForId { id });
+  }
+
+  listener
+    .unwrap()
+    .admission()
+    .set_filter(Some(Arc::from(filter)));
+
+  Ok(())
+}
+
+#[uniffi::export]
+pub async fn clear_listener_accept_filter(id: StreamId) -> Result<()> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+
+  if listener.is_none() {
+    return Err(SwiftKcpError::NoListenerForId { id });
+  }
+
+  listener.unwrap().admission().set_filter(None);
+
+  Ok(())
+}
+
+// Sessions rejected by the admission rules or the accept filter.
+#[uniffi::export]
+pub async fn get_listener_rejected_count(id: StreamId) -> Result<u64> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+
+  if listener.is_none() {
+    return Err(SwiftKcpError::NoListenerForId { id });
+  }
+
+  Ok(listener.unwrap().admission().rejected_count())
+}
+
+// Waits for the next session event of a listener.
+#[uniffi::export]
+pub async fn next_listener_event(id: StreamId) -> Result<ListenerEvent> {
+  let rt = RUNTIME.read().await;
+  if rt.is_none() {
+    return Err(SwiftKcpError::RuntimeNotInited);
+  }
+  let rt = rt.as_ref().unwrap();
+
+  let event = rt
+    .spawn(async move {
+      let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+      if listener.is_none() {
+        return Err(SwiftKcpError::NoListenerForId { id });
+      }
+      Ok(listener.unwrap().events().next().await)
+    })
+    .await?
+    .inspect_err(metrics::record_error)?;
+
+  Ok(event)
+}
+
+#[uniffi::export]
+pub async fn get_stream_compression_stats(id: StreamId) -> Result<CompressionStats> {
+  let stream = STREAM_MANAGER.lock().await.get_stream(id);
+
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+
+  Ok(stream.unwrap().compression_stats())
+}
+
+// Changes the config of an open stream, kcp parameters apply to the running
+// session. `stream`, `compression` and `session_expire_milisec` must be unset
+// or equal to the values in effect.
+#[uniffi::export]
+pub async fn update_stream_config(id: StreamId, params: KcpConfigParams) -> Result<()> {
+  params.validate()?;
+  let stream = STREAM_MANAGER.lock().await.get_stream(id);
+
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+
+  stream.unwrap().update_config(&params)
+}
+
+// Limits the upload of a stream with a token bucket. A `burst` of 0 allows one
+// second worth of bytes.
+#[uniffi::export]
+pub async fn set_stream_rate_limit(id: StreamId, bytes_per_sec: u64, burst: u64) -> Result<()> {
+  let stream = STREAM_MANAGER.lock().await.get_stream(id);
+
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+
+  stream.unwrap().rate_limiter().set(bytes_per_sec, burst)
+}
+
+#[uniffi::export]
+pub async fn clear_stream_rate_limit(id: StreamId) -> Result<()> {
+  let stream = STREAM_MANAGER.lock().await.get_stream(id);
+
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+
+  stream.unwrap().rate_limiter().clear();
+
+  Ok(())
+}
+
+#[uniffi::export]
+pub async fn get_stream_rate_limit_stats(id: StreamId) -> Result<RateLimitStats> {
+  let stream = STREAM_MANAGER.lock().await.get_stream(id);
+
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+
+  Ok(stream.unwrap().rate_limit_stats())
+}
+
+// Decisions of adaptive pacing, see `KcpConfigParams.adaptive_pacing`.
+#[uniffi::export]
+pub async fn get_stream_adaptive_stats(id: StreamId) -> Result<AdaptiveStats> {
+  let stream = STREAM_MANAGER.lock().await.get_stream(id);
+
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+
+  Ok(stream.unwrap().adaptive_stats())
+}
+
+// The mtu a stream uses, and the one found when it was created with
+// `KcpConfigParams.path_mtu_discovery`.
+#[uniffi::export]
+pub async fn get_stream_path_mtu(id: StreamId) -> Result<PathMtu> {
+  let stream = STREAM_MANAGER.lock().await.get_stream(id);
+
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+
+  Ok(stream.unwrap().path_mtu())
+}
+
+// Records the datagrams of a stream to the pcap file `path` and their decoded
+// kcp segments to `path` + ".txt", until `stop_capture`. Works on streams of
+// endpoints, and on streams created or accepted with
+// `KcpConfigParams.capturable`, tokio_kcp owns the sockets of the others. A
+// running capture is replaced.
+#[uniffi::export]
+pub async fn start_capture(id: StreamId, path: String) -> Result<()> {
+  let stream = STREAM_MANAGER.lock().await.get_stream(id);
+
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+  let stream = stream.unwrap();
+  if !stream.capturable() {
+    return Err(SwiftKcpError::CaptureUnavailable {
+      id,
+      reason: "the stream isn't connected through a relay, see `KcpConfigParams.capturable`"
+        .to_string(),
+    });
+  }
+
+  let capture = Capture::create(&path)?;
+  stream.set_capture(Some(Arc::new(capture)));
+  tracing::debug!(id, path, "capture started");
+
+  Ok(())
+}
+
+// Stops the capture of a stream and flushes its files. Does nothing if the
+// stream isn't captured.
+#[uniffi::export]
+pub async fn stop_capture(id: StreamId) -> Result<()> {
+  let stream = STREAM_MANAGER.lock().await.get_stream(id);
+
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+  let stream = stream.unwrap();
+
+  if let Some(capture) = stream.set_capture(None) {
+    capture.flush()?;
+    tracing::debug!(id, "capture stopped");
+  }
+
+  Ok(())
+}
+
+// Records every datagram of a listener, of all its sessions, like
+// `start_capture`. Works on listeners created with
+// `KcpConfigParams.capturable`. A running capture is replaced.
+#[uniffi::export]
+pub async fn start_listener_capture(id: StreamId, path: String) -> Result<()> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+
+  if listener.is_none() {
+    return Err(SwiftKcpError::NoListenerForId { id });
+  }
+  let listener = listener.unwrap();
+  if !listener.capturable() {
+    return Err(SwiftKcpError::CaptureUnavailable {
+      id,
+      reason: "the listener isn't relayed, see `KcpConfigParams.capturable`".to_string(),
+    });
+  }
+
+  let capture = Capture::create(&path)?;
+  listener.set_capture(Some(Arc::new(capture)));
+  tracing::debug!(id, path, "listener capture started");
+
+  Ok(())
+}
+
+// Stops the capture of a listener and flushes its files. Does nothing if the
+// listener isn't captured.
+#[uniffi::export]
+pub async fn stop_listener_capture(id: StreamId) -> Result<()> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+
+  if listener.is_none() {
+    return Err(SwiftKcpError::NoListenerForId { id });
+  }
+
+  if let Some(capture) = listener.unwrap().set_capture(None) {
+    capture.flush()?;
+    tracing::debug!(id, "listener capture stopped");
+  }
+
+  Ok(())
+}
+
+// Limits the total upload of all streams accepted by a listener, including
+// the ones accepted before.
+#[uniffi::export]
+pub async fn set_listener_rate_limit(id: StreamId, bytes_per_sec: u64, burst: u64) -> Result<()> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+
+  if listener.is_none() {
+    return Err(SwiftKcpError::NoListenerForId { id });
+  }
+
+  listener.unwrap().rate_limiter().set(bytes_per_sec, burst)
+}
+
+#[uniffi::export]
+pub async fn clear_listener_rate_limit(id: StreamId) -> Result<()> {
+  let listener = LISTENER_MANAGER.lock().await.get_stream(id);
+
+  if listener.is_none() {
+    return Err(SwiftKcpError::NoListenerForId { id });
+  }
+
+  listener.unwrap().rate_limiter().clear();
+
+  Ok(())
+}
+
+#[uniffi::export]
+pub fn default_mux_config_params() -> MuxConfigParams {
+  MuxConfigParams::default()
+}
+
+// Moves the stream into a new mux session, the stream id is no longer valid
+// for `read_stream`/`write_stream` afterwards. Both sides of the kcp stream
+// should create a mux session, one of them with `client` set to true.
+#[uniffi::export]
+pub async fn new_mux_session(
+  id: StreamId,
+  client: bool,
+  params: MuxConfigParams,
+) -> Result<StreamId> {
+  let rt = RUNTIME.read().await;
+  if rt.is_none() {
+    return Err(SwiftKcpError::RuntimeNotInited);
+  }
+  let rt = rt.as_ref().unwrap();
+
+  let stream = STREAM_MANAGER.lock().await.remove_stream(id);
+  if stream.is_none() {
+    return Err(SwiftKcpError::NoStreamForId { id });
+  }
+
+  let session = MuxSession::new(rt, stream.unwrap(), client, params);
+  let id = MUX_MANAGER.lock().await.insert_stream(session);
+
+  Ok(id)
+}
+
+#[uniffi::export]
+pub async fn remove_mux_session(id: StreamId) -> Result<()> {
+  let session = MUX_MANAGER.lock().await.remove_stream(id);
+
+  if session.is_none() {
+    return Err(SwiftKcpError::NoMuxForId { id });
+  }
+
+  Ok(())
+}
+
+#[uniffi::export]
+pub async fn mux_open(id: StreamId) -> Result<SubStreamId> {
+  let rt = RUNTIME.read().await;
+  if rt.is_none() {
+    return Err(SwiftKcpError::RuntimeNotInited);
+  }
+  let rt = rt.as_ref().unwrap();
+
+  let sid = rt
+    .spawn(async move {
+      let session = MUX_MANAGER.lock().await.get_stream(id);
+      if session.is_none() {
+        return Err(SwiftKcpError::NoMuxForId { id });
+      }
+      session.unwrap().open().await
+    })
+    .await?
+    .inspect_err(metrics::record_error)?;
+
+  Ok(sid)
+}
+
+#[uniffi::export]
+pub async fn mux_accept(id: StreamId) -> Result<SubStreamId> {
+  let rt = RUNTIME.read().await;
+  if rt.is_none() {
+    return Err(SwiftKcpError::RuntimeNotInited);
+  }
+  let rt = rt.as_ref().unwrap();
+
+  let sid = rt
+    .spawn(async move {
+      let session = MUX_MANAGER.lock().await.get_stream(id);
+      if session.is_none() {
+        return Err(SwiftKcpError::NoMuxForId { id });
+      }
+      session.unwrap().accept().await
+    })
+    .await?
+    .inspect_err(metrics::record_error)?;
+
+  Ok(sid)
+}
+
+#[uniffi::export]
+pub async fn mux_write(id: StreamId, sid: SubStreamId, data: Vec<u8>) -> Result<()> {
+  let rt = RUNTIME.read().await;
+  if rt.is_none() {
+    return Err(SwiftKcpError::RuntimeNotInited);
+  }
+  let rt = rt.as_ref().unwrap();
+
+  rt.spawn(async move {
+    let session = MUX_MANAGER.lock().await.get_stream(id);
+    if session.is_none() {
+      return Err(SwiftKcpError::NoMuxForId { id });
+    }
+    session.unwrap().write(sid, &data).await?;
+    metrics::record_bytes_out(data.len());
+    Ok(())
+  })
+  .await?
+  .inspect_err(metrics::record_error)?;
+
+  Ok(())
+}
+
+// Returns empty data when the remote side has closed the sub-stream. Fails
+// with `SubStreamClosed` once both sides closed it.
+#[uniffi::export]
+pub async fn mux_read(id: StreamId, sid: SubStreamId) -> Result<Vec<u8>> {
+  let rt = RUNTIME.read().await;
+  if rt.is_none() {
+    return Err(SwiftKcpError::RuntimeNotInited);
+  }
+  let rt = rt.as_ref().unwrap();
+
+  let data = rt
+    .spawn(async move {
+      let session = MUX_MANAGER.lock().await.get_stream(id);
+      if session.is_none() {
+        return Err(SwiftKcpError::NoMuxForId { id });
+      }
+      let data = session.unwrap().read(sid).await?;
+      metrics::record_bytes_in(data.len());
+      Ok(data)
+    })
+    .await?
+    .inspect_err(metrics::record_error)?;
+
+  Ok(data)
+}
+
+// Sends FIN to the remote side. The sub-stream can still be read until the
+// remote side closes it too.
+#[uniffi::export]
+pub async fn mux_close_stream(id: StreamId, sid: SubStreamId) -> Result<()> {
+  let rt = RUNTIME.read().await;
+  if rt.is_none() {
+    return Err(SwiftKcpError::RuntimeNotInited);
+  }
+  let rt = rt.as_ref().unwrap();
+
+  rt.spawn(async move {
+    let session = MUX_MANAGER.lock().await.get_stream(id);
+    if session.is_none() {
+      return Err(SwiftKcpError::NoMuxForId { id });
+    }
+    session.unwrap().close_stream(sid).await
+  })
+  .await?
+  .inspect_err(metrics::record_error)?;
+
+  Ok(())
+}
+
+#[uniffi::export]
+pub async fn get_mux_stream_count(id: StreamId) -> Result<u32> {
+  let session = MUX_MANAGER.lock().await.get_stream(id);
+
+  if session.is_none() {
+    return Err(SwiftKcpError::NoMuxForId { id });
+  }
+
+  Ok(session.unwrap().stream_count() as u32)
+}
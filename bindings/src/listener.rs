@@ -0,0 +1,224 @@
+use crate::admission::{Admission, AdmissionTicket};
+use crate::capture::Capture;
+use crate::events::{EventQueue, ListenerEvent};
+use crate::rate_limit::RateLimiter;
+use crate::relay::{ListenerRelay, RelayRoutes, SessionRoute};
+use crate::stream::{StreamOptions, SwiftKcpStream};
+use crate::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::task::JoinHandle;
+use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+// Admitted sessions waiting for `accept`. Each tokio_kcp listener queues as
+// many new sessions in front of admission control, sessions arriving while
+// both queues are full are closed and their peers have to connect again.
+const ACCEPT_BACKLOG: usize = 1024;
+
+#[derive(uniffi::Enum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrFamily {
+  Ipv4,
+  Ipv6,
+}
+
+/// Maps IPv4-mapped IPv6 addresses, which a dual-stack socket reports for IPv4
+/// peers, back to plain IPv4 addresses.
+pub fn normalize_addr(addr: SocketAddr) -> SocketAddr {
+  match addr {
+    SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+      Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+      None => addr,
+    },
+    SocketAddr::V4(..) => addr,
+  }
+}
+
+pub fn addr_family(addr: &SocketAddr) -> AddrFamily {
+  match addr {
+    SocketAddr::V4(..) => AddrFamily::Ipv4,
+    SocketAddr::V6(..) => AddrFamily::Ipv6,
+  }
+}
+
+// An admitted session, its peer address, conv and capture route if the
+// listener is relayed.
+type Accepted = (
+  KcpStream,
+  SocketAddr,
+  u32,
+  AdmissionTicket,
+  Option<SessionRoute>,
+);
+
+/// One or more `KcpListener`s accepting into a single queue.
+pub struct SwiftKcpListener {
+  accept_rx: Mutex<mpsc::Receiver<Accepted>>,
+  local_addrs: Vec<SocketAddr>,
+  // Set for the sockets that are relayed, see `relay::relay_listener`.
+  relay_routes: Vec<RelayRoutes>,
+  admission: Arc<Admission>,
+  rate_limiter: Arc<RateLimiter>,
+  events: Arc<EventQueue<ListenerEvent>>,
+  config: KcpConfig,
+  options: StreamOptions,
+  tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for SwiftKcpListener {
+  fn drop(&mut self) {
+    for task in &self.tasks {
+      task.abort();
+    }
+  }
+}
+
+impl SwiftKcpListener {
+  // Should be called within the tokio runtime.
+  pub fn new(
+    listeners: Vec<(KcpListener, Option<ListenerRelay>)>,
+    config: KcpConfig,
+    options: StreamOptions,
+  ) -> Result<Self> {
+    let local_addrs = listeners
+      .iter()
+      .map(|(listener, relay)| match relay {
+        Some(relay) => Ok(relay.local_addr),
+        None => listener.local_addr(),
+      })
+      .collect::<std::io::Result<Vec<_>>>()?;
+
+    let admission: Arc<Admission> = Default::default();
+    let events: Arc<EventQueue<ListenerEvent>> = Default::default();
+    let (accept_tx, accept_rx) = mpsc::channel(ACCEPT_BACKLOG);
+    let mut tasks = Vec::new();
+    let mut relay_routes = Vec::new();
+
+    for (mut listener, relay) in listeners {
+      let (accept_tx, admission, events) = (accept_tx.clone(), admission.clone(), events.clone());
+      // Packets of rejected sessions are dropped for as long as a session
+      // would take to expire, the peer's retransmissions would create the
+      // session again and again otherwise.
+      let reject_ttl = config.session_expire;
+      let relay = relay.map(|relay| {
+        tasks.push(relay.task);
+        relay_routes.push(relay.routes.clone());
+        (relay.peer_addrs, relay.routes)
+      });
+      tasks.push(tokio::spawn(async move {
+        while let Ok((stream, listener_addr)) = listener.accept().await {
+          let peer = match &relay {
+            Some((peer_addrs, _)) => peer_addrs.lock().unwrap().get(&listener_addr).copied(),
+            None => Some(listener_addr),
+          };
+          // The relay already forgot the peer.
+          let Some(peer) = peer else {
+            continue;
+          };
+          let addr = normalize_addr(peer);
+          let conv = stream.session().conv().await;
+          // Rejected sessions are closed by dropping the stream.
+          let ticket = match admission.admit(addr, conv).await {
+            Some(ticket) => ticket,
+            None => {
+              listener.reject(listener_addr, conv, reject_ttl);
+              events.push(ListenerEvent::SessionRejected {
+                addr: addr.to_string(),
+                conv,
+              });
+              continue;
+            }
+          };
+          events.push(ListenerEvent::SessionAccepted {
+            addr: addr.to_string(),
+            conv,
+          });
+
+          let route = relay.as_ref().map(|(_, routes)| routes.session(peer, conv));
+          if accept_tx
+            .send((stream, addr, conv, ticket, route))
+            .await
+            .is_err()
+          {
+            break;
+          }
+        }
+      }));
+    }
+
+    Ok(Self {
+      accept_rx: Mutex::new(accept_rx),
+      local_addrs,
+      relay_routes,
+      admission,
+      rate_limiter: Default::default(),
+      events,
+      config,
+      options,
+      tasks,
+    })
+  }
+
+  pub async fn accept(&self) -> Result<(SwiftKcpStream, SocketAddr)> {
+    let accepted = self.accept_rx.lock().await.recv().await;
+    let (stream, addr, conv, ticket, route) =
+      accepted.ok_or_else(|| std::io::Error::other("accept channel closed unexpectly"))?;
+
+    let mut stream = SwiftKcpStream::new(stream, &self.config, self.options)
+      .with_admission_ticket(ticket)
+      .with_shared_rate_limiter(self.rate_limiter.clone())
+      .with_listener_events(self.events.clone(), conv)
+      .with_remote_addr(addr);
+    if let Some(route) = route {
+      stream = stream.with_session_route(route);
+    }
+    Ok((stream, addr))
+  }
+
+  /// Whether the datagrams of the listener go through a relay that can
+  /// capture them.
+  pub fn capturable(&self) -> bool {
+    !self.relay_routes.is_empty()
+  }
+
+  /// Starts recording every datagram of the listener to `capture`, or stops
+  /// if it's `None`. Returns the capture it replaces.
+  pub fn set_capture(&self, capture: Option<Arc<Capture>>) -> Option<Arc<Capture>> {
+    let mut replaced = None;
+    for routes in &self.relay_routes {
+      replaced = routes.set_capture(capture.clone()).or(replaced);
+    }
+    replaced
+  }
+
+  pub fn local_addr(&self) -> SocketAddr {
+    self.local_addrs[0]
+  }
+
+  pub fn local_addrs(&self) -> &[SocketAddr] {
+    &self.local_addrs
+  }
+
+  pub fn admission(&self) -> &Admission {
+    &self.admission
+  }
+
+  pub fn events(&self) -> &EventQueue<ListenerEvent> {
+    &self.events
+  }
+
+  /// Limits the total upload of the streams accepted by this listener.
+  pub fn rate_limiter(&self) -> &RateLimiter {
+    &self.rate_limiter
+  }
+}
+
+#[test]
+fn test_normalize_addr() {
+  let mapped: SocketAddr = "[::ffff:192.168.1.2]:3100".parse().unwrap();
+  let v6: SocketAddr = "[2001:db8::1]:3100".parse().unwrap();
+
+  assert_eq!(normalize_addr(mapped), "192.168.1.2:3100".parse().unwrap());
+  assert_eq!(addr_family(&normalize_addr(mapped)), AddrFamily::Ipv4);
+  assert_eq!(normalize_addr(v6), v6);
+}
@@ -0,0 +1,233 @@
+use crate::{Result, SwiftKcpError};
+use lazy_static::lazy_static;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, RwLock as StdRwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::subscriber::Interest;
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_log::NormalizeEvent;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+/// Receives the logs of the bindings and of tokio_kcp.
+#[uniffi::export(callback_interface)]
+pub trait LogSink: Send + Sync {
+  /// Called on the thread that logs, it shouldn't block.
+  fn log(&self, record: LogRecord);
+}
+
+#[derive(uniffi::Enum, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum LogLevel {
+  Off,
+  Error,
+  Warn,
+  #[default]
+  Info,
+  Debug,
+  Trace,
+}
+
+#[derive(uniffi::Record, Debug, Clone)]
+pub struct LogRecord {
+  pub level: LogLevel,
+  /// Module that logged, e.g. "bindings" or "tokio_kcp::session"
+  pub target: String,
+  /// The message followed by its fields, e.g. "stream connected id=1"
+  pub message: String,
+  pub timestamp_millisec: u64,
+}
+
+lazy_static! {
+  static ref SINK: StdRwLock<Option<Arc<dyn LogSink>>> = StdRwLock::new(None);
+}
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+// Whether records of the log crate go through `install`'s bridge, and so
+// follow `set_level`.
+static LOG_BRIDGE: AtomicBool = AtomicBool::new(false);
+
+impl From<&Level> for LogLevel {
+  fn from(level: &Level) -> Self {
+    match *level {
+      Level::ERROR => LogLevel::Error,
+      Level::WARN => LogLevel::Warn,
+      Level::INFO => LogLevel::Info,
+      Level::DEBUG => LogLevel::Debug,
+      Level::TRACE => LogLevel::Trace,
+    }
+  }
+}
+
+fn log_level_filter(level: LogLevel) -> log::LevelFilter {
+  match level {
+    LogLevel::Off => log::LevelFilter::Off,
+    LogLevel::Error => log::LevelFilter::Error,
+    LogLevel::Warn => log::LevelFilter::Warn,
+    LogLevel::Info => log::LevelFilter::Info,
+    LogLevel::Debug => log::LevelFilter::Debug,
+    LogLevel::Trace => log::LevelFilter::Trace,
+  }
+}
+
+fn enabled(level: LogLevel) -> bool {
+  level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+#[derive(Default)]
+struct MessageVisitor {
+  message: String,
+  fields: String,
+}
+
+impl Visit for MessageVisitor {
+  fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+    match field.name() {
+      "message" => {
+        let _ = write!(self.message, "{:?}", value);
+      }
+      // Fields of records bridged from the log crate.
+      name if name.starts_with("log.") => {}
+      name => {
+        let _ = write!(self.fields, " {}={:?}", name, value);
+      }
+    }
+  }
+
+  fn record_str(&mut self, field: &Field, value: &str) {
+    match field.name() {
+      "message" => self.message.push_str(value),
+      name if name.starts_with("log.") => {}
+      name => {
+        let _ = write!(self.fields, " {}={}", name, value);
+      }
+    }
+  }
+}
+
+/// Forwards events to the sink set with `set_log_sink`, for hosts that add it
+/// to a subscriber of their own instead of calling `install_log_subscriber`.
+/// The level is checked on every event, so `set_log_level` applies right away.
+pub struct SinkLayer;
+
+impl<S: Subscriber> Layer<S> for SinkLayer {
+  fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+    Interest::sometimes()
+  }
+
+  fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+    enabled(metadata.level().into())
+  }
+
+  fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+    let sink = SINK.read().unwrap().clone();
+    let sink = match sink {
+      Some(sink) => sink,
+      None => return,
+    };
+
+    let metadata = event.normalized_metadata();
+    let metadata = metadata.as_ref().unwrap_or_else(|| event.metadata());
+    let mut visitor = MessageVisitor::default();
+    event.record(&mut visitor);
+
+    let timestamp_millisec = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|d| d.as_millis() as u64)
+      .unwrap_or(0);
+
+    sink.log(LogRecord {
+      level: metadata.level().into(),
+      target: metadata.target().to_string(),
+      message: visitor.message + &visitor.fields,
+      timestamp_millisec,
+    });
+  }
+}
+
+/// Installs a global subscriber forwarding to the sink, and a bridge from the
+/// log crate tokio_kcp logs with. Fails like `try_init` if the process
+/// already has a global subscriber or logger, keeping theirs.
+pub fn install() -> Result<()> {
+  let installed = |e: &dyn std::fmt::Display| SwiftKcpError::LoggerInstalled { msg: e.to_string() };
+
+  tracing_log::LogTracer::init().map_err(|e| installed(&e))?;
+  LOG_BRIDGE.store(true, Ordering::Relaxed);
+  set_level(current_level());
+  let subscriber = tracing_subscriber::registry().with(SinkLayer);
+  tracing::subscriber::set_global_default(subscriber).map_err(|e| installed(&e))
+}
+
+pub fn set_level(level: LogLevel) {
+  LEVEL.store(level as u8, Ordering::Relaxed);
+  // Records of the log crate are dropped before they're formatted. The max
+  // level of a logger installed by the host is left alone.
+  if LOG_BRIDGE.load(Ordering::Relaxed) {
+    log::set_max_level(log_level_filter(level));
+  }
+}
+
+pub fn set_sink(sink: Option<Arc<dyn LogSink>>) {
+  *SINK.write().unwrap() = sink;
+}
+
+fn current_level() -> LogLevel {
+  match LEVEL.load(Ordering::Relaxed) {
+    0 => LogLevel::Off,
+    1 => LogLevel::Error,
+    2 => LogLevel::Warn,
+    3 => LogLevel::Info,
+    4 => LogLevel::Debug,
+    _ => LogLevel::Trace,
+  }
+}
+
+#[test]
+fn test_log_sink() {
+  use std::sync::Mutex as StdMutex;
+
+  #[derive(Default)]
+  struct Records(StdMutex<Vec<LogRecord>>);
+
+  impl LogSink for Arc<Records> {
+    fn log(&self, record: LogRecord) {
+      self.0.lock().unwrap().push(record);
+    }
+  }
+
+  let records = Arc::new(Records::default());
+  set_sink(Some(Arc::new(records.clone())));
+  set_level(LogLevel::Info);
+  let only_here = |records: &Arc<Records>| {
+    // Other tests may log while this one runs.
+    let records = records.0.lock().unwrap();
+    records
+      .iter()
+      .filter(|record| record.target == module_path!())
+      .cloned()
+      .collect::<Vec<_>>()
+  };
+
+  // Scoped to a subscriber of the host, nothing global is installed.
+  let subscriber = tracing_subscriber::registry().with(SinkLayer);
+  tracing::subscriber::with_default(subscriber, || {
+    tracing::info!(id = 1, "stream connected");
+    tracing::debug!("filtered out");
+  });
+  tracing::info!("no subscriber");
+  let scoped = only_here(&records);
+  assert_eq!(scoped.len(), 1);
+  assert_eq!(scoped[0].message, "stream connected id=1");
+
+  // Installed once, a second install fails instead of replacing it.
+  install().unwrap();
+  assert!(matches!(
+    install(),
+    Err(SwiftKcpError::LoggerInstalled { .. })
+  ));
+  log::warn!("from the log crate");
+  let records = only_here(&records);
+  assert_eq!(records.len(), 2);
+  assert_eq!(records[1].level, LogLevel::Warn);
+  assert_eq!(records[1].message, "from the log crate");
+}
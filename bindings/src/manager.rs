@@ -1,12 +1,11 @@
 use std::sync::Arc;
 use std::{collections::HashMap, sync::atomic::AtomicU64};
-use tokio::sync::Mutex;
 
 pub type StreamId = u64;
 
 pub struct Manager<T> {
   id: AtomicU64,
-  item_by_id: HashMap<StreamId, Arc<Mutex<T>>>,
+  item_by_id: HashMap<StreamId, Arc<T>>,
 }
 
 impl<T> Manager<T> {
@@ -22,34 +21,23 @@ impl<T> Manager<T> {
   }
 
   fn next_id(&self) -> StreamId {
-    let id = self.id.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-    id
+    self.id.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
   }
 
   pub fn insert_stream(&mut self, stream: T) -> StreamId {
     let id = self.next_id();
 
-    self.item_by_id.insert(id, Arc::new(Mutex::new(stream)));
+    self.item_by_id.insert(id, Arc::new(stream));
 
     id
   }
 
-  pub fn get_mut_stream(&self, id: StreamId) -> Option<Arc<Mutex<T>>> {
-    let d = self.item_by_id.get(&id);
-
-    if d.is_none() {
-      return None;
-    }
-
-    let stream = d.unwrap();
-
-    let e = stream.clone();
-
-    Some(e)
+  pub fn get_stream(&self, id: StreamId) -> Option<Arc<T>> {
+    self.item_by_id.get(&id).cloned()
   }
 
-  pub fn remove_stream(&mut self, id: StreamId) -> Option<Arc<Mutex<T>>> {
-    self.item_by_id.remove(&id).map(|stream| stream)
+  pub fn remove_stream(&mut self, id: StreamId) -> Option<Arc<T>> {
+    self.item_by_id.remove(&id)
   }
 }
 
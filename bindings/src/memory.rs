@@ -0,0 +1,288 @@
+use crate::error::SwiftKcpError;
+use crate::kcp_util::KcpConfigParams;
+use crate::Result;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::task::{Context, Poll};
+use tokio::io::ReadBuf;
+use tokio::sync::mpsc;
+use tokio_kcp::KcpTransport;
+
+// Datagrams queued for a socket that isn't reading, more are dropped like a
+// full socket buffer drops them.
+const QUEUE_LEN: usize = 1024;
+// Where ports of sockets bound to port 0 are taken from.
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+#[derive(Default)]
+struct Sockets {
+  by_addr: HashMap<SocketAddr, mpsc::Sender<Datagram>>,
+  next_port: u16,
+}
+
+/// Delivers datagrams between `MemorySocket`s by address, in the process and
+/// without touching the OS network. Datagrams to an address nobody is bound
+/// to are dropped, like UDP does.
+#[derive(Default)]
+pub struct MemoryNetwork {
+  sockets: StdMutex<Sockets>,
+}
+
+impl MemoryNetwork {
+  /// Binds a socket at `addr`, or at a free port of its IP if the port is 0.
+  pub fn bind(self: &Arc<Self>, addr: SocketAddr) -> io::Result<MemorySocket> {
+    let mut sockets = self.sockets.lock().unwrap();
+    let addr = match addr.port() {
+      0 => {
+        let ports = EPHEMERAL_PORTS.len();
+        let free = (0..ports)
+          .map(|i| *EPHEMERAL_PORTS.start() + ((sockets.next_port as usize + i) % ports) as u16)
+          .find(|port| {
+            !sockets
+              .by_addr
+              .contains_key(&SocketAddr::new(addr.ip(), *port))
+          })
+          .ok_or_else(|| io::Error::from(io::ErrorKind::AddrInUse))?;
+        sockets.next_port = free - *EPHEMERAL_PORTS.start() + 1;
+        SocketAddr::new(addr.ip(), free)
+      }
+      _ if sockets.by_addr.contains_key(&addr) => {
+        return Err(io::Error::from(io::ErrorKind::AddrInUse));
+      }
+      _ => addr,
+    };
+
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    sockets.by_addr.insert(addr, tx);
+    Ok(MemorySocket {
+      addr,
+      network: self.clone(),
+      rx: StdMutex::new(rx),
+    })
+  }
+
+  fn send(&self, buf: &[u8], from: SocketAddr, to: SocketAddr) {
+    let tx = self.sockets.lock().unwrap().by_addr.get(&to).cloned();
+    if let Some(tx) = tx {
+      let _ = tx.try_send((buf.to_vec(), from));
+    }
+  }
+}
+
+/// A UDP socket of a `MemoryNetwork`, see `KcpTransport`. Sends never block,
+/// datagrams the receiver has no room for are lost.
+pub struct MemorySocket {
+  addr: SocketAddr,
+  network: Arc<MemoryNetwork>,
+  rx: StdMutex<mpsc::Receiver<Datagram>>,
+}
+
+impl std::fmt::Debug for MemorySocket {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("MemorySocket")
+      .field("addr", &self.addr)
+      .finish()
+  }
+}
+
+impl Drop for MemorySocket {
+  fn drop(&mut self) {
+    let mut sockets = self.network.sockets.lock().unwrap();
+    sockets.by_addr.remove(&self.addr);
+  }
+}
+
+impl KcpTransport for MemorySocket {
+  fn try_send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+    self.network.send(buf, self.addr, target);
+    Ok(buf.len())
+  }
+
+  fn poll_send_to(
+    &self,
+    _cx: &mut Context<'_>,
+    buf: &[u8],
+    target: SocketAddr,
+  ) -> Poll<io::Result<usize>> {
+    Poll::Ready(self.try_send_to(buf, target))
+  }
+
+  fn poll_recv_from(
+    &self,
+    cx: &mut Context<'_>,
+    buf: &mut ReadBuf<'_>,
+  ) -> Poll<io::Result<SocketAddr>> {
+    let mut rx = self.rx.lock().unwrap();
+    match rx.poll_recv(cx) {
+      Poll::Ready(Some((datagram, from))) => {
+        // Truncated like a UDP datagram larger than the buffer.
+        let n = datagram.len().min(buf.remaining());
+        buf.put_slice(&datagram[..n]);
+        Poll::Ready(Ok(from))
+      }
+      // The network holds a sender for as long as the socket lives.
+      Poll::Ready(None) => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+      Poll::Pending => Poll::Pending,
+    }
+  }
+
+  fn local_addr(&self) -> io::Result<SocketAddr> {
+    Ok(self.addr)
+  }
+}
+
+/// Where a client of the memory network connecting to `remote` is bound.
+pub fn client_addr(remote: &SocketAddr) -> SocketAddr {
+  let ip: IpAddr = match remote {
+    SocketAddr::V4(..) => Ipv4Addr::LOCALHOST.into(),
+    SocketAddr::V6(..) => Ipv6Addr::LOCALHOST.into(),
+  };
+  SocketAddr::new(ip, 0)
+}
+
+/// Refuses the params that only make sense with a UDP socket.
+pub fn check_params(params: &KcpConfigParams) -> Result<()> {
+  let unsupported = |field: &str| SwiftKcpError::InvalidConfig {
+    field: field.to_string(),
+    reason: "not supported by the memory transport".to_string(),
+  };
+
+  if params.capturable == Some(true) {
+    return Err(unsupported("capturable"));
+  }
+  if params.path_mtu_discovery == Some(true) {
+    return Err(unsupported("path_mtu_discovery"));
+  }
+  if params.impairment.is_some() {
+    return Err(unsupported("impairment"));
+  }
+  let socket_options = [
+    ("reuse_address", params.reuse_address.is_some()),
+    ("reuse_port", params.reuse_port.is_some()),
+    ("send_buffer_size", params.send_buffer_size.is_some()),
+    ("recv_buffer_size", params.recv_buffer_size.is_some()),
+    ("ip_tos", params.ip_tos.is_some()),
+    ("ipv6_only", params.ipv6_only.is_some()),
+  ];
+  match socket_options.iter().find(|(_, set)| *set) {
+    Some((field, _)) => Err(unsupported(field)),
+    None => Ok(()),
+  }
+}
+
+#[test]
+fn test_memory_transport() {
+  use crate::events::StreamEvent;
+  use crate::listener::SwiftKcpListener;
+  use crate::stream::{StreamOptions, SwiftKcpStream};
+  use std::time::Duration;
+  use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+  // The paused clock jumps to the next timer whenever every task waits.
+  let rt = tokio::runtime::Builder::new_current_thread()
+    .enable_all()
+    .start_paused(true)
+    .build()
+    .unwrap();
+  let wall = std::time::Instant::now();
+
+  rt.block_on(async {
+    let network = Arc::new(MemoryNetwork::default());
+    let params = KcpConfigParams {
+      idle_timeout_milisec: Some(10_000),
+      ..Default::default()
+    };
+    let options = StreamOptions::from_params(&params);
+    let config: KcpConfig = params.into();
+
+    let server_addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+    let socket = Arc::new(network.bind(server_addr).unwrap());
+    assert!(network.bind(server_addr).is_err());
+    let listener = KcpListener::from_transport(config, socket).await.unwrap();
+    let listener = SwiftKcpListener::new(vec![(listener, None)], config, options).unwrap();
+
+    let socket = Arc::new(network.bind(client_addr(&server_addr)).unwrap());
+    let client_addr = socket.local_addr().unwrap();
+    assert_eq!(client_addr, "127.0.0.1:49152".parse().unwrap());
+    let stream = KcpStream::connect_with_transport(&config, socket, server_addr)
+      .await
+      .unwrap();
+    let client = SwiftKcpStream::new(stream, &config, options);
+
+    client.write(b"hello").await.unwrap();
+    let (server, addr) = listener.accept().await.unwrap();
+    assert_eq!(addr, client_addr);
+    assert_eq!(server.read().await.unwrap(), b"hello");
+    server.write(b"world").await.unwrap();
+    assert_eq!(client.read().await.unwrap(), b"world");
+
+    // Idle after 10s on the paused clock, in no time on the wall clock.
+    let start = tokio::time::Instant::now();
+    loop {
+      if let StreamEvent::Idle { idle_millisec } = client.next_event().await {
+        assert!(
+          (10_000..10_100).contains(&idle_millisec),
+          "{}",
+          idle_millisec
+        );
+        break;
+      }
+    }
+    assert!(start.elapsed() >= Duration::from_millis(9_900));
+  });
+  assert!(wall.elapsed() < Duration::from_secs(5));
+}
+
+// Each session counts kcp timestamps from its own start on its own runtime.
+// With a base shared by the process, the first session of a paused runtime
+// an hour ahead would stop the timestamps of later sessions of a real clock
+// for that hour, and the lost segment would never be resent.
+#[test]
+fn test_memory_retransmit_clock_base() {
+  use crate::listener::SwiftKcpListener;
+  use crate::stream::{StreamOptions, SwiftKcpStream};
+  use std::time::Duration;
+  use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+  let network = Arc::new(MemoryNetwork::default());
+  let params = KcpConfigParams::default();
+  let options = StreamOptions::from_params(&params);
+  let config: KcpConfig = params.into();
+  let server_addr: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+
+  let rt = tokio::runtime::Builder::new_current_thread()
+    .enable_all()
+    .start_paused(true)
+    .build()
+    .unwrap();
+  rt.block_on(async {
+    tokio::time::sleep(Duration::from_secs(3600)).await;
+    let socket = Arc::new(network.bind(client_addr(&server_addr)).unwrap());
+    KcpStream::connect_with_transport(&config, socket, server_addr)
+      .await
+      .unwrap();
+  });
+  drop(rt);
+
+  let rt = tokio::runtime::Runtime::new().unwrap();
+  rt.block_on(async {
+    // The first segment is lost, nothing is bound to the address yet.
+    let socket = Arc::new(network.bind(client_addr(&server_addr)).unwrap());
+    let stream = KcpStream::connect_with_transport(&config, socket, server_addr)
+      .await
+      .unwrap();
+    let client = SwiftKcpStream::new(stream, &config, options);
+    client.write(b"hello").await.unwrap();
+
+    let socket = Arc::new(network.bind(server_addr).unwrap());
+    let listener = KcpListener::from_transport(config, socket).await.unwrap();
+    let listener = SwiftKcpListener::new(vec![(listener, None)], config, options).unwrap();
+    let accepted = tokio::time::timeout(Duration::from_secs(5), listener.accept()).await;
+    let (server, _) = accepted.expect("the lost segment wasn't resent").unwrap();
+    assert_eq!(server.read().await.unwrap(), b"hello");
+  });
+}
@@ -0,0 +1,296 @@
+use crate::error::SwiftKcpError;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_kcp::KcpObserver;
+
+// Requests larger than this are answered with 400.
+const MAX_REQUEST: usize = 8 * 1024;
+// Connections that don't send a whole request within this are answered with
+// 408, so that idle clients don't hold a task each.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Upper bounds of the RTT histogram buckets in milliseconds.
+const RTT_BUCKETS: [u64; 10] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000];
+
+lazy_static! {
+  static ref BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+  static ref BYTES_IN: AtomicU64 = AtomicU64::new(0);
+  static ref STREAMS_CONNECTED: AtomicU64 = AtomicU64::new(0);
+  static ref STREAMS_ACCEPTED: AtomicU64 = AtomicU64::new(0);
+  static ref ERRORS: DashMap<&'static str, u64> = DashMap::new();
+  static ref RETRANSMITS: AtomicU64 = AtomicU64::new(0);
+  static ref RTT_HISTOGRAM: RttHistogram = RttHistogram::default();
+}
+
+// Cumulative counts as in the Prometheus text format, the last one is +Inf.
+#[derive(Default)]
+struct RttHistogram {
+  buckets: [AtomicU64; RTT_BUCKETS.len() + 1],
+  sum_micros: AtomicU64,
+}
+
+impl RttHistogram {
+  fn observe(&self, rtt: Duration) {
+    let millis = rtt.as_millis() as u64;
+    let first = RTT_BUCKETS
+      .iter()
+      .position(|bound| millis <= *bound)
+      .unwrap_or(RTT_BUCKETS.len());
+    for bucket in &self.buckets[first..] {
+      bucket.fetch_add(1, Ordering::Relaxed);
+    }
+    self
+      .sum_micros
+      .fetch_add(rtt.as_micros() as u64, Ordering::Relaxed);
+  }
+}
+
+// Receives the RTT samples and retransmissions of every kcp session.
+struct SessionObserver;
+
+impl KcpObserver for SessionObserver {
+  fn on_rtt(&self, rtt: Duration) {
+    RTT_HISTOGRAM.observe(rtt);
+  }
+
+  fn on_retransmit(&self) {
+    RETRANSMITS.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+/// Counts the RTT samples and retransmissions of the kcp sessions created
+/// from now on and of the open ones.
+pub fn observe_sessions() {
+  tokio_kcp::set_observer(Some(Arc::new(SessionObserver)));
+}
+
+/// Number of items in each manager when the snapshot is taken.
+#[derive(Debug, Default)]
+pub struct Gauges {
+  pub streams: usize,
+  pub listeners: usize,
+  pub mux_sessions: usize,
+  pub endpoints: usize,
+}
+
+/// Counts bytes the host wrote to streams and mux sub-streams, before
+/// compression.
+pub fn record_bytes_out(n: usize) {
+  BYTES_OUT.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// Counts bytes read by the host from streams and mux sub-streams, after
+/// decompression.
+pub fn record_bytes_in(n: usize) {
+  BYTES_IN.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+pub fn record_connected() {
+  STREAMS_CONNECTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_accepted() {
+  STREAMS_ACCEPTED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts `err` by variant, meant for `Result::inspect_err`.
+pub fn record_error(err: &SwiftKcpError) {
+  *ERRORS.entry(err.kind()).or_insert(0) += 1;
+}
+
+/// Renders the metrics in the Prometheus text format.
+pub fn render(gauges: &Gauges) -> String {
+  let mut out = String::new();
+
+  let mut gauge = |name: &str, help: &str, value: usize| {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+  };
+  gauge("kcp_streams", "Open kcp streams.", gauges.streams);
+  gauge("kcp_listeners", "Open kcp listeners.", gauges.listeners);
+  gauge(
+    "kcp_mux_sessions",
+    "Open mux sessions.",
+    gauges.mux_sessions,
+  );
+  gauge("kcp_endpoints", "Open client endpoints.", gauges.endpoints);
+
+  let _ = writeln!(out, "# HELP kcp_streams_opened_total Streams opened.");
+  let _ = writeln!(out, "# TYPE kcp_streams_opened_total counter");
+  let _ = writeln!(
+    out,
+    "kcp_streams_opened_total{{kind=\"connect\"}} {}",
+    STREAMS_CONNECTED.load(Ordering::Relaxed)
+  );
+  let _ = writeln!(
+    out,
+    "kcp_streams_opened_total{{kind=\"accept\"}} {}",
+    STREAMS_ACCEPTED.load(Ordering::Relaxed)
+  );
+
+  let _ = writeln!(
+    out,
+    "# HELP kcp_bytes_total Payload bytes written and read by the application."
+  );
+  let _ = writeln!(out, "# TYPE kcp_bytes_total counter");
+  let _ = writeln!(
+    out,
+    "kcp_bytes_total{{direction=\"out\"}} {}",
+    BYTES_OUT.load(Ordering::Relaxed)
+  );
+  let _ = writeln!(
+    out,
+    "kcp_bytes_total{{direction=\"in\"}} {}",
+    BYTES_IN.load(Ordering::Relaxed)
+  );
+
+  let _ = writeln!(
+    out,
+    "# HELP kcp_errors_total Errors of stream, listener and mux operations."
+  );
+  let _ = writeln!(out, "# TYPE kcp_errors_total counter");
+  let mut errors: Vec<_> = ERRORS.iter().map(|e| (*e.key(), *e.value())).collect();
+  errors.sort();
+  for (kind, count) in errors {
+    let _ = writeln!(out, "kcp_errors_total{{kind=\"{}\"}} {}", kind, count);
+  }
+
+  let _ = writeln!(
+    out,
+    "# HELP kcp_retransmits_total Data segments sent again by kcp sessions."
+  );
+  let _ = writeln!(out, "# TYPE kcp_retransmits_total counter");
+  let _ = writeln!(
+    out,
+    "kcp_retransmits_total {}",
+    RETRANSMITS.load(Ordering::Relaxed)
+  );
+
+  let _ = writeln!(
+    out,
+    "# HELP kcp_rtt_seconds Round trip times measured by kcp sessions."
+  );
+  let _ = writeln!(out, "# TYPE kcp_rtt_seconds histogram");
+  for (bound, bucket) in RTT_BUCKETS.iter().zip(&RTT_HISTOGRAM.buckets) {
+    let _ = writeln!(
+      out,
+      "kcp_rtt_seconds_bucket{{le=\"{}\"}} {}",
+      *bound as f64 / 1000.0,
+      bucket.load(Ordering::Relaxed)
+    );
+  }
+  let count = RTT_HISTOGRAM.buckets[RTT_BUCKETS.len()].load(Ordering::Relaxed);
+  let _ = writeln!(out, "kcp_rtt_seconds_bucket{{le=\"+Inf\"}} {}", count);
+  let _ = writeln!(
+    out,
+    "kcp_rtt_seconds_sum {}",
+    RTT_HISTOGRAM.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+  );
+  let _ = writeln!(out, "kcp_rtt_seconds_count {}", count);
+
+  out
+}
+
+/// Serves `GET /metrics` on `listener` until the task is aborted.
+// Should be called within the tokio runtime.
+pub async fn serve(listener: TcpListener) {
+  loop {
+    let (conn, _) = match listener.accept().await {
+      Ok(conn) => conn,
+      Err(e) => {
+        tracing::warn!(error = %e, "metrics accept failed");
+        continue;
+      }
+    };
+    tokio::spawn(async move {
+      if let Err(e) = respond(conn).await {
+        tracing::debug!(error = %e, "metrics request failed");
+      }
+    });
+  }
+}
+
+async fn respond(mut conn: TcpStream) -> std::io::Result<()> {
+  let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut conn)).await {
+    Ok(Ok(Some(request))) => request,
+    Ok(Ok(None)) => return Ok(()),
+    Ok(Err(e)) if e.kind() == std::io::ErrorKind::InvalidData => {
+      return write_response(&mut conn, "400 Bad Request", "").await
+    }
+    Ok(Err(e)) => return Err(e),
+    Err(_) => return write_response(&mut conn, "408 Request Timeout", "").await,
+  };
+
+  let request_line = request.split(|b| *b == b'\n').next().unwrap_or_default();
+  let mut parts = std::str::from_utf8(request_line)
+    .unwrap_or_default()
+    .split(' ');
+  match (parts.next(), parts.next()) {
+    (Some("GET"), Some("/metrics")) => {
+      let body = crate::metrics_snapshot().await;
+      write_response(&mut conn, "200 OK", &body).await
+    }
+    _ => write_response(&mut conn, "404 Not Found", "").await,
+  }
+}
+
+// Reads up to the end of the headers, `None` if the client closed the
+// connection before.
+async fn read_request(conn: &mut TcpStream) -> std::io::Result<Option<Vec<u8>>> {
+  let mut request = Vec::new();
+  let mut buf = [0u8; 1024];
+  while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+    let n = conn.read(&mut buf).await?;
+    if n == 0 {
+      return Ok(None);
+    }
+    request.extend_from_slice(&buf[..n]);
+    if request.len() > MAX_REQUEST {
+      return Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "request too large",
+      ));
+    }
+  }
+  Ok(Some(request))
+}
+
+async fn write_response(conn: &mut TcpStream, status: &str, body: &str) -> std::io::Result<()> {
+  let response = format!(
+    "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+    status,
+    body.len(),
+    body
+  );
+  conn.write_all(response.as_bytes()).await?;
+  conn.shutdown().await
+}
+
+#[test]
+fn test_render() {
+  record_bytes_out(10);
+  record_error(&SwiftKcpError::NoStreamForId { id: 1 });
+  SessionObserver.on_retransmit();
+  SessionObserver.on_rtt(Duration::from_millis(20));
+  SessionObserver.on_rtt(Duration::from_secs(10));
+
+  let text = render(&Gauges {
+    streams: 2,
+    ..Default::default()
+  });
+  assert!(text.contains("\nkcp_streams 2\n"));
+  assert!(text.contains("# TYPE kcp_bytes_total counter\n"));
+  assert!(text.contains("kcp_errors_total{kind=\"NoStreamForId\"} "));
+  assert!(text.contains("\nkcp_retransmits_total "));
+  assert!(text.contains("# TYPE kcp_rtt_seconds histogram\n"));
+  // Other tests record samples too, only the buckets the 20ms sample falls in
+  // are known to be counted.
+  assert!(!text.contains("kcp_rtt_seconds_bucket{le=\"0.025\"} 0\n"));
+  assert!(!text.contains("kcp_rtt_seconds_bucket{le=\"+Inf\"} 0\n"));
+}
@@ -0,0 +1,561 @@
+use crate::error::SwiftKcpError;
+use crate::stream::SwiftKcpStream;
+use crate::Result;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use tokio::sync::{mpsc, Mutex, Notify};
+use tokio::task::JoinHandle;
+
+pub type SubStreamId = u32;
+
+// Frame layout: cmd(u8) | sid(u32) | len(u32) | payload
+const HEADER_LEN: usize = 9;
+const MAX_FRAME_PAYLOAD: usize = 32 * 1024;
+
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+// Payload: consumed(u32) | window(u32)
+const CMD_UPD: u8 = 3;
+// The sender overran the window, reads and writes of the sub-stream fail.
+const CMD_RST: u8 = 4;
+
+const DEFAULT_STREAM_WINDOW: u32 = 256 * 1024;
+const DEFAULT_ACCEPT_BACKLOG: u32 = 64;
+
+#[derive(uniffi::Record, Default)]
+pub struct MuxConfigParams {
+  /// Receive window of each sub-stream in bytes, default is 256KB
+  pub stream_window: Option<u32>,
+  /// Max opened sub-streams waiting for `mux_accept`, default is 64
+  pub accept_backlog: Option<u32>,
+}
+
+struct Frame {
+  cmd: u8,
+  sid: SubStreamId,
+  payload: Vec<u8>,
+}
+
+impl Frame {
+  fn encode(cmd: u8, sid: SubStreamId, payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+    buf.push(cmd);
+    buf.extend_from_slice(&sid.to_be_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+  }
+
+  fn update(sid: SubStreamId, consumed: u32, window: u32) -> Vec<u8> {
+    let mut payload = [0u8; 8];
+    payload[..4].copy_from_slice(&consumed.to_be_bytes());
+    payload[4..].copy_from_slice(&window.to_be_bytes());
+    Frame::encode(CMD_UPD, sid, &payload)
+  }
+
+  // Takes one complete frame off the front of `buf` if there is one. The
+  // length is checked before waiting for the payload, so a peer can't make
+  // `buf` grow past one frame.
+  fn parse(buf: &mut Vec<u8>) -> Result<Option<Frame>> {
+    if buf.len() < HEADER_LEN {
+      return Ok(None);
+    }
+    let len = u32::from_be_bytes(buf[5..9].try_into().unwrap()) as usize;
+    if len > MAX_FRAME_PAYLOAD {
+      return Err(SwiftKcpError::Protocol {
+        msg: format!(
+          "mux frame of {} bytes, at most {} allowed",
+          len, MAX_FRAME_PAYLOAD
+        ),
+      });
+    }
+    if buf.len() < HEADER_LEN + len {
+      return Ok(None);
+    }
+
+    let rest = buf.split_off(HEADER_LEN + len);
+    let frame = std::mem::replace(buf, rest);
+
+    Ok(Some(Frame {
+      cmd: frame[0],
+      sid: u32::from_be_bytes(frame[1..5].try_into().unwrap()),
+      payload: frame[HEADER_LEN..].to_vec(),
+    }))
+  }
+}
+
+#[derive(Default)]
+struct SubStreamState {
+  recv_chunks: VecDeque<Vec<u8>>,
+  recv_fin: bool,
+  local_fin: bool,
+  // Why the sub-stream was reset, by us when the peer sent more than our
+  // window allows or by the peer when we did.
+  reset: Option<&'static str>,
+  // Bytes received, bytes handed to the reader, and the value last reported
+  // to the peer.
+  received: u32,
+  consumed: u32,
+  reported: u32,
+  // Bytes sent, and the peer's view of what it has consumed.
+  sent: u32,
+  peer_consumed: u32,
+  peer_window: u32,
+}
+
+#[derive(Default)]
+struct SubStream {
+  state: StdMutex<SubStreamState>,
+  readable: Notify,
+  writable: Notify,
+}
+
+impl SubStream {
+  fn close_remote(&self) {
+    self.state.lock().unwrap().recv_fin = true;
+    self.readable.notify_one();
+    self.writable.notify_one();
+  }
+
+  // Drops what was received, reads and writes fail from now on.
+  fn reset(&self, reason: &'static str) {
+    {
+      let mut state = self.state.lock().unwrap();
+      state.reset = Some(reason);
+      state.recv_fin = true;
+      state.local_fin = true;
+      state.recv_chunks.clear();
+    }
+    self.readable.notify_one();
+    self.writable.notify_one();
+  }
+}
+
+fn reset_error(sid: SubStreamId, reason: &str) -> SwiftKcpError {
+  SwiftKcpError::Protocol {
+    msg: format!("mux sub-stream {} was reset, {}", sid, reason),
+  }
+}
+
+struct MuxInner {
+  stream: Arc<SwiftKcpStream>,
+  client: bool,
+  window: u32,
+  next_sid: AtomicU32,
+  // Highest id the peer opened, ids aren't reused so the ones up to it that
+  // are gone were closed.
+  last_peer_sid: AtomicU32,
+  closed: AtomicBool,
+  streams: StdMutex<HashMap<SubStreamId, Arc<SubStream>>>,
+  // Frames sent on behalf of the receiving side, so that it never waits for
+  // the stream's writer.
+  control_tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl MuxInner {
+  fn get(&self, sid: SubStreamId) -> Option<Arc<SubStream>> {
+    self.streams.lock().unwrap().get(&sid).cloned()
+  }
+
+  // Whether `sid` was opened and has been removed since.
+  fn was_closed(&self, sid: SubStreamId) -> bool {
+    let opened = match (sid % 2 == 1) == self.client {
+      true => sid < self.next_sid.load(Ordering::SeqCst),
+      false => sid <= self.last_peer_sid.load(Ordering::SeqCst),
+    };
+    sid != 0 && opened && self.get(sid).is_none()
+  }
+
+  // Queues a frame for `send_loop`, lost if the session is closed.
+  fn send_control(&self, frame: Vec<u8>) {
+    let _ = self.control_tx.send(frame);
+  }
+
+  fn insert(&self, sid: SubStreamId) -> Arc<SubStream> {
+    let sub = Arc::new(SubStream::default());
+    self.streams.lock().unwrap().insert(sid, sub.clone());
+    sub
+  }
+
+  // Forgets a sub-stream once both sides have sent FIN.
+  fn remove_if_finished(&self, sid: SubStreamId, sub: &SubStream) {
+    let finished = {
+      let state = sub.state.lock().unwrap();
+      state.local_fin && state.recv_fin && state.recv_chunks.is_empty()
+    };
+    if finished {
+      self.streams.lock().unwrap().remove(&sid);
+    }
+  }
+
+  fn close(&self) {
+    self.closed.store(true, Ordering::Release);
+    for sub in self.streams.lock().unwrap().values() {
+      sub.close_remote();
+    }
+  }
+
+  fn handle_frame(&self, frame: Frame, accept_tx: &mpsc::Sender<SubStreamId>) -> Result<()> {
+    match frame.cmd {
+      CMD_SYN => {
+        // The peer opens even ids if we are the client and odd ones otherwise.
+        if frame.sid == 0 || (frame.sid % 2 == 1) == self.client {
+          return Err(SwiftKcpError::Protocol {
+            msg: format!("mux sub-stream {} opened by the wrong side", frame.sid),
+          });
+        }
+        // A repeated SYN, or one of a sub-stream closed already.
+        if frame.sid <= self.last_peer_sid.load(Ordering::SeqCst) {
+          return Ok(());
+        }
+        self.last_peer_sid.store(frame.sid, Ordering::SeqCst);
+        let sub = self.insert(frame.sid);
+        if frame.payload.len() >= 4 {
+          let window = u32::from_be_bytes(frame.payload[..4].try_into().unwrap());
+          sub.state.lock().unwrap().peer_window = window;
+        }
+        if accept_tx.try_send(frame.sid).is_err() {
+          self.streams.lock().unwrap().remove(&frame.sid);
+          self.send_control(Frame::encode(CMD_FIN, frame.sid, &[]));
+          return Ok(());
+        }
+        self.send_control(Frame::update(frame.sid, 0, self.window));
+      }
+      CMD_FIN => {
+        if let Some(sub) = self.get(frame.sid) {
+          sub.close_remote();
+          self.remove_if_finished(frame.sid, &sub);
+        }
+      }
+      CMD_PSH => {
+        if let Some(sub) = self.get(frame.sid) {
+          let overrun = {
+            let mut state = sub.state.lock().unwrap();
+            let len = frame.payload.len() as u32;
+            let buffered = state.received.wrapping_sub(state.consumed);
+            if state.reset.is_some() {
+              return Ok(());
+            }
+            if buffered as u64 + len as u64 > self.window as u64 {
+              true
+            } else {
+              state.received = state.received.wrapping_add(len);
+              state.recv_chunks.push_back(frame.payload);
+              false
+            }
+          };
+          if overrun {
+            tracing::debug!(sid = frame.sid, "mux sub-stream reset, window overrun");
+            // Kept until the reset is reported to the reader or writer.
+            sub.reset("the peer overran its window");
+            self.send_control(Frame::encode(CMD_RST, frame.sid, &[]));
+            return Ok(());
+          }
+          sub.readable.notify_one();
+        }
+      }
+      CMD_RST => {
+        if let Some(sub) = self.get(frame.sid) {
+          tracing::debug!(sid = frame.sid, "mux sub-stream reset by the peer");
+          sub.reset("we overran the peer's window");
+        }
+      }
+      CMD_UPD => {
+        if let (Some(sub), true) = (self.get(frame.sid), frame.payload.len() >= 8) {
+          let mut state = sub.state.lock().unwrap();
+          state.peer_consumed = u32::from_be_bytes(frame.payload[..4].try_into().unwrap());
+          state.peer_window = u32::from_be_bytes(frame.payload[4..8].try_into().unwrap());
+          drop(state);
+          sub.writable.notify_one();
+        }
+      }
+      _ => {}
+    }
+
+    Ok(())
+  }
+
+  async fn recv_loop(self: Arc<Self>, accept_tx: mpsc::Sender<SubStreamId>) {
+    let mut buf: Vec<u8> = Vec::new();
+
+    loop {
+      let data = match self.stream.read().await {
+        Ok(data) if !data.is_empty() => data,
+        _ => break,
+      };
+      buf.extend_from_slice(&data);
+
+      loop {
+        let ret = match Frame::parse(&mut buf) {
+          Ok(Some(frame)) => self.handle_frame(frame, &accept_tx),
+          Ok(None) => break,
+          Err(e) => Err(e),
+        };
+        if let Err(e) = ret {
+          tracing::debug!(error = %e, "mux session closed");
+          self.close();
+          return;
+        }
+      }
+    }
+
+    self.close();
+  }
+
+  async fn send_loop(self: Arc<Self>, mut control_rx: mpsc::UnboundedReceiver<Vec<u8>>) {
+    while let Some(frame) = control_rx.recv().await {
+      if let Err(e) = self.stream.write(&frame).await {
+        tracing::debug!(error = %e, "mux session closed");
+        self.close();
+        return;
+      }
+    }
+  }
+}
+
+/// A smux-like multiplexer running on top of one kcp stream.
+///
+/// Sub-stream ids opened by the client side are odd and the ones opened by the
+/// server side are even so both sides can open sub-streams at the same time.
+/// Peers breaking the framing or the id rule close the session, and peers
+/// sending past a sub-stream's window get that sub-stream reset with a RST
+/// frame, which fails their pending writes.
+pub struct MuxSession {
+  inner: Arc<MuxInner>,
+  accept_rx: Mutex<mpsc::Receiver<SubStreamId>>,
+  recv_task: JoinHandle<()>,
+  send_task: JoinHandle<()>,
+}
+
+impl Drop for MuxSession {
+  fn drop(&mut self) {
+    self.recv_task.abort();
+    self.send_task.abort();
+  }
+}
+
+impl MuxSession {
+  pub fn new(
+    rt: &tokio::runtime::Runtime,
+    stream: Arc<SwiftKcpStream>,
+    client: bool,
+    params: MuxConfigParams,
+  ) -> Self {
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    let inner = Arc::new(MuxInner {
+      stream,
+      client,
+      window: params.stream_window.unwrap_or(DEFAULT_STREAM_WINDOW).max(1),
+      next_sid: AtomicU32::new(if client { 1 } else { 2 }),
+      last_peer_sid: AtomicU32::new(0),
+      closed: AtomicBool::new(false),
+      streams: StdMutex::new(HashMap::new()),
+      control_tx,
+    });
+
+    let backlog = params
+      .accept_backlog
+      .unwrap_or(DEFAULT_ACCEPT_BACKLOG)
+      .max(1);
+    let (accept_tx, accept_rx) = mpsc::channel(backlog as usize);
+    let recv_task = rt.spawn(inner.clone().recv_loop(accept_tx));
+    let send_task = rt.spawn(inner.clone().send_loop(control_rx));
+
+    Self {
+      inner,
+      accept_rx: Mutex::new(accept_rx),
+      recv_task,
+      send_task,
+    }
+  }
+
+  // Sub-streams closed on both sides, or reset and reported, are removed and
+  // fail with `SubStreamClosed`.
+  fn get_sub_stream(&self, sid: SubStreamId) -> Result<Arc<SubStream>> {
+    match self.inner.get(sid) {
+      Some(sub) => Ok(sub),
+      None if self.inner.was_closed(sid) => Err(SwiftKcpError::SubStreamClosed { sid }),
+      None => Err(SwiftKcpError::NoSubStreamForId { sid }),
+    }
+  }
+
+  // Reports a reset once, later calls find the sub-stream closed.
+  fn reset(&self, sid: SubStreamId, sub: &SubStream, reason: &str) -> SwiftKcpError {
+    self.inner.remove_if_finished(sid, sub);
+    reset_error(sid, reason)
+  }
+
+  fn check_open(&self) -> Result<()> {
+    if self.inner.closed.load(Ordering::Acquire) {
+      return Err(SwiftKcpError::MuxClosed);
+    }
+    Ok(())
+  }
+
+  pub async fn open(&self) -> Result<SubStreamId> {
+    self.check_open()?;
+    let sid = self.inner.next_sid.fetch_add(2, Ordering::SeqCst);
+    // Writes wait for the peer's window, it's sent as soon as the SYN arrives.
+    // Assuming a window could overrun a smaller one and reset the sub-stream.
+    self.inner.insert(sid);
+
+    let frame = Frame::encode(CMD_SYN, sid, &self.inner.window.to_be_bytes());
+    self.inner.stream.write(&frame).await?;
+
+    Ok(sid)
+  }
+
+  pub async fn accept(&self) -> Result<SubStreamId> {
+    self
+      .accept_rx
+      .lock()
+      .await
+      .recv()
+      .await
+      .ok_or(SwiftKcpError::MuxClosed)
+  }
+
+  pub async fn write(&self, sid: SubStreamId, data: &[u8]) -> Result<()> {
+    let sub = self.get_sub_stream(sid)?;
+    let mut data = data;
+
+    while !data.is_empty() {
+      self.check_open()?;
+
+      let n = {
+        let mut state = sub.state.lock().unwrap();
+        if let Some(reason) = state.reset {
+          drop(state);
+          return Err(self.reset(sid, &sub, reason));
+        }
+        // A FIN before any window means the peer refused the sub-stream.
+        if state.local_fin || (state.recv_fin && state.peer_window == 0) {
+          return Err(SwiftKcpError::SubStreamClosed { sid });
+        }
+        let inflight = state.sent.wrapping_sub(state.peer_consumed);
+        let available = state.peer_window.saturating_sub(inflight) as usize;
+        let n = available.min(data.len()).min(MAX_FRAME_PAYLOAD);
+        state.sent = state.sent.wrapping_add(n as u32);
+        n
+      };
+
+      // The peer's window is full, wait for an update.
+      if n == 0 {
+        sub.writable.notified().await;
+        continue;
+      }
+
+      let frame = Frame::encode(CMD_PSH, sid, &data[..n]);
+      self.inner.stream.write(&frame).await?;
+      data = &data[n..];
+    }
+
+    Ok(())
+  }
+
+  /// Returns an empty vec once the peer has closed the sub-stream.
+  pub async fn read(&self, sid: SubStreamId) -> Result<Vec<u8>> {
+    let sub = self.get_sub_stream(sid)?;
+
+    loop {
+      let (data, update) = {
+        let mut state = sub.state.lock().unwrap();
+        match state.recv_chunks.pop_front() {
+          Some(data) => {
+            state.consumed = state.consumed.wrapping_add(data.len() as u32);
+            let unreported = state.consumed.wrapping_sub(state.reported);
+            let update = unreported >= self.inner.window / 2;
+            if update {
+              state.reported = state.consumed;
+            }
+            (Some(data), update.then_some(state.consumed))
+          }
+          None if state.reset.is_some() => {
+            let reason = state.reset.unwrap();
+            drop(state);
+            return Err(self.reset(sid, &sub, reason));
+          }
+          None if state.recv_fin => (Some(Vec::new()), None),
+          None => (None, None),
+        }
+      };
+
+      if let Some(consumed) = update {
+        self
+          .inner
+          .send_control(Frame::update(sid, consumed, self.inner.window));
+      }
+
+      match data {
+        Some(data) => {
+          if data.is_empty() {
+            self.inner.remove_if_finished(sid, &sub);
+          }
+          return Ok(data);
+        }
+        None => sub.readable.notified().await,
+      }
+    }
+  }
+
+  pub async fn close_stream(&self, sid: SubStreamId) -> Result<()> {
+    let sub = match self.get_sub_stream(sid) {
+      Err(SwiftKcpError::SubStreamClosed { .. }) => return Ok(()),
+      sub => sub?,
+    };
+
+    {
+      let mut state = sub.state.lock().unwrap();
+      if state.local_fin {
+        return Ok(());
+      }
+      state.local_fin = true;
+    }
+    sub.writable.notify_one();
+
+    if !self.inner.closed.load(Ordering::Acquire) {
+      self
+        .inner
+        .stream
+        .write(&Frame::encode(CMD_FIN, sid, &[]))
+        .await?;
+    }
+    self.inner.remove_if_finished(sid, &sub);
+
+    Ok(())
+  }
+
+  pub fn stream_count(&self) -> usize {
+    self.inner.streams.lock().unwrap().len()
+  }
+}
+
+#[test]
+fn test_frame_parse() {
+  let mut buf = Frame::encode(CMD_PSH, 3, b"hello");
+  buf.extend_from_slice(&Frame::update(3, 5, 1024));
+  buf.extend_from_slice(&Frame::encode(CMD_FIN, 3, &[])[..4]);
+
+  let frame = Frame::parse(&mut buf).unwrap().unwrap();
+  assert_eq!(frame.cmd, CMD_PSH);
+  assert_eq!(frame.sid, 3);
+  assert_eq!(frame.payload, b"hello");
+
+  let frame = Frame::parse(&mut buf).unwrap().unwrap();
+  assert_eq!(frame.cmd, CMD_UPD);
+  assert_eq!(frame.payload.len(), 8);
+
+  // Incomplete frames stay in the buffer.
+  assert!(Frame::parse(&mut buf).unwrap().is_none());
+  assert_eq!(buf.len(), 4);
+
+  // Oversized frames are rejected from their header alone.
+  let mut buf = vec![CMD_PSH, 0, 0, 0, 3];
+  buf.extend_from_slice(&u32::MAX.to_be_bytes());
+  assert!(matches!(
+    Frame::parse(&mut buf),
+    Err(SwiftKcpError::Protocol { .. })
+  ));
+}
@@ -0,0 +1,230 @@
+use socket2::SockRef;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio_kcp::KcpSession;
+
+// How long the peer has to answer a probe. It answers at its next flush.
+const PROBE_WAIT: Duration = Duration::from_millis(300);
+// Probes of a size sent before it's taken as too big, a probe may just be lost.
+const PROBE_ATTEMPTS: usize = 2;
+// Smallest UDP payload tried, the one of the IPv4 minimum MTU. A peer that
+// doesn't answer it doesn't answer probes at all.
+const MIN_MTU: usize = 548;
+// The search stops once the largest size that passed and the smallest one
+// that didn't are this close.
+const PRECISION: usize = 8;
+
+#[derive(uniffi::Record, Debug, Clone)]
+pub struct PathMtu {
+  /// Kcp mtu the stream uses
+  pub mtu: u32,
+  /// Largest mtu that passed the probes, unset if the stream wasn't probed or
+  /// probing isn't supported on this platform
+  pub discovered: Option<u32>,
+}
+
+// Sets or clears the don't fragment bit, returns false on platforms we can't
+// set it on. Cleared, the socket is back to the system default.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_dont_fragment(socket: &SockRef, addr: &SocketAddr, on: bool) -> io::Result<bool> {
+  let (level, name, value) = match (addr, on) {
+    (SocketAddr::V4(..), true) => (
+      libc::IPPROTO_IP,
+      libc::IP_MTU_DISCOVER,
+      libc::IP_PMTUDISC_DO,
+    ),
+    (SocketAddr::V4(..), false) => (
+      libc::IPPROTO_IP,
+      libc::IP_MTU_DISCOVER,
+      libc::IP_PMTUDISC_WANT,
+    ),
+    (SocketAddr::V6(..), true) => (
+      libc::IPPROTO_IPV6,
+      libc::IPV6_MTU_DISCOVER,
+      libc::IPV6_PMTUDISC_DO,
+    ),
+    (SocketAddr::V6(..), false) => (
+      libc::IPPROTO_IPV6,
+      libc::IPV6_MTU_DISCOVER,
+      libc::IPV6_PMTUDISC_WANT,
+    ),
+  };
+  setsockopt(socket, level, name, value)?;
+  Ok(true)
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+fn set_dont_fragment(socket: &SockRef, addr: &SocketAddr, on: bool) -> io::Result<bool> {
+  let (level, name) = match addr {
+    SocketAddr::V4(..) => (libc::IPPROTO_IP, libc::IP_DONTFRAG),
+    SocketAddr::V6(..) => (libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG),
+  };
+  setsockopt(socket, level, name, on as i32)?;
+  Ok(true)
+}
+
+#[cfg(not(any(
+  target_os = "linux",
+  target_os = "android",
+  target_os = "macos",
+  target_os = "ios"
+)))]
+fn set_dont_fragment(_socket: &SockRef, _addr: &SocketAddr, _on: bool) -> io::Result<bool> {
+  Ok(false)
+}
+
+#[cfg(any(
+  target_os = "linux",
+  target_os = "android",
+  target_os = "macos",
+  target_os = "ios"
+))]
+fn setsockopt(socket: &SockRef, level: i32, name: i32, value: i32) -> io::Result<()> {
+  use std::os::unix::io::AsRawFd;
+
+  // SAFETY: `value` outlives the call and its size is passed along.
+  let ret = unsafe {
+    libc::setsockopt(
+      socket.as_raw_fd(),
+      level,
+      name,
+      &value as *const i32 as *const libc::c_void,
+      std::mem::size_of::<i32>() as libc::socklen_t,
+    )
+  };
+  if ret != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+#[cfg(unix)]
+fn is_too_big(err: &io::Error) -> bool {
+  err.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(not(unix))]
+fn is_too_big(_err: &io::Error) -> bool {
+  false
+}
+
+// A probe of `size` bytes passes if the peer answers it. The kernel fails
+// sends above the path MTU it knows of right away.
+async fn probe(session: &KcpSession, size: usize) -> io::Result<bool> {
+  for _ in 0..PROBE_ATTEMPTS {
+    let received = session.received_packets();
+    match session.send_padded_window_probe(size) {
+      Ok(()) => {}
+      Err(e) if is_too_big(&e) => return Ok(false),
+      Err(e) => return Err(e),
+    }
+    let answer = session.wait_received_after(received);
+    if tokio::time::timeout(PROBE_WAIT, answer).await.is_ok() {
+      return Ok(true);
+    }
+  }
+  Ok(false)
+}
+
+/// Finds the largest kcp mtu up to `max_mtu` that reaches `remote` without IP
+/// fragmentation. The connected `session` sends window probes padded to the
+/// sizes of a binary search with the DF bit set on `udp`, the socket its
+/// datagrams leave from, and the largest one the peer answers wins. Returns
+/// `None` where the DF bit can't be set or if the peer answers none.
+///
+/// Meant to run before the stream carries data, answers to anything else would
+/// pass a probe. `udp` must send the session's datagrams itself, through a
+/// relay the probes would only be checked on the loopback hop.
+// Should be called within the tokio runtime.
+pub async fn discover(
+  session: &KcpSession,
+  udp: &UdpSocket,
+  remote: SocketAddr,
+  max_mtu: usize,
+) -> io::Result<Option<usize>> {
+  let socket = SockRef::from(udp);
+  if !set_dont_fragment(&socket, &remote, true)? {
+    return Ok(None);
+  }
+
+  let discovered = search(session, max_mtu).await;
+  // Later datagrams may be fragmented again should the path change.
+  set_dont_fragment(&socket, &remote, false)?;
+  discovered
+}
+
+// Searches between the largest size that passed and the smallest one that
+// didn't, starting with `max_mtu` and `MIN_MTU`.
+async fn search(session: &KcpSession, max_mtu: usize) -> io::Result<Option<usize>> {
+  if probe(session, max_mtu).await? {
+    return Ok(Some(max_mtu));
+  }
+  // Nothing smaller left to try, or a peer that doesn't answer.
+  if max_mtu <= MIN_MTU || !probe(session, MIN_MTU).await? {
+    return Ok(None);
+  }
+
+  let (mut passed, mut failed) = (MIN_MTU, max_mtu);
+  while failed - passed > PRECISION {
+    let size = passed + (failed - passed) / 2;
+    match probe(session, size).await? {
+      true => passed = size,
+      false => failed = size,
+    }
+  }
+  Ok(Some(passed))
+}
+
+#[test]
+fn test_discover_loopback() {
+  use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+  let rt = tokio::runtime::Runtime::new().unwrap();
+  rt.block_on(async {
+    let config = KcpConfig::default();
+    let mut listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+      // Keeps the accepted sessions alive.
+      let mut accepted = Vec::new();
+      while let Ok(session) = listener.accept().await {
+        accepted.push(session);
+      }
+    });
+
+    let stream = KcpStream::connect(&config, addr).await.unwrap();
+    let transport = stream.session().kcp_socket().lock().transport().clone();
+    let udp = transport.udp_socket().unwrap();
+    let mtu = discover(stream.session(), udp, addr, 1400).await.unwrap();
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    assert_eq!(mtu, Some(1400));
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    let _ = mtu;
+
+    // IPv4 takes up to 65507 bytes of UDP payload, larger sends fail right away.
+    #[cfg(target_os = "linux")]
+    {
+      let mtu = discover(stream.session(), udp, addr, 70_000)
+        .await
+        .unwrap()
+        .unwrap();
+      assert!((65_507 - PRECISION..=65_507).contains(&mtu), "{}", mtu);
+    }
+
+    // Nobody answers on a port nothing listens on.
+    drop(stream);
+    let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = silent.local_addr().unwrap();
+    drop(silent);
+    let stream = KcpStream::connect(&config, addr).await.unwrap();
+    let transport = stream.session().kcp_socket().lock().transport().clone();
+    let udp = transport.udp_socket().unwrap();
+    // Given up after the largest and the smallest size.
+    let start = std::time::Instant::now();
+    let mtu = discover(stream.session(), udp, addr, 1400).await;
+    assert!(matches!(mtu, Ok(None) | Err(..)), "{:?}", mtu);
+    assert!(start.elapsed() < PROBE_WAIT * (2 * PROBE_ATTEMPTS as u32 + 1));
+  });
+}
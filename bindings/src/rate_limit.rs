@@ -0,0 +1,156 @@
+use crate::error::SwiftKcpError;
+use crate::Result;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(uniffi::Record, Debug, Clone)]
+pub struct RateLimitStats {
+  /// Limit of the stream itself
+  pub bytes_per_sec: Option<u64>,
+  pub burst: Option<u64>,
+  /// Limit shared by all streams accepted by the same listener
+  pub listener_bytes_per_sec: Option<u64>,
+  /// Upload throughput measured over the last second
+  pub throughput_bytes_per_sec: f64,
+  /// Bytes handed to kcp, after compression
+  pub bytes_written: u64,
+  /// Total time writes were delayed by the limits
+  pub throttled_millisec: u64,
+}
+
+struct TokenBucket {
+  bytes_per_sec: u64,
+  burst: u64,
+  tokens: f64,
+  last: Instant,
+}
+
+impl TokenBucket {
+  fn new(bytes_per_sec: u64, burst: u64) -> Self {
+    Self {
+      bytes_per_sec,
+      burst,
+      tokens: burst as f64,
+      last: Instant::now(),
+    }
+  }
+
+  // Takes `n` tokens, the bucket may go into debt. Returns how long the
+  // caller should wait until the debt is paid off.
+  fn take(&mut self, n: usize, now: Instant) -> Duration {
+    let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+    self.tokens = (self.tokens + elapsed * self.bytes_per_sec as f64).min(self.burst as f64);
+    self.last = now;
+
+    self.tokens -= n as f64;
+    if self.tokens >= 0.0 {
+      return Duration::ZERO;
+    }
+    Duration::from_secs_f64(-self.tokens / self.bytes_per_sec as f64)
+  }
+}
+
+/// A token bucket that can be changed or turned off at any time.
+#[derive(Default)]
+pub struct RateLimiter {
+  bucket: StdMutex<Option<TokenBucket>>,
+}
+
+impl RateLimiter {
+  /// A `burst` of 0 allows one second worth of bytes.
+  pub fn set(&self, bytes_per_sec: u64, burst: u64) -> Result<()> {
+    if bytes_per_sec == 0 {
+      return Err(SwiftKcpError::InvalidConfig {
+        field: "bytes_per_sec".to_string(),
+        reason: "must be positive".to_string(),
+      });
+    }
+    let burst = if burst == 0 { bytes_per_sec } else { burst };
+
+    *self.bucket.lock().unwrap() = Some(TokenBucket::new(bytes_per_sec, burst));
+    Ok(())
+  }
+
+  pub fn clear(&self) {
+    *self.bucket.lock().unwrap() = None;
+  }
+
+  pub fn limit(&self) -> Option<(u64, u64)> {
+    let bucket = self.bucket.lock().unwrap();
+    bucket.as_ref().map(|b| (b.bytes_per_sec, b.burst))
+  }
+
+  /// Waits until `n` bytes may be sent, returns how long it waited.
+  // Should be called within the tokio runtime.
+  pub async fn acquire(&self, n: usize) -> Duration {
+    let wait = match self.bucket.lock().unwrap().as_mut() {
+      Some(bucket) => bucket.take(n, Instant::now()),
+      None => Duration::ZERO,
+    };
+    if !wait.is_zero() {
+      tokio::time::sleep(wait).await;
+    }
+    wait
+  }
+}
+
+/// Bytes sent in the current and the last completed window.
+pub struct Throughput {
+  start: Instant,
+  bytes: u64,
+  last_rate: f64,
+}
+
+impl Default for Throughput {
+  fn default() -> Self {
+    Self {
+      start: Instant::now(),
+      bytes: 0,
+      last_rate: 0.0,
+    }
+  }
+}
+
+impl Throughput {
+  fn roll(&mut self, now: Instant) {
+    let elapsed = now.saturating_duration_since(self.start);
+    if elapsed < THROUGHPUT_WINDOW {
+      return;
+    }
+    // A window long past says nothing about the current rate.
+    self.last_rate = match elapsed < THROUGHPUT_WINDOW * 2 {
+      true => self.bytes as f64 / elapsed.as_secs_f64(),
+      false => 0.0,
+    };
+    self.start = now;
+    self.bytes = 0;
+  }
+
+  pub fn record(&mut self, n: usize) {
+    self.roll(Instant::now());
+    self.bytes += n as u64;
+  }
+
+  pub fn bytes_per_sec(&mut self) -> f64 {
+    self.roll(Instant::now());
+    self.last_rate
+  }
+}
+
+#[test]
+fn test_token_bucket() {
+  let start = Instant::now();
+  let mut bucket = TokenBucket::new(1000, 500);
+  bucket.last = start;
+
+  assert_eq!(bucket.take(500, start), Duration::ZERO);
+  assert_eq!(bucket.take(250, start), Duration::from_millis(250));
+  // 500ms later the debt of 250 is paid off and 250 tokens are refilled.
+  let later = start + Duration::from_millis(500);
+  assert_eq!(bucket.take(250, later), Duration::ZERO);
+  // Refilling never exceeds the burst.
+  let much_later = later + Duration::from_secs(10);
+  assert_eq!(bucket.take(600, much_later), Duration::from_millis(100));
+}
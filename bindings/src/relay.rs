@@ -0,0 +1,233 @@
+use crate::capture::{self, Capture, Direction};
+#[cfg(feature = "impairment")]
+use crate::impair::Impairment;
+#[cfg(feature = "impairment")]
+use crate::kcp_util::ImpairmentParams;
+use crate::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+const PACKET_BUF: usize = 65536;
+
+/// Peer address of each loopback socket a relay talks to a listener from.
+pub type PeerAddrs = Arc<StdMutex<HashMap<SocketAddr, SocketAddr>>>;
+
+#[derive(Default)]
+struct Routes {
+  // Records every datagram of the listener.
+  capture: Option<Arc<Capture>>,
+  // (peer address, conv) of an accepted session -> its running capture
+  capture_by_session: HashMap<(SocketAddr, u32), Arc<Capture>>,
+  // (inbound, outbound) impairment of every datagram
+  #[cfg(feature = "impairment")]
+  impairment: Option<(Arc<Impairment>, Arc<Impairment>)>,
+}
+
+/// What a listener relay does with the datagrams besides forwarding them.
+#[derive(Clone)]
+pub struct RelayRoutes {
+  routes: Arc<StdMutex<Routes>>,
+  // The socket peers send to.
+  front: Arc<UdpSocket>,
+}
+
+impl RelayRoutes {
+  /// Starts recording every datagram of the listener to `capture`, or stops
+  /// if it's `None`. Returns the capture it replaces.
+  pub fn set_capture(&self, capture: Option<Arc<Capture>>) -> Option<Arc<Capture>> {
+    std::mem::replace(&mut self.routes.lock().unwrap().capture, capture)
+  }
+
+  /// Route of the accepted session of `peer`, the address the relay sees, and
+  /// `conv`.
+  pub fn session(&self, peer: SocketAddr, conv: u32) -> SessionRoute {
+    SessionRoute {
+      routes: self.clone(),
+      key: (peer, conv),
+    }
+  }
+
+  /// Impairs every datagram of the listener in both directions.
+  #[cfg(feature = "impairment")]
+  pub fn set_impairment(&self, params: ImpairmentParams) {
+    // Each direction gets its own impairment, like a real link.
+    let inbound = Impairment::new(params.clone());
+    let outbound = Impairment::new(ImpairmentParams {
+      seed: params.seed.map(|seed| !seed),
+      ..params
+    });
+    self.routes.lock().unwrap().impairment = Some((Arc::new(inbound), Arc::new(outbound)));
+  }
+
+  #[cfg(feature = "impairment")]
+  fn impairment(&self) -> Option<(Arc<Impairment>, Arc<Impairment>)> {
+    self.routes.lock().unwrap().impairment.clone()
+  }
+
+  // Records a datagram of `peer` to the captures of the listener and of its
+  // session. `local` is the address the datagram was sent from or to.
+  fn record(&self, direction: Direction, local: SocketAddr, peer: SocketAddr, packet: &[u8]) {
+    let captures = {
+      let routes = self.routes.lock().unwrap();
+      let session = match packet.len() >= kcp::KCP_OVERHEAD {
+        true => routes
+          .capture_by_session
+          .get(&(peer, kcp::get_conv(packet)))
+          .cloned(),
+        false => None,
+      };
+      [routes.capture.clone(), session]
+    };
+
+    for capture in captures.into_iter().flatten() {
+      capture.record(direction, local, peer, packet);
+    }
+  }
+}
+
+/// Lets an accepted stream record its own datagrams, see `RelayRoutes`.
+/// Removes the capture when the stream is dropped.
+pub struct SessionRoute {
+  routes: RelayRoutes,
+  key: (SocketAddr, u32),
+}
+
+impl Drop for SessionRoute {
+  fn drop(&mut self) {
+    let mut routes = self.routes.routes.lock().unwrap();
+    routes.capture_by_session.remove(&self.key);
+  }
+}
+
+impl SessionRoute {
+  /// Starts recording the datagrams of the session to `capture`, or stops if
+  /// it's `None`. Returns the capture it replaces.
+  pub fn set_capture(&self, capture: Option<Arc<Capture>>) -> Option<Arc<Capture>> {
+    let mut routes = self.routes.routes.lock().unwrap();
+    match capture {
+      Some(capture) => routes.capture_by_session.insert(self.key, capture),
+      None => routes.capture_by_session.remove(&self.key),
+    }
+  }
+
+  /// The socket peers send to, shared with the other sessions.
+  pub fn udp(&self) -> Arc<UdpSocket> {
+    self.routes.front.clone()
+  }
+}
+
+/// A relay between the socket peers send to and a `KcpListener` bound to
+/// loopback, see `relay_listener`.
+pub struct ListenerRelay {
+  pub local_addr: SocketAddr,
+  pub peer_addrs: PeerAddrs,
+  pub routes: RelayRoutes,
+  pub task: JoinHandle<()>,
+}
+
+// Aborts the task relaying a peer's packets when the relay goes away.
+struct PeerTask(JoinHandle<()>);
+
+impl Drop for PeerTask {
+  fn drop(&mut self) {
+    self.0.abort();
+  }
+}
+
+/// Relays the datagrams between the listener socket `front` and a
+/// `KcpListener` listening on `inner`, a loopback address, so that they can
+/// be captured and impaired, see `RelayRoutes`.
+///
+/// Every peer address talks to `inner` from a loopback socket of its own, the
+/// listener tells the sessions of a peer apart by their conv. Peers quiet for
+/// `peer_idle` are forgotten.
+// Should be called within the tokio runtime.
+pub fn relay_listener(
+  front: UdpSocket,
+  inner: SocketAddr,
+  peer_idle: Duration,
+) -> Result<ListenerRelay> {
+  let local_addr = front.local_addr()?;
+  let front = Arc::new(front);
+  let routes = RelayRoutes {
+    routes: Default::default(),
+    front: front.clone(),
+  };
+  let peer_addrs: PeerAddrs = Default::default();
+
+  let task = {
+    let (front, routes, peer_addrs) = (front.clone(), routes.clone(), peer_addrs.clone());
+    tokio::spawn(async move {
+      // peer address -> loopback socket of the peer, the address the peer
+      // sends to and its task
+      let mut peers: HashMap<SocketAddr, (Arc<UdpSocket>, SocketAddr, PeerTask)> = HashMap::new();
+      let mut buf = [0u8; PACKET_BUF];
+
+      loop {
+        let (n, peer) = match front.recv_from(&mut buf).await {
+          Ok(ret) => ret,
+          Err(_) => continue,
+        };
+        let packet = &buf[..n];
+
+        peers.retain(|_, (_, _, task)| !task.0.is_finished());
+        let (socket, local) = match peers.get(&peer) {
+          Some((socket, local, _)) => (socket.clone(), *local),
+          None => {
+            let socket = match UdpSocket::bind("127.0.0.1:0").await {
+              Ok(socket) => Arc::new(socket),
+              Err(_) => continue,
+            };
+            let loopback = match socket.local_addr() {
+              Ok(addr) => addr,
+              Err(_) => continue,
+            };
+            peer_addrs.lock().unwrap().insert(loopback, peer);
+            let local = capture::local_addr_for(local_addr, peer);
+
+            let task = {
+              let (socket, front, routes) = (socket.clone(), front.clone(), routes.clone());
+              let peer_addrs = peer_addrs.clone();
+              tokio::spawn(async move {
+                let mut buf = [0u8; PACKET_BUF];
+                // The listener's answers go back out through `front`.
+                while let Ok(Ok(n)) = tokio::time::timeout(peer_idle, socket.recv(&mut buf)).await {
+                  let packet = &buf[..n];
+                  routes.record(Direction::Sent, local, peer, packet);
+                  #[cfg(feature = "impairment")]
+                  if let Some((_, outbound)) = routes.impairment() {
+                    outbound.forward(&front, packet, peer).await;
+                    continue;
+                  }
+                  let _ = front.send_to(packet, peer).await;
+                }
+                peer_addrs.lock().unwrap().remove(&loopback);
+              })
+            };
+            peers.insert(peer, (socket.clone(), local, PeerTask(task)));
+            (socket, local)
+          }
+        };
+
+        routes.record(Direction::Received, local, peer, packet);
+        #[cfg(feature = "impairment")]
+        if let Some((inbound, _)) = routes.impairment() {
+          inbound.forward(&socket, packet, inner).await;
+          continue;
+        }
+        let _ = socket.send_to(packet, inner).await;
+      }
+    })
+  };
+
+  Ok(ListenerRelay {
+    local_addr,
+    peer_addrs,
+    routes,
+    task,
+  })
+}
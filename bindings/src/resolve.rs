@@ -0,0 +1,160 @@
+use crate::error::SwiftKcpError;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Resolves host names for `new_stream`, e.g. to stub DNS out in tests.
+#[uniffi::export(callback_interface)]
+pub trait KcpResolver: Send + Sync {
+  /// Returns IP addresses of `host`, an empty result means resolving failed.
+  fn resolve(&self, host: String) -> Vec<String>;
+}
+
+#[derive(uniffi::Enum, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpPreference {
+  /// Alternate between families, starting with the first resolved address
+  #[default]
+  Any,
+  PreferIpv4,
+  PreferIpv6,
+  Ipv4Only,
+  Ipv6Only,
+}
+
+// Splits "host:port" or "[v6]:port".
+fn split_host_port(addr_str: &str) -> Option<(&str, u16)> {
+  let (host, port) = addr_str.rsplit_once(':')?;
+  let port = port.parse().ok()?;
+  let host = host
+    .strip_prefix('[')
+    .and_then(|h| h.strip_suffix(']'))
+    .unwrap_or(host);
+
+  if host.is_empty() {
+    return None;
+  }
+  Some((host, port))
+}
+
+fn resolve_failed(host: &str, msg: impl ToString) -> SwiftKcpError {
+  SwiftKcpError::ResolveFailed {
+    host: host.to_string(),
+    msg: msg.to_string(),
+  }
+}
+
+/// Orders addresses by `preference`. With `Any` families are interleaved so
+/// that a broken family doesn't delay every attempt, as in RFC 8305.
+pub fn sort_addrs(addrs: Vec<SocketAddr>, preference: IpPreference) -> Vec<SocketAddr> {
+  let v6_first = addrs.first().is_some_and(|a| a.is_ipv6());
+  let (v4, v6): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv4());
+
+  match preference {
+    IpPreference::Ipv4Only => v4,
+    IpPreference::Ipv6Only => v6,
+    IpPreference::PreferIpv4 => v4.into_iter().chain(v6).collect(),
+    IpPreference::PreferIpv6 => v6.into_iter().chain(v4).collect(),
+    IpPreference::Any => {
+      let (mut first, mut second) = match v6_first {
+        true => (v6.into_iter(), v4.into_iter()),
+        false => (v4.into_iter(), v6.into_iter()),
+      };
+      let mut sorted = Vec::new();
+      loop {
+        match (first.next(), second.next()) {
+          (None, None) => break,
+          (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+      }
+      sorted
+    }
+  }
+}
+
+/// Resolves `addr_str` to candidate addresses in the order they should be tried.
+// Should be called within the tokio runtime.
+pub async fn resolve(
+  addr_str: &str,
+  preference: IpPreference,
+  resolver: Option<Arc<dyn KcpResolver>>,
+) -> Result<Vec<SocketAddr>> {
+  let (host, addrs) = match SocketAddr::from_str(addr_str) {
+    Ok(addr) => (addr_str, vec![addr]),
+    Err(..) => lookup(addr_str, resolver).await?,
+  };
+
+  let addrs = sort_addrs(addrs, preference);
+  if addrs.is_empty() {
+    return Err(resolve_failed(host, "no usable address"));
+  }
+
+  Ok(addrs)
+}
+
+// Returns the host part of `addr_str` and its addresses.
+async fn lookup(
+  addr_str: &str,
+  resolver: Option<Arc<dyn KcpResolver>>,
+) -> Result<(&str, Vec<SocketAddr>)> {
+  let (host, port) =
+    split_host_port(addr_str).ok_or_else(|| resolve_failed(addr_str, "invalid address"))?;
+
+  let addrs: Vec<SocketAddr> = match resolver {
+    Some(resolver) => {
+      let owned_host = host.to_string();
+      let ips = tokio::task::spawn_blocking(move || resolver.resolve(owned_host)).await?;
+      ips
+        .iter()
+        .filter_map(|ip| IpAddr::from_str(ip).ok())
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect()
+    }
+    None => tokio::net::lookup_host((host, port))
+      .await
+      .map_err(|e| resolve_failed(host, e))?
+      .collect(),
+  };
+
+  Ok((host, addrs))
+}
+
+/// Whether the local host has a route to `addr`. UDP has no handshake, so this
+/// is the only thing we can check before picking an address.
+pub fn has_route(addr: &SocketAddr) -> bool {
+  let local = crate::socket::unspecified_addr(addr);
+  UdpSocket::bind(local)
+    .and_then(|udp| udp.connect(addr))
+    .is_ok()
+}
+
+#[test]
+fn test_resolve_order() {
+  let v4a: SocketAddr = "1.1.1.1:1".parse().unwrap();
+  let v4b: SocketAddr = "2.2.2.2:1".parse().unwrap();
+  let v6a: SocketAddr = "[::1]:1".parse().unwrap();
+  let v6b: SocketAddr = "[::2]:1".parse().unwrap();
+  let addrs = vec![v6a, v6b, v4a, v4b];
+
+  assert_eq!(
+    sort_addrs(addrs.clone(), IpPreference::Any),
+    vec![v6a, v4a, v6b, v4b]
+  );
+  assert_eq!(
+    sort_addrs(addrs.clone(), IpPreference::PreferIpv6),
+    vec![v6a, v6b, v4a, v4b]
+  );
+  assert_eq!(
+    sort_addrs(addrs.clone(), IpPreference::Ipv4Only),
+    vec![v4a, v4b]
+  );
+
+  assert_eq!(
+    split_host_port("game.example.com:3100"),
+    Some(("game.example.com", 3100))
+  );
+  assert_eq!(split_host_port("[::1]:80"), Some(("::1", 80)));
+  assert_eq!(split_host_port("game.example.com"), None);
+}
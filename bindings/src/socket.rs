@@ -0,0 +1,202 @@
+use crate::error::SwiftKcpError;
+use crate::kcp_util::KcpConfigParams;
+use crate::Result;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+/// UDP socket options applied before the socket is bound.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SocketOptions {
+  pub reuse_address: Option<bool>,
+  pub reuse_port: Option<bool>,
+  pub send_buffer_size: Option<u32>,
+  pub recv_buffer_size: Option<u32>,
+  pub ip_tos: Option<u8>,
+  pub ipv6_only: Option<bool>,
+}
+
+impl SocketOptions {
+  pub fn from_params(params: &KcpConfigParams) -> Self {
+    Self {
+      reuse_address: params.reuse_address,
+      reuse_port: params.reuse_port,
+      send_buffer_size: params.send_buffer_size,
+      recv_buffer_size: params.recv_buffer_size,
+      ip_tos: params.ip_tos,
+      ipv6_only: params.ipv6_only,
+    }
+  }
+
+  fn apply(&self, socket: &Socket, addr: &SocketAddr) -> io::Result<()> {
+    if let Some(reuse) = self.reuse_address {
+      socket.set_reuse_address(reuse)?;
+    }
+    if let Some(reuse) = self.reuse_port {
+      #[cfg(unix)]
+      socket.set_reuse_port(reuse)?;
+      #[cfg(not(unix))]
+      let _ = reuse;
+    }
+    if let (Some(only_v6), SocketAddr::V6(..)) = (self.ipv6_only, addr) {
+      socket.set_only_v6(only_v6)?;
+    }
+    self.apply_live(socket, addr)
+  }
+
+  // Options that can still change once the socket is bound.
+  fn apply_live(&self, socket: &Socket, addr: &SocketAddr) -> io::Result<()> {
+    if let Some(size) = self.send_buffer_size {
+      socket.set_send_buffer_size(size as usize)?;
+    }
+    if let Some(size) = self.recv_buffer_size {
+      socket.set_recv_buffer_size(size as usize)?;
+    }
+    if let Some(tos) = self.ip_tos {
+      match addr {
+        SocketAddr::V4(..) => socket.set_tos(tos as u32)?,
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos"))]
+        SocketAddr::V6(..) => socket.set_tclass_v6(tos as u32)?,
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+        SocketAddr::V6(..) => {}
+      }
+    }
+    Ok(())
+  }
+
+  /// Updates a bound socket. Options only used when binding must be unset or
+  /// equal to the current values of the socket.
+  pub fn update(&self, udp: &UdpSocket) -> Result<()> {
+    let socket = SockRef::from(udp);
+    let addr = udp.local_addr()?;
+    let not_updatable = |field: &str| SwiftKcpError::ConfigNotUpdatable {
+      field: field.to_string(),
+      reason: "only used when the socket is bound".to_string(),
+    };
+
+    if let Some(reuse) = self.reuse_address {
+      if socket.reuse_address()? != reuse {
+        return Err(not_updatable("reuse_address"));
+      }
+    }
+    #[cfg(unix)]
+    if let Some(reuse) = self.reuse_port {
+      if socket.reuse_port()? != reuse {
+        return Err(not_updatable("reuse_port"));
+      }
+    }
+    if let (Some(only_v6), SocketAddr::V6(..)) = (self.ipv6_only, addr) {
+      if socket.only_v6()? != only_v6 {
+        return Err(not_updatable("ipv6_only"));
+      }
+    }
+
+    self.apply_live(&socket, &addr)?;
+    Ok(())
+  }
+}
+
+/// Unspecified local address of the same family as `remote`.
+pub fn unspecified_addr(remote: &SocketAddr) -> SocketAddr {
+  match remote {
+    SocketAddr::V4(..) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+    SocketAddr::V6(..) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+  }
+}
+
+/// Where the UDP socket of a stream or listener comes from.
+pub enum LocalSocket {
+  Addr(SocketAddr),
+  Fd(i32),
+}
+
+impl LocalSocket {
+  // Should be called within the tokio runtime.
+  pub fn into_udp(self, options: &SocketOptions) -> io::Result<UdpSocket> {
+    match self {
+      LocalSocket::Addr(addr) => bind_udp(addr, options),
+      LocalSocket::Fd(fd) => adopt_udp(fd, options),
+    }
+  }
+}
+
+// Should be called within the tokio runtime.
+pub fn bind_udp(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+  let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+  options.apply(&socket, &addr)?;
+  socket.set_nonblocking(true)?;
+  socket.bind(&addr.into())?;
+
+  UdpSocket::from_std(socket.into())
+}
+
+// Takes ownership of `fd`, it's closed once the returned socket is dropped.
+// Should be called within the tokio runtime.
+#[cfg(unix)]
+pub fn adopt_udp(fd: i32, options: &SocketOptions) -> io::Result<UdpSocket> {
+  use std::mem::ManuallyDrop;
+  use std::os::unix::io::FromRawFd;
+
+  if fd < 0 {
+    return Err(io::Error::new(
+      io::ErrorKind::InvalidInput,
+      format!("invalid fd {}", fd),
+    ));
+  }
+
+  // SAFETY: the caller hands the fd over to us and doesn't use it anymore.
+  // Not dropped until it's checked and set up, we don't close a fd we refuse
+  // to adopt.
+  let socket = ManuallyDrop::new(unsafe { Socket::from_raw_fd(fd) });
+  let addr = match (socket.r#type(), socket.local_addr()) {
+    (Ok(ty), Ok(addr)) if ty == Type::DGRAM => addr.as_socket(),
+    _ => None,
+  };
+  let addr = addr.ok_or_else(|| {
+    io::Error::new(
+      io::ErrorKind::InvalidInput,
+      format!("fd {} is not a bound UDP socket", fd),
+    )
+  })?;
+  options.apply(&socket, &addr)?;
+  socket.set_nonblocking(true)?;
+
+  UdpSocket::from_std(ManuallyDrop::into_inner(socket).into())
+}
+
+#[cfg(not(unix))]
+pub fn adopt_udp(fd: i32, _options: &SocketOptions) -> io::Result<UdpSocket> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    format!("adopting fd {} is only supported on unix", fd),
+  ))
+}
+
+#[cfg(unix)]
+#[test]
+fn test_adopt_udp_keeps_refused_fd() {
+  use std::os::unix::io::IntoRawFd;
+
+  // IPV6_V6ONLY can't change once bound, so applying it fails.
+  let udp = match std::net::UdpSocket::bind("[::1]:0") {
+    Ok(udp) => udp,
+    Err(_) => return,
+  };
+  let fd = udp.into_raw_fd();
+  let options = SocketOptions {
+    ipv6_only: Some(true),
+    ..Default::default()
+  };
+
+  let rt = tokio::runtime::Builder::new_current_thread()
+    .enable_io()
+    .build()
+    .unwrap();
+  let _guard = rt.enter();
+  assert!(adopt_udp(fd, &options).is_err());
+
+  // Still open and still the caller's to close.
+  assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+  assert_eq!(unsafe { libc::close(fd) }, 0);
+}
@@ -0,0 +1,712 @@
+use crate::adaptive::{self, AdaptivePacing, AdaptiveStats, Adjustment};
+use crate::admission::AdmissionTicket;
+use crate::capture::Capture;
+use crate::compression::{self, CompressionStats, KcpCompression};
+use crate::endpoint::{ClientEndpoint, EndpointRoute};
+use crate::error::SwiftKcpError;
+use crate::events::{EventQueue, ListenerEvent, StreamEvent, StreamEvents};
+use crate::kcp_util::KcpConfigParams;
+use crate::pmtu::PathMtu;
+use crate::rate_limit::{RateLimitStats, RateLimiter, Throughput};
+use crate::relay::SessionRoute;
+use crate::socket::SocketOptions;
+use crate::Result;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::{
+  io::{self, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+  sync::Mutex,
+  task::JoinHandle,
+  time::{self, MissedTickBehavior},
+};
+use tokio_kcp::{KcpConfig, KcpSession, KcpStream, KcpTransport};
+
+const READ_BUF: usize = 65535;
+
+// A message can't be split into more than 128 fragments by kcp.
+const MAX_FRAGMENTS: usize = 128;
+
+// Rate limited writes in stream mode are sent in chunks of this size, so a
+// large write doesn't wait for its whole size before anything is sent.
+const RATE_LIMIT_CHUNK: usize = 16 * 1024;
+
+/// Options that are handled by the bindings rather than by tokio_kcp.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamOptions {
+  pub compression: KcpCompression,
+  // (bytes per second, burst)
+  pub rate_limit: Option<(u64, u64)>,
+  // (min, max) bytes per second
+  pub adaptive: Option<(u64, u64)>,
+  pub idle_timeout_milisec: Option<u32>,
+}
+
+impl StreamOptions {
+  pub fn from_params(params: &KcpConfigParams) -> Self {
+    let rate_limit = params
+      .rate_limit_bytes_per_sec
+      .filter(|rate| *rate > 0)
+      .map(|rate| (rate, params.rate_limit_burst.unwrap_or(0)));
+    let adaptive = params
+      .adaptive_max_bytes_per_sec
+      .filter(|max| params.adaptive_pacing == Some(true) && *max > 0)
+      .map(|max| {
+        let min = params.adaptive_min_bytes_per_sec.unwrap_or(max / 16);
+        (min.clamp(1, max), max)
+      });
+
+    Self {
+      compression: params.compression.unwrap_or_default(),
+      rate_limit,
+      adaptive,
+      idle_timeout_milisec: params.idle_timeout_milisec,
+    }
+  }
+}
+
+struct StreamReader {
+  stream: ReadHalf<KcpStream>,
+  // Decompressed bytes not yet returned by `read_exact`.
+  pending: Vec<u8>,
+}
+
+#[derive(Default)]
+struct CompressionCounters {
+  raw_bytes_written: AtomicU64,
+  compressed_bytes_written: AtomicU64,
+  raw_bytes_read: AtomicU64,
+  compressed_bytes_read: AtomicU64,
+}
+
+// Bytes are counted without compression too, both sizes are equal then.
+impl CompressionCounters {
+  fn written(&self, raw: usize, compressed: usize) {
+    self
+      .raw_bytes_written
+      .fetch_add(raw as u64, Ordering::Relaxed);
+    self
+      .compressed_bytes_written
+      .fetch_add(compressed as u64, Ordering::Relaxed);
+  }
+
+  fn read(&self, raw: usize, compressed: usize) {
+    self.raw_bytes_read.fetch_add(raw as u64, Ordering::Relaxed);
+    self
+      .compressed_bytes_read
+      .fetch_add(compressed as u64, Ordering::Relaxed);
+  }
+}
+
+#[derive(Default)]
+struct WriteShaping {
+  limiter: Arc<RateLimiter>,
+  // Shared by the streams accepted by the same listener.
+  shared_limiter: Option<Arc<RateLimiter>>,
+  // Drives `limiter` and the session's send window and fast resend when
+  // adaptive pacing is enabled.
+  adaptive: Option<Arc<AdaptivePacing>>,
+  // Samples the session for `adaptive` every period until the stream is
+  // dropped. Started by the first write, which runs within the tokio runtime.
+  sampler: StdMutex<Option<JoinHandle<()>>>,
+  throughput: StdMutex<Throughput>,
+  bytes_written: AtomicU64,
+  throttled_nanos: AtomicU64,
+}
+
+// Bursts of 100ms, larger bursts would just fill kcp's send queue.
+fn adaptive_burst(bytes_per_sec: u64) -> u64 {
+  (bytes_per_sec / 10).max(1)
+}
+
+impl WriteShaping {
+  // Returns how long the stream's own limit delayed the write.
+  async fn acquire(&self, n: usize) -> Duration {
+    let throttled = self.limiter.acquire(n).await;
+    let mut waited = throttled;
+    if let Some(shared_limiter) = &self.shared_limiter {
+      waited += shared_limiter.acquire(n).await;
+    }
+    self
+      .throttled_nanos
+      .fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+    throttled
+  }
+
+  // `stalled` is how long kcp took to take the bytes, it waits when its send
+  // queue is full.
+  fn record(&self, n: usize, throttled: Duration, stalled: Duration, session: &Arc<KcpSession>) {
+    self.throughput.lock().unwrap().record(n);
+    self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+
+    if let Some(adaptive) = &self.adaptive {
+      adaptive.record(throttled, stalled);
+      self.start_sampler(adaptive, session);
+    }
+  }
+
+  fn start_sampler(&self, adaptive: &Arc<AdaptivePacing>, session: &Arc<KcpSession>) {
+    let mut sampler = self.sampler.lock().unwrap();
+    if sampler.is_some() {
+      return;
+    }
+
+    let (adaptive, limiter, session) = (adaptive.clone(), self.limiter.clone(), session.clone());
+    *sampler = Some(tokio::spawn(async move {
+      let mut interval = time::interval(adaptive::SAMPLE_PERIOD);
+      interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+      // The first tick completes right away.
+      interval.tick().await;
+      loop {
+        interval.tick().await;
+        if let Some(adjustment) = adaptive.sample(session.stats()) {
+          adjust(&limiter, adjustment, &session);
+        }
+      }
+    }));
+  }
+
+  fn adjust(&self, adjustment: Adjustment, session: &KcpSession) {
+    adjust(&self.limiter, adjustment, session);
+  }
+}
+
+impl Drop for WriteShaping {
+  fn drop(&mut self) {
+    if let Some(sampler) = self.sampler.lock().unwrap().take() {
+      sampler.abort();
+    }
+  }
+}
+
+fn adjust(limiter: &RateLimiter, adjustment: Adjustment, session: &KcpSession) {
+  let rate = adjustment.bytes_per_sec;
+  let _ = limiter.set(rate, adaptive_burst(rate));
+  session.set_send_window(adjustment.send_window);
+  session.set_fast_resend(adjustment.fast_resend);
+}
+
+// Reads and writes lock different halves so that a pending read doesn't
+// block writes on the same stream.
+pub struct SwiftKcpStream {
+  reader: Mutex<StreamReader>,
+  writer: Mutex<WriteHalf<KcpStream>>,
+  options: StreamOptions,
+  // Kept to change the kcp parameters after the stream is split.
+  session: Arc<KcpSession>,
+  config: StdMutex<KcpConfig>,
+  // The socket kcp packets are sent from, shared with other sessions of the
+  // same listener. Not a UDP socket for streams of the in-memory transport.
+  udp: Option<Arc<dyn KcpTransport>>,
+  stream_mode: bool,
+  // Only grows, the peer may still send messages as large as the ones of the
+  // mtu the stream started with.
+  max_message_size: AtomicUsize,
+  counters: CompressionCounters,
+  shaping: WriteShaping,
+  // Set when the stream is connected through a shared client endpoint.
+  endpoint_route: Option<EndpointRoute>,
+  // Set when the stream is connected through a relay of its own, see
+  // `KcpConfigParams.capturable`. Dropped after `endpoint_route`.
+  private_endpoint: Option<ClientEndpoint>,
+  // Set when the stream is accepted by a relayed listener.
+  session_route: Option<SessionRoute>,
+  // Set when the stream is accepted by a listener with admission control.
+  admission_ticket: Option<AdmissionTicket>,
+  // Set when the path was probed before connecting.
+  discovered_mtu: Option<usize>,
+  events: StreamEvents,
+}
+
+fn max_message_size(mtu: usize) -> usize {
+  let mss = mtu.saturating_sub(kcp::KCP_OVERHEAD);
+  READ_BUF.max(mss * MAX_FRAGMENTS)
+}
+
+fn ratio(compressed: u64, raw: u64) -> f64 {
+  if raw == 0 {
+    return 1.0;
+  }
+  compressed as f64 / raw as f64
+}
+
+impl SwiftKcpStream {
+  pub fn new(stream: KcpStream, config: &KcpConfig, options: StreamOptions) -> Self {
+    let udp = stream.session().kcp_socket().lock().transport().clone();
+    let session = stream.shared_session();
+    let (reader, writer) = io::split(stream);
+
+    let mut shaping = WriteShaping::default();
+    // Can't fail, `from_params` drops zero rates.
+    if let Some((bytes_per_sec, burst)) = options.rate_limit {
+      let _ = shaping.limiter.set(bytes_per_sec, burst);
+    }
+    if let Some((min, max)) = options.adaptive {
+      let _ = shaping.limiter.set(max, adaptive_burst(max));
+      shaping.adaptive = Some(Arc::new(AdaptivePacing::new(min, max, config)));
+    }
+
+    Self {
+      reader: Mutex::new(StreamReader {
+        stream: reader,
+        pending: Vec::new(),
+      }),
+      writer: Mutex::new(writer),
+      options,
+      session,
+      config: StdMutex::new(*config),
+      udp: Some(udp),
+      stream_mode: config.stream,
+      max_message_size: AtomicUsize::new(max_message_size(config.mtu)),
+      counters: CompressionCounters::default(),
+      shaping,
+      endpoint_route: None,
+      private_endpoint: None,
+      session_route: None,
+      admission_ticket: None,
+      discovered_mtu: None,
+      events: StreamEvents::new(options.idle_timeout_milisec),
+    }
+  }
+
+  pub fn with_endpoint_route(mut self, route: EndpointRoute) -> Self {
+    self.endpoint_route = Some(route);
+    // Only a loopback socket talking to the endpoint.
+    self.udp = None;
+    self
+  }
+
+  // The stream keeps `endpoint` alive and still updates the options of its
+  // socket.
+  pub fn with_private_endpoint(mut self, endpoint: ClientEndpoint, route: EndpointRoute) -> Self {
+    self.udp = Some(endpoint.udp());
+    self.endpoint_route = Some(route);
+    self.private_endpoint = Some(endpoint);
+    self
+  }
+
+  // Accepted streams share the listener's socket, the relay's one rather than
+  // the loopback socket the listener is bound to.
+  pub fn with_session_route(mut self, route: SessionRoute) -> Self {
+    self.udp = Some(route.udp());
+    self.session_route = Some(route);
+    self
+  }
+
+  /// Whether the datagrams of the stream go through a relay that can capture
+  /// them.
+  pub fn capturable(&self) -> bool {
+    self.endpoint_route.is_some() || self.session_route.is_some()
+  }
+
+  /// Starts recording the datagrams of the stream to `capture`, or stops if
+  /// it's `None`. Returns the capture it replaces.
+  pub fn set_capture(&self, capture: Option<Arc<Capture>>) -> Option<Arc<Capture>> {
+    match (&self.endpoint_route, &self.session_route) {
+      (Some(route), _) => route.set_capture(capture),
+      (None, Some(route)) => route.set_capture(capture),
+      (None, None) => None,
+    }
+  }
+
+  /// Reports `StreamEvent::Connected`.
+  pub fn with_remote_addr(self, addr: SocketAddr) -> Self {
+    self.events.connected(addr);
+    self
+  }
+
+  pub fn with_listener_events(mut self, queue: Arc<EventQueue<ListenerEvent>>, conv: u32) -> Self {
+    self.events = self.events.with_listener(queue, conv);
+    self
+  }
+
+  pub fn with_admission_ticket(mut self, ticket: AdmissionTicket) -> Self {
+    self.admission_ticket = Some(ticket);
+    self
+  }
+
+  pub fn with_discovered_mtu(mut self, mtu: usize) -> Self {
+    self.discovered_mtu = Some(mtu);
+    self
+  }
+
+  pub fn with_shared_rate_limiter(mut self, limiter: Arc<RateLimiter>) -> Self {
+    self.shaping.shared_limiter = Some(limiter);
+    self
+  }
+
+  fn compression(&self) -> KcpCompression {
+    self.options.compression
+  }
+
+  pub async fn write(&self, data: &[u8]) -> Result<()> {
+    match self.write_data(data).await {
+      Ok(()) => {
+        self.events.activity(data.len());
+        Ok(())
+      }
+      Err(e) => {
+        self.events.failed(e.to_string());
+        Err(e)
+      }
+    }
+  }
+
+  async fn write_data(&self, data: &[u8]) -> Result<()> {
+    let mut writer = self.writer.lock().await;
+
+    if self.compression() == KcpCompression::None {
+      self.send(&mut writer, data).await?;
+      self.counters.written(data.len(), data.len());
+      return Ok(());
+    }
+
+    if !self.stream_mode && data.len() > compression::MAX_CHUNK_LEN {
+      return Err(SwiftKcpError::Compression {
+        msg: format!(
+          "message of {} bytes, compressed messages are at most {} bytes",
+          data.len(),
+          compression::MAX_CHUNK_LEN
+        ),
+      });
+    }
+
+    // Frames are bounded so that readers can reject oversized ones.
+    for chunk in data.chunks(compression::MAX_CHUNK_LEN) {
+      let mut payload = compression::compress(self.compression(), chunk);
+      if self.stream_mode {
+        payload = compression::frame(payload);
+      }
+      self.send(&mut writer, &payload).await?;
+
+      self.counters.written(chunk.len(), payload.len());
+    }
+
+    Ok(())
+  }
+
+  // Writes `payload` as is, waiting for the rate limits.
+  async fn send(&self, writer: &mut WriteHalf<KcpStream>, payload: &[u8]) -> Result<()> {
+    // Splitting a message would break its boundaries.
+    let chunk_size = match self.stream_mode {
+      true => RATE_LIMIT_CHUNK,
+      false => payload.len().max(1),
+    };
+
+    for chunk in payload.chunks(chunk_size) {
+      let throttled = self.shaping.acquire(chunk.len()).await;
+      let start = Instant::now();
+      writer.write_all(chunk).await?;
+      self
+        .shaping
+        .record(chunk.len(), throttled, start.elapsed(), &self.session);
+    }
+
+    Ok(())
+  }
+
+  pub async fn read(&self) -> Result<Vec<u8>> {
+    match self.read_data().await {
+      Ok(data) if data.is_empty() => {
+        self.events.end_of_session();
+        Ok(data)
+      }
+      Ok(data) => {
+        self.events.data_received(data.len());
+        Ok(data)
+      }
+      Err(e) => {
+        self.events.failed(e.to_string());
+        Err(e)
+      }
+    }
+  }
+
+  async fn read_data(&self) -> Result<Vec<u8>> {
+    let mut reader = self.reader.lock().await;
+
+    if !reader.pending.is_empty() {
+      return Ok(std::mem::take(&mut reader.pending));
+    }
+
+    if self.compression() == KcpCompression::None {
+      let mut buf: Vec<u8> = vec![0; READ_BUF];
+      let n = reader.stream.read(&mut buf).await?;
+      buf.truncate(n);
+      self.counters.read(n, n);
+      return Ok(buf);
+    }
+
+    self.read_chunk(&mut reader.stream).await
+  }
+
+  pub async fn read_exact(&self, len: usize) -> Result<Vec<u8>> {
+    match self.read_exact_data(len).await {
+      Ok(Some(data)) => {
+        self.events.data_received(data.len());
+        Ok(data)
+      }
+      Ok(None) => {
+        self.events.end_of_session();
+        Err(std::io::Error::from(ErrorKind::UnexpectedEof).into())
+      }
+      Err(e) => {
+        self.events.failed(e.to_string());
+        Err(e)
+      }
+    }
+  }
+
+  // Returns `None` if the session ends first.
+  async fn read_exact_data(&self, len: usize) -> Result<Option<Vec<u8>>> {
+    let mut reader = self.reader.lock().await;
+
+    if self.compression() == KcpCompression::None {
+      let mut data: Vec<u8> = vec![0; len];
+      match reader.stream.read_exact(&mut data).await {
+        Ok(_) => {
+          self.counters.read(len, len);
+          return Ok(Some(data));
+        }
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+      }
+    }
+
+    while reader.pending.len() < len {
+      let chunk = self.read_chunk(&mut reader.stream).await?;
+      if chunk.is_empty() {
+        return Ok(None);
+      }
+      reader.pending.extend_from_slice(&chunk);
+    }
+
+    let rest = reader.pending.split_off(len);
+    Ok(Some(std::mem::replace(&mut reader.pending, rest)))
+  }
+
+  // Reads one compressed message (message mode) or frame (stream mode) and
+  // returns it decompressed. An empty result means EOF.
+  async fn read_chunk(&self, stream: &mut ReadHalf<KcpStream>) -> Result<Vec<u8>> {
+    let payload = if self.stream_mode {
+      let mut header = [0u8; 4];
+      match stream.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+      }
+      let mut payload = vec![0; compression::frame_len(header)?];
+      stream.read_exact(&mut payload).await?;
+      // The header is counted with the payload below.
+      self.counters.read(0, header.len());
+      payload
+    } else {
+      let mut payload = vec![0; self.max_message_size.load(Ordering::Relaxed)];
+      let n = stream.read(&mut payload).await?;
+      if n == 0 {
+        return Ok(Vec::new());
+      }
+      payload.truncate(n);
+      payload
+    };
+
+    let data = compression::decompress(self.compression(), &payload)?;
+
+    self.counters.read(data.len(), payload.len());
+
+    Ok(data)
+  }
+
+  pub async fn flush(&self) -> Result<()> {
+    self.writer.lock().await.flush().await?;
+    Ok(())
+  }
+
+  pub async fn shutdown(&self) -> Result<()> {
+    self.writer.lock().await.shutdown().await?;
+    self.events.shutdown();
+    Ok(())
+  }
+
+  // Should be called within the tokio runtime.
+  pub async fn next_event(&self) -> StreamEvent {
+    self.events.next().await
+  }
+
+  pub fn compression_stats(&self) -> CompressionStats {
+    let raw_bytes_written = self.counters.raw_bytes_written.load(Ordering::Relaxed);
+    let compressed_bytes_written = self
+      .counters
+      .compressed_bytes_written
+      .load(Ordering::Relaxed);
+    let raw_bytes_read = self.counters.raw_bytes_read.load(Ordering::Relaxed);
+    let compressed_bytes_read = self.counters.compressed_bytes_read.load(Ordering::Relaxed);
+
+    CompressionStats {
+      compression: self.compression(),
+      raw_bytes_written,
+      compressed_bytes_written,
+      raw_bytes_read,
+      compressed_bytes_read,
+      write_ratio: ratio(compressed_bytes_written, raw_bytes_written),
+      read_ratio: ratio(compressed_bytes_read, raw_bytes_read),
+    }
+  }
+
+  /// Applies the fields of `params` that are set. Kcp parameters are changed
+  /// on the running session, except for `stream` and `session_expire_milisec`
+  /// which are fixed when it's created. Nothing is applied if a field can't be
+  /// changed.
+  pub fn update_config(&self, params: &KcpConfigParams) -> Result<()> {
+    let mut config = self.config.lock().unwrap();
+    let mut updated = *config;
+    params.apply_to(&mut updated);
+
+    if updated.session_expire != config.session_expire {
+      return Err(SwiftKcpError::ConfigNotUpdatable {
+        field: "session_expire_milisec".to_string(),
+        reason: "only set when the session is created".to_string(),
+      });
+    }
+    if params.stream.is_some_and(|v| v != config.stream) {
+      return Err(SwiftKcpError::ConfigNotUpdatable {
+        field: "stream".to_string(),
+        reason: "both sides must use the same mode".to_string(),
+      });
+    }
+    if params.compression.is_some_and(|v| v != self.compression()) {
+      return Err(SwiftKcpError::ConfigNotUpdatable {
+        field: "compression".to_string(),
+        reason: "both sides must use the same mode".to_string(),
+      });
+    }
+
+    let socket_options = SocketOptions::from_params(params);
+    let has_socket_options = params.reuse_address.is_some()
+      || params.reuse_port.is_some()
+      || params.send_buffer_size.is_some()
+      || params.recv_buffer_size.is_some()
+      || params.ip_tos.is_some()
+      || params.ipv6_only.is_some();
+    match self.udp.as_deref().map(|transport| transport.udp_socket()) {
+      Some(Some(udp)) => socket_options.update(udp)?,
+      Some(None) if has_socket_options => {
+        return Err(SwiftKcpError::ConfigNotUpdatable {
+          field: "socket options".to_string(),
+          reason: "the stream doesn't use a UDP socket".to_string(),
+        })
+      }
+      None if has_socket_options => {
+        return Err(SwiftKcpError::ConfigNotUpdatable {
+          field: "socket options".to_string(),
+          reason: "the stream uses the socket of an endpoint".to_string(),
+        })
+      }
+      _ => {}
+    }
+
+    let adaptive_changed = params
+      .adaptive_pacing
+      .is_some_and(|v| v != self.options.adaptive.is_some())
+      || params
+        .adaptive_min_bytes_per_sec
+        .is_some_and(|v| self.options.adaptive.map(|(min, _)| min) != Some(v))
+      || params
+        .adaptive_max_bytes_per_sec
+        .is_some_and(|v| self.options.adaptive.map(|(_, max)| max) != Some(v));
+    if adaptive_changed {
+      return Err(SwiftKcpError::ConfigNotUpdatable {
+        field: "adaptive_pacing".to_string(),
+        reason: "only set when the stream is created".to_string(),
+      });
+    }
+    if self.shaping.adaptive.is_some() {
+      let driven = [
+        (
+          "rate_limit_bytes_per_sec",
+          params.rate_limit_bytes_per_sec.is_some(),
+        ),
+        ("window_size_send", updated.wnd_size.0 != config.wnd_size.0),
+        (
+          "nodelay_resend",
+          updated.nodelay.resend != config.nodelay.resend,
+        ),
+      ];
+      if let Some((field, _)) = driven.iter().find(|(_, changed)| *changed) {
+        return Err(SwiftKcpError::ConfigNotUpdatable {
+          field: field.to_string(),
+          reason: "driven by adaptive pacing".to_string(),
+        });
+      }
+    }
+
+    if let Err(e) = self.session.set_config(&updated) {
+      return Err(SwiftKcpError::InvalidConfig {
+        field: "mtu".to_string(),
+        reason: e.to_string(),
+      });
+    }
+    *config = updated;
+    self
+      .max_message_size
+      .fetch_max(max_message_size(updated.mtu), Ordering::Relaxed);
+    // `set_config` reset what the controller tuned.
+    if let Some(adaptive) = &self.shaping.adaptive {
+      adaptive.set_mtu(updated.mtu);
+      self.shaping.adjust(adaptive.current(), &self.session);
+    }
+
+    if let Some(millisec) = params.idle_timeout_milisec {
+      self.events.set_idle_timeout(millisec);
+    }
+
+    match params.rate_limit_bytes_per_sec {
+      Some(0) => self.shaping.limiter.clear(),
+      Some(rate) => self
+        .shaping
+        .limiter
+        .set(rate, params.rate_limit_burst.unwrap_or(0))?,
+      None => {}
+    }
+
+    Ok(())
+  }
+
+  pub fn rate_limiter(&self) -> &RateLimiter {
+    &self.shaping.limiter
+  }
+
+  pub fn rate_limit_stats(&self) -> RateLimitStats {
+    let limit = self.shaping.limiter.limit();
+    let shared_limit = self
+      .shaping
+      .shared_limiter
+      .as_ref()
+      .and_then(|limiter| limiter.limit());
+
+    RateLimitStats {
+      bytes_per_sec: limit.map(|(bytes_per_sec, _)| bytes_per_sec),
+      burst: limit.map(|(_, burst)| burst),
+      listener_bytes_per_sec: shared_limit.map(|(bytes_per_sec, _)| bytes_per_sec),
+      throughput_bytes_per_sec: self.shaping.throughput.lock().unwrap().bytes_per_sec(),
+      bytes_written: self.shaping.bytes_written.load(Ordering::Relaxed),
+      throttled_millisec: self.shaping.throttled_nanos.load(Ordering::Relaxed) / 1_000_000,
+    }
+  }
+
+  pub fn adaptive_stats(&self) -> AdaptiveStats {
+    match &self.shaping.adaptive {
+      Some(adaptive) => adaptive.stats(),
+      None => AdaptiveStats::default(),
+    }
+  }
+
+  pub fn path_mtu(&self) -> PathMtu {
+    PathMtu {
+      mtu: self.config.lock().unwrap().mtu as u32,
+      discovered: self.discovered_mtu.map(|mtu| mtu as u32),
+    }
+  }
+}
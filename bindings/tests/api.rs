@@ -0,0 +1,799 @@
+// End-to-end tests of the exported API over loopback UDP.
+//
+// The bindings keep their runtime and managers in globals, so the tests take
+// turns through `LOCK`. Futures are driven by a plain executor on a thread of
+// their own, like the Swift side drives them, which also bounds how long a
+// deadlock can hang a test.
+
+use bindings::*;
+use std::future::Future;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn run<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) -> T {
+  let (tx, rx) = mpsc::channel();
+  std::thread::spawn(move || {
+    let _ = tx.send(futures::executor::block_on(future));
+  });
+  rx.recv_timeout(TIMEOUT).expect("timed out, deadlock?")
+}
+
+// Resolves after `duration` without blocking the executor, the test bodies
+// don't run on the bindings' runtime so tokio's timers aren't available.
+async fn sleep(duration: Duration) {
+  let (tx, rx) = futures::channel::oneshot::channel();
+  std::thread::spawn(move || {
+    std::thread::sleep(duration);
+    let _ = tx.send(());
+  });
+  let _ = rx.await;
+}
+
+// The output of `future`, or `None` if it takes longer than `duration`.
+async fn timeout<T>(duration: Duration, future: impl Future<Output = T>) -> Option<T> {
+  let future = std::pin::pin!(future);
+  let timer = std::pin::pin!(sleep(duration));
+  match futures::future::select(future, timer).await {
+    futures::future::Either::Left((output, _)) => Some(output),
+    futures::future::Either::Right(..) => None,
+  }
+}
+
+// Runs `test` with a fresh runtime and stops it afterwards.
+fn with_runtime<T: Send + 'static>(test: impl Future<Output = T> + Send + 'static) -> T {
+  let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+  run(init_runtime()).unwrap();
+  let ret = run(test);
+  run(deinit_runtime());
+  ret
+}
+
+fn params() -> KcpConfigParams {
+  let mut params = default_kcp_config_params();
+  params.nodelay = Some(true);
+  params
+}
+
+// A listener, a stream connected to it and the accepted stream.
+async fn connected_pair() -> (StreamId, StreamId, StreamId) {
+  let listener = new_listener("127.0.0.1:0".to_string(), params())
+    .await
+    .unwrap();
+  let addr = local_addr(listener).await.unwrap();
+  let client = new_stream(addr, params()).await.unwrap();
+
+  // The listener learns of a session from its first packet.
+  write_stream(client, b"hello".to_vec()).await.unwrap();
+  let accepted = accepet(listener).await.unwrap();
+  assert_eq!(read_stream(accepted.id).await.unwrap(), b"hello");
+
+  (listener, client, accepted.id)
+}
+
+#[test]
+fn test_round_trip() {
+  with_runtime(async {
+    let (listener, client, server) = connected_pair().await;
+
+    write_stream(server, b"world".to_vec()).await.unwrap();
+    assert_eq!(read_stream(client).await.unwrap(), b"world");
+
+    // read_exact_stream waits for the whole message across writes.
+    let message: Vec<u8> = (0..=255).cycle().take(10_000).collect();
+    for chunk in message.chunks(3000) {
+      write_stream(client, chunk.to_vec()).await.unwrap();
+    }
+    assert_eq!(read_exact_stream(server, 10_000).await.unwrap(), message);
+
+    // Without compression both sizes count the bytes as they are.
+    let stats = get_stream_compression_stats(client).await.unwrap();
+    assert_eq!(stats.raw_bytes_written, 10_005);
+    assert_eq!(stats.compressed_bytes_written, 10_005);
+    assert_eq!(stats.raw_bytes_read, 5);
+    assert_eq!(stats.write_ratio, 1.0);
+
+    flush_stream(client).await.unwrap();
+    shutdown_stream(client).await.unwrap();
+    remove_stream(client).await.unwrap();
+    remove_stream(server).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+// The memory transport pairs streams and listeners without UDP sockets.
+#[test]
+fn test_memory_transport() {
+  with_runtime(async {
+    let listener = new_memory_listener("10.0.0.1:4000".to_string(), params())
+      .await
+      .unwrap();
+    assert_eq!(local_addr(listener).await.unwrap(), "10.0.0.1:4000");
+    let client = new_memory_stream("10.0.0.1:4000".to_string(), params())
+      .await
+      .unwrap();
+
+    write_stream(client, b"hello".to_vec()).await.unwrap();
+    let accepted = accepet(listener).await.unwrap();
+    assert!(accepted.addr.starts_with("127.0.0.1:"));
+    assert_eq!(read_stream(accepted.id).await.unwrap(), b"hello");
+    let message: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+    write_stream(accepted.id, message.clone()).await.unwrap();
+    assert_eq!(
+      read_exact_stream(client, message.len() as u32)
+        .await
+        .unwrap(),
+      message
+    );
+
+    // Nothing else is bound there, and there are no socket options to set.
+    assert!(new_memory_listener("10.0.0.1:4000".to_string(), params())
+      .await
+      .is_err());
+    let mut update = params();
+    update.send_buffer_size = Some(1 << 20);
+    assert!(matches!(
+      update_stream_config(client, update).await,
+      Err(SwiftKcpError::ConfigNotUpdatable { .. })
+    ));
+    let mut capturable = params();
+    capturable.capturable = Some(true);
+    assert!(matches!(
+      new_memory_stream("10.0.0.1:4000".to_string(), capturable).await,
+      Err(SwiftKcpError::InvalidConfig { field, .. }) if field == "capturable"
+    ));
+
+    remove_stream(client).await.unwrap();
+    remove_stream(accepted.id).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+#[test]
+fn test_removed_ids() {
+  with_runtime(async {
+    let (listener, client, server) = connected_pair().await;
+    let count = get_stream_count().await;
+
+    remove_stream(client).await.unwrap();
+    remove_stream(server).await.unwrap();
+    remove_listener(listener).await.unwrap();
+    assert_eq!(get_stream_count().await, count - 2);
+
+    assert!(matches!(
+      remove_stream(client).await,
+      Err(SwiftKcpError::NoStreamForId { id }) if id == client
+    ));
+    assert!(matches!(
+      write_stream(server, b"gone".to_vec()).await,
+      Err(SwiftKcpError::NoStreamForId { .. })
+    ));
+    assert!(matches!(
+      remove_listener(listener).await,
+      Err(SwiftKcpError::NoListenerForId { .. })
+    ));
+  });
+}
+
+#[test]
+fn test_unknown_ids() {
+  with_runtime(async {
+    let id = StreamId::MAX;
+
+    assert!(matches!(
+      read_stream(id).await,
+      Err(SwiftKcpError::NoStreamForId { .. })
+    ));
+    assert!(matches!(
+      write_stream(id, vec![1]).await,
+      Err(SwiftKcpError::NoStreamForId { .. })
+    ));
+    assert!(matches!(
+      read_exact_stream(id, 1).await,
+      Err(SwiftKcpError::NoStreamForId { .. })
+    ));
+    assert!(matches!(
+      shutdown_stream(id).await,
+      Err(SwiftKcpError::NoStreamForId { .. })
+    ));
+    assert!(matches!(
+      remove_stream(id).await,
+      Err(SwiftKcpError::NoStreamForId { .. })
+    ));
+    assert!(matches!(
+      accepet(id).await,
+      Err(SwiftKcpError::NoListenerForId { .. })
+    ));
+    assert!(matches!(
+      remove_listener(id).await,
+      Err(SwiftKcpError::NoListenerForId { .. })
+    ));
+  });
+}
+
+#[test]
+fn test_runtime_not_inited() {
+  let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+  run(deinit_runtime());
+
+  run(async {
+    assert!(matches!(
+      new_listener("127.0.0.1:0".to_string(), params()).await,
+      Err(SwiftKcpError::RuntimeNotInited)
+    ));
+    assert!(matches!(
+      new_stream("127.0.0.1:3100".to_string(), params()).await,
+      Err(SwiftKcpError::RuntimeNotInited)
+    ));
+    assert!(matches!(
+      read_stream(0).await,
+      Err(SwiftKcpError::RuntimeNotInited)
+    ));
+    assert!(matches!(
+      write_stream(0, vec![1]).await,
+      Err(SwiftKcpError::RuntimeNotInited)
+    ));
+    assert!(matches!(
+      accepet(0).await,
+      Err(SwiftKcpError::RuntimeNotInited)
+    ));
+  });
+
+  // Stopping a stopped runtime is fine.
+  run(deinit_runtime());
+}
+
+#[test]
+fn test_double_init() {
+  with_runtime(async {
+    // A second init replaces the runtime.
+    init_runtime().await.unwrap();
+
+    let (listener, client, server) = connected_pair().await;
+    remove_stream(client).await.unwrap();
+    remove_stream(server).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+// A pending read must not block other calls, 0.3.0 deadlocked here.
+#[test]
+fn test_pending_read_blocks_nothing() {
+  with_runtime(async {
+    let (listener, client, server) = connected_pair().await;
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+      let _ = tx.send(futures::executor::block_on(read_stream(server)));
+    });
+    sleep(Duration::from_millis(100)).await;
+
+    // Manager lookups, writes and accepts go on while the read waits.
+    get_stream_count().await;
+    let other = new_stream(local_addr(listener).await.unwrap(), params())
+      .await
+      .unwrap();
+    write_stream(other, b"other".to_vec()).await.unwrap();
+    let accepted = accepet(listener).await.unwrap();
+    assert_eq!(read_stream(accepted.id).await.unwrap(), b"other");
+
+    write_stream(client, b"late".to_vec()).await.unwrap();
+    let data = rx.recv_timeout(TIMEOUT).unwrap().unwrap();
+    assert_eq!(data, b"late");
+
+    for id in [client, server, other, accepted.id] {
+      remove_stream(id).await.unwrap();
+    }
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+// Sessions sharing an endpoint socket are accepted and answered on their own.
+#[test]
+fn test_endpoint_sessions() {
+  with_runtime(async {
+    let listener = new_listener("127.0.0.1:0".to_string(), params())
+      .await
+      .unwrap();
+    let addr = local_addr(listener).await.unwrap();
+    let endpoint = new_endpoint("127.0.0.1:0".to_string()).await.unwrap();
+
+    let first = endpoint_connect(endpoint, addr.clone(), params())
+      .await
+      .unwrap();
+    write_stream(first, b"first".to_vec()).await.unwrap();
+    let first_accepted = accepet(listener).await.unwrap();
+    let second = endpoint_connect(endpoint, addr, params()).await.unwrap();
+    write_stream(second, b"second".to_vec()).await.unwrap();
+    let second_accepted = accepet(listener).await.unwrap();
+
+    // Both come from the endpoint's socket.
+    assert_eq!(first_accepted.addr, second_accepted.addr);
+    assert_eq!(read_stream(first_accepted.id).await.unwrap(), b"first");
+    assert_eq!(read_stream(second_accepted.id).await.unwrap(), b"second");
+    write_stream(second_accepted.id, b"to second".to_vec())
+      .await
+      .unwrap();
+    write_stream(first_accepted.id, b"to first".to_vec())
+      .await
+      .unwrap();
+    assert_eq!(read_stream(first).await.unwrap(), b"to first");
+    assert_eq!(read_stream(second).await.unwrap(), b"to second");
+    write_stream(first, b"still first".to_vec()).await.unwrap();
+    assert_eq!(
+      read_stream(first_accepted.id).await.unwrap(),
+      b"still first"
+    );
+
+    for id in [first, second, first_accepted.id, second_accepted.id] {
+      remove_stream(id).await.unwrap();
+    }
+    remove_endpoint(endpoint).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+// A rejected session is counted once, its retransmissions are dropped.
+#[test]
+fn test_rejected_session() {
+  with_runtime(async {
+    let listener = new_listener("127.0.0.1:0".to_string(), params())
+      .await
+      .unwrap();
+    set_listener_admission(
+      listener,
+      AdmissionParams {
+        deny: vec!["127.0.0.1".to_string()],
+        ..Default::default()
+      },
+    )
+    .await
+    .unwrap();
+    let client = new_stream(local_addr(listener).await.unwrap(), params())
+      .await
+      .unwrap();
+    write_stream(client, b"hello".to_vec()).await.unwrap();
+
+    assert!(matches!(
+      next_listener_event(listener).await.unwrap(),
+      ListenerEvent::SessionRejected { .. }
+    ));
+    // Unacknowledged, the segment is sent again every few hundred ms without
+    // another event.
+    let next = timeout(Duration::from_secs(2), next_listener_event(listener)).await;
+    assert!(next.is_none(), "{:?}", next);
+    assert_eq!(get_listener_rejected_count(listener).await.unwrap(), 1);
+
+    remove_stream(client).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+struct StaticResolver(Vec<String>);
+
+impl KcpResolver for StaticResolver {
+  fn resolve(&self, _host: String) -> Vec<String> {
+    self.0.clone()
+  }
+}
+
+// Nothing listens on the first address, the second answers and wins the race
+// without a session being accepted for the probes.
+#[test]
+fn test_happy_eyeballs() {
+  with_runtime(async {
+    let listener = new_listener("127.0.0.1:0".to_string(), params())
+      .await
+      .unwrap();
+    let port = local_addr(listener)
+      .await
+      .unwrap()
+      .rsplit_once(':')
+      .unwrap()
+      .1
+      .to_string();
+    set_resolver(Box::new(StaticResolver(vec![
+      "127.0.0.2".to_string(),
+      "127.0.0.1".to_string(),
+    ])))
+    .await;
+
+    let client = new_stream(format!("game.test:{}", port), params())
+      .await
+      .unwrap();
+    clear_resolver().await;
+    assert!(matches!(
+      next_event(client).await.unwrap(),
+      StreamEvent::Connected { addr } if addr.starts_with("127.0.0.1:")
+    ));
+    // The probes of the race are answered without creating a session.
+    let next = timeout(Duration::from_millis(200), next_listener_event(listener)).await;
+    assert!(next.is_none(), "{:?}", next);
+    write_stream(client, b"hello".to_vec()).await.unwrap();
+    let accepted = accepet(listener).await.unwrap();
+    assert_eq!(read_stream(accepted.id).await.unwrap(), b"hello");
+
+    remove_stream(client).await.unwrap();
+    remove_stream(accepted.id).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+// An unspecified IPv6 local socket reaches IPv4 peers unless it's IPv6 only.
+#[test]
+fn test_dual_stack_local() {
+  with_runtime(async {
+    let listener = new_listener("127.0.0.1:0".to_string(), params())
+      .await
+      .unwrap();
+    let addr = local_addr(listener).await.unwrap();
+
+    let client = new_stream_with_local(addr.clone(), "[::]:0".to_string(), params())
+      .await
+      .unwrap();
+    write_stream(client, b"hello".to_vec()).await.unwrap();
+    let accepted = accepet(listener).await.unwrap();
+    assert_eq!(read_stream(accepted.id).await.unwrap(), b"hello");
+
+    let mut v6_only = params();
+    v6_only.ipv6_only = Some(true);
+    assert!(matches!(
+      new_stream_with_local(addr, "[::]:0".to_string(), v6_only).await,
+      Err(SwiftKcpError::ResolveFailed { .. })
+    ));
+
+    remove_stream(client).await.unwrap();
+    remove_stream(accepted.id).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+// Path MTU discovery probes the connected session, loopback takes any size.
+#[test]
+fn test_path_mtu_discovery() {
+  with_runtime(async {
+    let listener = new_listener("127.0.0.1:0".to_string(), params())
+      .await
+      .unwrap();
+    let addr = local_addr(listener).await.unwrap();
+    let mut client_params = params();
+    client_params.mtu = Some(1400);
+    client_params.path_mtu_discovery = Some(true);
+    let client = new_stream(addr, client_params).await.unwrap();
+
+    let path_mtu = get_stream_path_mtu(client).await.unwrap();
+    assert_eq!(path_mtu.mtu, 1400);
+    #[cfg(target_os = "linux")]
+    assert_eq!(path_mtu.discovered, Some(1400));
+
+    // The probes opened the session, data goes through as usual.
+    write_stream(client, b"hello".to_vec()).await.unwrap();
+    let accepted = accepet(listener).await.unwrap();
+    assert_eq!(read_stream(accepted.id).await.unwrap(), b"hello");
+
+    remove_stream(client).await.unwrap();
+    remove_stream(accepted.id).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+// Kcp parameters change on the running session, fields fixed at creation are
+// refused.
+#[test]
+fn test_update_stream_config() {
+  with_runtime(async {
+    let (listener, client, server) = connected_pair().await;
+
+    let mut update = params();
+    update.mtu = Some(1200);
+    update.nodelay_interval = Some(20);
+    update.window_size_send = Some(512);
+    update.window_size_recv = Some(512);
+    update.flush_write = Some(true);
+    update_stream_config(client, update).await.unwrap();
+    assert_eq!(get_stream_path_mtu(client).await.unwrap().mtu, 1200);
+
+    let message: Vec<u8> = (0..=255).cycle().take(100_000).collect();
+    write_stream(client, message.clone()).await.unwrap();
+    assert_eq!(
+      read_exact_stream(server, message.len() as u32)
+        .await
+        .unwrap(),
+      message
+    );
+
+    let mut update = params();
+    update.session_expire_milisec = Some(1000);
+    assert!(matches!(
+      update_stream_config(client, update).await,
+      Err(SwiftKcpError::ConfigNotUpdatable { field, .. }) if field == "session_expire_milisec"
+    ));
+    let mut update = params();
+    update.stream = Some(true);
+    update.mtu = Some(600);
+    assert!(matches!(
+      update_stream_config(client, update).await,
+      Err(SwiftKcpError::ConfigNotUpdatable { field, .. }) if field == "stream"
+    ));
+    assert_eq!(get_stream_path_mtu(client).await.unwrap().mtu, 1200);
+
+    remove_stream(client).await.unwrap();
+    remove_stream(server).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+// A round trip on a stream records an RTT sample and exposes the retransmit
+// counter in the snapshot.
+#[test]
+fn test_metrics_rtt() {
+  with_runtime(async {
+    let (listener, client, server) = connected_pair().await;
+    write_stream(server, b"world".to_vec()).await.unwrap();
+    assert_eq!(read_stream(client).await.unwrap(), b"world");
+
+    // The client got an ACK of its push, the one of the server's may still be
+    // in flight.
+    let metrics = metrics_snapshot().await;
+    let count = metrics
+      .lines()
+      .find_map(|line| line.strip_prefix("kcp_rtt_seconds_count "))
+      .unwrap();
+    assert!(count.parse::<u64>().unwrap() >= 1, "{}", metrics);
+    retransmits(&metrics);
+
+    remove_stream(client).await.unwrap();
+    remove_stream(server).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+fn retransmits(metrics: &str) -> u64 {
+  metrics
+    .lines()
+    .find_map(|line| line.strip_prefix("kcp_retransmits_total "))
+    .unwrap()
+    .parse()
+    .unwrap()
+}
+
+// Run with `--features impairment`.
+#[cfg(feature = "impairment")]
+#[test]
+fn test_impairment_loss_latency() {
+  with_runtime(async {
+    let listener = new_listener("127.0.0.1:0".to_string(), params())
+      .await
+      .unwrap();
+    let addr = local_addr(listener).await.unwrap();
+    let mut impaired = params();
+    impaired.impairment = Some(ImpairmentParams {
+      loss_percent: 20.0,
+      latency_millisec: 50,
+      seed: Some(7),
+      ..Default::default()
+    });
+    let client = new_stream(addr, impaired).await.unwrap();
+    let retransmits_before = retransmits(&metrics_snapshot().await);
+
+    // Both directions are delayed.
+    let start = std::time::Instant::now();
+    write_stream(client, b"hello".to_vec()).await.unwrap();
+    let server = accepet(listener).await.unwrap().id;
+    assert_eq!(read_stream(server).await.unwrap(), b"hello");
+    write_stream(server, b"world".to_vec()).await.unwrap();
+    assert_eq!(read_stream(client).await.unwrap(), b"world");
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    // Lost datagrams are sent again, the data arrives whole and in order.
+    let message: Vec<u8> = (0..=255).cycle().take(50_000).collect();
+    for chunk in message.chunks(1000) {
+      write_stream(client, chunk.to_vec()).await.unwrap();
+    }
+    assert_eq!(read_exact_stream(server, 50_000).await.unwrap(), message);
+    assert!(retransmits(&metrics_snapshot().await) > retransmits_before);
+
+    remove_stream(client).await.unwrap();
+    remove_stream(server).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+#[test]
+fn test_listener_capture() {
+  with_runtime(async {
+    let dir = std::env::temp_dir().join(format!("kcp-capture-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+    let mut capturable = params();
+    capturable.capturable = Some(true);
+
+    let listener = new_listener("0.0.0.0:0".to_string(), capturable)
+      .await
+      .unwrap();
+    let port = local_addr(listener)
+      .await
+      .unwrap()
+      .parse::<std::net::SocketAddr>()
+      .unwrap()
+      .port();
+    start_listener_capture(listener, path("listener.pcap"))
+      .await
+      .unwrap();
+    let client = new_stream(format!("127.0.0.1:{}", port), params())
+      .await
+      .unwrap();
+    write_stream(client, b"hello".to_vec()).await.unwrap();
+    let server = accepet(listener).await.unwrap().id;
+    assert_eq!(read_stream(server).await.unwrap(), b"hello");
+
+    start_capture(server, path("server.pcap")).await.unwrap();
+    write_stream(server, b"world".to_vec()).await.unwrap();
+    assert_eq!(read_stream(client).await.unwrap(), b"world");
+    stop_capture(server).await.unwrap();
+    stop_listener_capture(listener).await.unwrap();
+    assert!(matches!(
+      start_capture(client, path("client.pcap")).await,
+      Err(SwiftKcpError::CaptureUnavailable { .. })
+    ));
+
+    // The listener is bound to 0.0.0.0, its datagrams are recorded with the
+    // address the peer sent them to.
+    for name in ["listener.pcap", "server.pcap"] {
+      let pcap = std::fs::read(path(name)).unwrap();
+      // Global header, record header, then the IPv4 header.
+      let ip = &pcap[24 + 16..];
+      assert_eq!(ip[12..16], [127, 0, 0, 1], "{}", name);
+      assert_eq!(ip[16..20], [127, 0, 0, 1], "{}", name);
+    }
+
+    remove_stream(client).await.unwrap();
+    remove_stream(server).await.unwrap();
+    remove_listener(listener).await.unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+  });
+}
+
+// Sub-streams opened by both sides of one session carry their own data, and
+// writes larger than the window wait for the reader.
+#[test]
+fn test_mux_sub_streams() {
+  with_runtime(async {
+    let (listener, client, server) = connected_pair().await;
+    let mut mux_params = default_mux_config_params();
+    mux_params.stream_window = Some(64 * 1024);
+    let client_mux = new_mux_session(client, true, mux_params).await.unwrap();
+    let mut mux_params = default_mux_config_params();
+    mux_params.stream_window = Some(64 * 1024);
+    let server_mux = new_mux_session(server, false, mux_params).await.unwrap();
+
+    let first = mux_open(client_mux).await.unwrap();
+    let second = mux_open(client_mux).await.unwrap();
+    mux_write(client_mux, first, b"first".to_vec())
+      .await
+      .unwrap();
+    mux_write(client_mux, second, b"second".to_vec())
+      .await
+      .unwrap();
+    assert_eq!(mux_accept(server_mux).await.unwrap(), first);
+    assert_eq!(mux_accept(server_mux).await.unwrap(), second);
+    assert_eq!(mux_read(server_mux, second).await.unwrap(), b"second");
+    assert_eq!(mux_read(server_mux, first).await.unwrap(), b"first");
+
+    let third = mux_open(server_mux).await.unwrap();
+    assert_eq!(third % 2, 0);
+    let message: Vec<u8> = (0..=255).cycle().take(200 * 1024).collect();
+    let write = mux_write(server_mux, third, message.clone());
+    let read = async {
+      assert_eq!(mux_accept(client_mux).await.unwrap(), third);
+      let mut received = Vec::new();
+      while received.len() < message.len() {
+        received.extend(mux_read(client_mux, third).await.unwrap());
+      }
+      received
+    };
+    let (written, received) = futures::join!(write, read);
+    written.unwrap();
+    assert_eq!(received, message);
+
+    // The other sub-streams still work, and close on their own.
+    mux_write(server_mux, first, b"reply".to_vec())
+      .await
+      .unwrap();
+    assert_eq!(mux_read(client_mux, first).await.unwrap(), b"reply");
+    mux_close_stream(client_mux, first).await.unwrap();
+    assert!(mux_read(server_mux, first).await.unwrap().is_empty());
+    mux_close_stream(server_mux, first).await.unwrap();
+    assert_eq!(get_mux_stream_count(server_mux).await.unwrap(), 2);
+    // Closed on both sides, the sub-stream is gone but its id is known.
+    assert!(matches!(
+      mux_read(server_mux, first).await,
+      Err(SwiftKcpError::SubStreamClosed { .. })
+    ));
+    mux_close_stream(server_mux, first).await.unwrap();
+
+    remove_mux_session(client_mux).await.unwrap();
+    remove_mux_session(server_mux).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
+
+// cmd | sid | len | payload, like the mux sessions send them.
+fn mux_frame(cmd: u8, sid: u32, payload: &[u8]) -> Vec<u8> {
+  let mut frame = vec![cmd];
+  frame.extend_from_slice(&sid.to_be_bytes());
+  frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+  frame.extend_from_slice(payload);
+  frame
+}
+
+#[test]
+fn test_mux_protocol_violations() {
+  const SYN: u8 = 0;
+  const PSH: u8 = 2;
+  const UPD: u8 = 3;
+  const RST: u8 = 4;
+
+  with_runtime(async {
+    // A peer sending past the window gets its sub-stream reset.
+    let (listener, client, server) = connected_pair().await;
+    let mut mux_params = default_mux_config_params();
+    mux_params.stream_window = Some(1024);
+    let server_mux = new_mux_session(server, false, mux_params).await.unwrap();
+
+    write_stream(client, mux_frame(SYN, 1, &[])).await.unwrap();
+    assert_eq!(mux_accept(server_mux).await.unwrap(), 1);
+    write_stream(client, mux_frame(PSH, 1, &[0; 1000]))
+      .await
+      .unwrap();
+    // Overruns the window even if the first frame was read already.
+    write_stream(client, mux_frame(PSH, 1, &[0; 1025]))
+      .await
+      .unwrap();
+    let mut ret = mux_read(server_mux, 1).await;
+    if ret.is_ok() {
+      ret = mux_read(server_mux, 1).await;
+    }
+    assert!(matches!(ret, Err(SwiftKcpError::Protocol { .. })));
+    // The reset is reported once.
+    assert!(matches!(
+      mux_read(server_mux, 1).await,
+      Err(SwiftKcpError::SubStreamClosed { .. })
+    ));
+    // The peer learns of it from a RST, after the window updates.
+    let rst = mux_frame(RST, 1, &[]);
+    let mut frames = Vec::new();
+    while !frames.ends_with(&rst) {
+      frames.extend(read_stream(client).await.unwrap());
+    }
+    assert_eq!(frames[0], UPD);
+
+    // A RST fails a write waiting for the peer's window.
+    let sid = mux_open(server_mux).await.unwrap();
+    let write = mux_write(server_mux, sid, b"hello".to_vec());
+    let reset = async {
+      // The SYN, the write waits for a window that never comes.
+      while read_stream(client).await.unwrap().len() < mux_frame(SYN, sid, &[0; 4]).len() {}
+      write_stream(client, mux_frame(RST, sid, &[]))
+        .await
+        .unwrap();
+    };
+    let (written, _) = futures::join!(write, reset);
+    assert!(matches!(written, Err(SwiftKcpError::Protocol { .. })));
+
+    // Opening an id of the other side closes the session.
+    write_stream(client, mux_frame(SYN, 4, &[])).await.unwrap();
+    assert!(matches!(
+      mux_accept(server_mux).await,
+      Err(SwiftKcpError::MuxClosed)
+    ));
+
+    remove_stream(client).await.unwrap();
+    remove_mux_session(server_mux).await.unwrap();
+    remove_listener(listener).await.unwrap();
+  });
+}
@@ -35,12 +35,11 @@ fn run(cmd_str: &str, cwd: Option<PathBuf>) -> Result<()> {
   let parts: Vec<_> = cmd_str.split(" ").collect();
 
   let mut cmd = process::Command::new(parts[0]);
-  if cwd.is_some() {
-    cmd.current_dir(cwd.unwrap());
+  if let Some(cwd) = cwd {
+    cmd.current_dir(cwd);
   }
 
-  for i in 1..parts.len() {
-    let part = parts[i];
+  for part in &parts[1..] {
     cmd.arg(part);
   }
 
@@ -70,8 +69,7 @@ impl Builder {
   }
 
   fn bindings_path(&self) -> PathBuf {
-    let bindings_path = self.workspace_path.join("./bindings");
-    bindings_path
+    self.workspace_path.join("./bindings")
   }
 
   fn output_folder_name(&self) -> &str {
@@ -86,17 +84,13 @@ impl Builder {
   }
 
   fn target_path(&self) -> PathBuf {
-    let output_path = self.workspace_path.join("./target");
-    output_path
+    self.workspace_path.join("./target")
   }
 
   // steps
   fn remove_output(&self) -> Result<()> {
     run(
-      &format!(
-        "rm -rf {}",
-        self.output_path().to_string_lossy().to_string()
-      ),
+      &format!("rm -rf {}", self.output_path().to_string_lossy()),
       None,
     )?;
 
@@ -131,10 +125,10 @@ impl Builder {
       true => "cargo build --release",
       false => "cargo build",
     };
-    run(&build_cmd, Some(self.bindings_path()))?;
+    run(build_cmd, Some(self.bindings_path()))?;
 
     // cargo run --release -p uniffi-bindgen generate --language swift --lib-file $(TARGET_DIR)/release/libbindings.dylib src/bindings.udl
-    let cmd_str = format!("cargo run --release -p uniffi-bindgen generate --language swift --lib-file {}/{}/libbindings.dylib src/bindings.udl", self.target_path().to_string_lossy().to_string(), self.build_directory());
+    let cmd_str = format!("cargo run --release -p uniffi-bindgen generate --language swift --lib-file {}/{}/libbindings.dylib src/bindings.udl", self.target_path().to_string_lossy(), self.build_directory());
     run(&cmd_str, Some(self.bindings_path()))?;
 
     // sed -i '' 's/module\ BindingsFFI/framework\ module\ BindingsFFI/' src/BindingsFFI.modulemap
@@ -195,7 +189,7 @@ impl Builder {
       run(
         &format!(
           "cp {}/{} ./BindingsFFI",
-          this_target_p.to_string_lossy().to_string(),
+          this_target_p.to_string_lossy(),
           STATIC_LIB_NAME,
         ),
         Some(this_target_framework_p.clone()),
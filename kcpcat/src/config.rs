@@ -0,0 +1,176 @@
+use anyhow::{Context, Result};
+#[cfg(feature = "impairment")]
+use bindings::ImpairmentParams;
+use bindings::{IpPreference, KcpCompression, KcpConfigParams};
+use clap::Args;
+use serde::de::DeserializeOwned;
+use std::path::PathBuf;
+
+// Parses the snake_case names the JSON and TOML configs use, e.g. "lz4".
+fn parse_enum<T: DeserializeOwned>(s: &str) -> std::result::Result<T, String> {
+  serde_json::from_value(serde_json::Value::String(s.to_string())).map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "impairment")]
+fn parse_impairment(s: &str) -> std::result::Result<ImpairmentParams, String> {
+  serde_json::from_str(s).map_err(|e| e.to_string())
+}
+
+/// Kcp config of a command. The preset is applied first, then the config
+/// file, then the flags of single fields.
+#[derive(Args, Debug)]
+#[command(next_help_heading = "Kcp config")]
+pub struct ConfigArgs {
+  /// Start from a preset: default, normal, fast, fast2, fast3, turbo or
+  /// low_bandwidth
+  #[arg(long)]
+  preset: Option<String>,
+  /// Read a config file, TOML if it ends with .toml and JSON otherwise
+  #[arg(long, value_name = "PATH")]
+  config: Option<PathBuf>,
+
+  /// Max Transmission Unit
+  #[arg(long)]
+  mtu: Option<i16>,
+  /// Enable nodelay
+  #[arg(long)]
+  nodelay: Option<bool>,
+  /// Internal update interval (ms)
+  #[arg(long)]
+  nodelay_interval: Option<i32>,
+  /// ACK number to enable fast resend
+  #[arg(long)]
+  nodelay_resend: Option<i32>,
+  /// Disable congetion control
+  #[arg(long)]
+  nodelay_nc: Option<bool>,
+  /// Send window size
+  #[arg(long)]
+  window_size_send: Option<u16>,
+  /// Recv window size, at least 128
+  #[arg(long)]
+  window_size_recv: Option<u16>,
+  /// Session expire duration
+  #[arg(long)]
+  session_expire_milisec: Option<u32>,
+  /// Flush KCP state immediately after write
+  #[arg(long)]
+  flush_write: Option<bool>,
+  /// Flush ACKs immediately after input
+  #[arg(long)]
+  flush_acks_input: Option<bool>,
+  /// Stream mode
+  #[arg(long)]
+  stream: Option<bool>,
+  /// Payload compression, none or lz4. Both sides should use the same mode
+  #[arg(long, value_parser = parse_enum::<KcpCompression>)]
+  compression: Option<KcpCompression>,
+  /// Set SO_REUSEADDR on the UDP socket
+  #[arg(long)]
+  reuse_address: Option<bool>,
+  /// Set SO_REUSEPORT on the UDP socket
+  #[arg(long)]
+  reuse_port: Option<bool>,
+  /// UDP socket send buffer size (SO_SNDBUF)
+  #[arg(long)]
+  send_buffer_size: Option<u32>,
+  /// UDP socket recv buffer size (SO_RCVBUF)
+  #[arg(long)]
+  recv_buffer_size: Option<u32>,
+  /// IP TOS byte (IPv4) or traffic class (IPv6)
+  #[arg(long)]
+  ip_tos: Option<u8>,
+  /// IPV6_V6ONLY of IPv6 sockets
+  #[arg(long)]
+  ipv6_only: Option<bool>,
+  /// Address family of resolved host names: any, prefer_ipv4, prefer_ipv6,
+  /// ipv4_only or ipv6_only
+  #[arg(long, value_parser = parse_enum::<IpPreference>)]
+  ip_preference: Option<IpPreference>,
+  /// Upload limit in bytes per second, 0 means unlimited
+  #[arg(long)]
+  rate_limit_bytes_per_sec: Option<u64>,
+  /// Bytes that may be sent at once after idling
+  #[arg(long)]
+  rate_limit_burst: Option<u64>,
+  /// Adapt the upload rate to kcp's send queue
+  #[arg(long)]
+  adaptive_pacing: Option<bool>,
+  /// Lower bound of the adaptive rate in bytes per second
+  #[arg(long)]
+  adaptive_min_bytes_per_sec: Option<u64>,
+  /// Upper bound of the adaptive rate in bytes per second
+  #[arg(long)]
+  adaptive_max_bytes_per_sec: Option<u64>,
+  /// Probe the path and lower the mtu before connecting
+  #[arg(long)]
+  path_mtu_discovery: Option<bool>,
+  /// Relay the datagrams in-process so that they can be captured
+  #[arg(long)]
+  capturable: Option<bool>,
+  /// Report idle streams after this long without reads or writes
+  #[arg(long)]
+  idle_timeout_milisec: Option<u32>,
+  /// Simulate a bad network, e.g. '{"loss_percent": 5, "latency_millisec":
+  /// 50}'. Only with the impairment feature
+  #[cfg(feature = "impairment")]
+  #[arg(long, value_name = "JSON", value_parser = parse_impairment)]
+  impairment: Option<ImpairmentParams>,
+}
+
+impl ConfigArgs {
+  pub fn params(self) -> Result<KcpConfigParams> {
+    let mut params = match &self.preset {
+      Some(name) => bindings::kcp_config_preset(name.clone())?,
+      None => bindings::default_kcp_config_params(),
+    };
+
+    if let Some(path) = &self.config {
+      let text =
+        std::fs::read_to_string(path).with_context(|| format!("can't read {}", path.display()))?;
+      let file = match path.extension().is_some_and(|ext| ext == "toml") {
+        true => bindings::kcp_config_from_toml(text)?,
+        false => bindings::kcp_config_from_json(text)?,
+      };
+      params = params.with_overrides(file);
+    }
+
+    let flags = KcpConfigParams {
+      mtu: self.mtu,
+      nodelay: self.nodelay,
+      nodelay_interval: self.nodelay_interval,
+      nodelay_resend: self.nodelay_resend,
+      nodelay_nc: self.nodelay_nc,
+      window_size_send: self.window_size_send,
+      window_size_recv: self.window_size_recv,
+      session_expire_milisec: self.session_expire_milisec,
+      flush_write: self.flush_write,
+      flush_acks_input: self.flush_acks_input,
+      stream: self.stream,
+      compression: self.compression,
+      reuse_address: self.reuse_address,
+      reuse_port: self.reuse_port,
+      send_buffer_size: self.send_buffer_size,
+      recv_buffer_size: self.recv_buffer_size,
+      ip_tos: self.ip_tos,
+      ipv6_only: self.ipv6_only,
+      ip_preference: self.ip_preference,
+      rate_limit_bytes_per_sec: self.rate_limit_bytes_per_sec,
+      rate_limit_burst: self.rate_limit_burst,
+      adaptive_pacing: self.adaptive_pacing,
+      adaptive_min_bytes_per_sec: self.adaptive_min_bytes_per_sec,
+      adaptive_max_bytes_per_sec: self.adaptive_max_bytes_per_sec,
+      path_mtu_discovery: self.path_mtu_discovery,
+      capturable: self.capturable,
+      idle_timeout_milisec: self.idle_timeout_milisec,
+      #[cfg(feature = "impairment")]
+      impairment: self.impairment,
+      #[cfg(not(feature = "impairment"))]
+      impairment: None,
+    };
+    let params = params.with_overrides(flags);
+    bindings::validate_kcp_config_params(params.clone())?;
+
+    Ok(params)
+  }
+}
@@ -0,0 +1,201 @@
+use anyhow::{bail, Result};
+use bindings::{KcpConfigParams, StreamId};
+use clap::Args;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+use tokio::time::{self, MissedTickBehavior};
+
+// A message starts with its sequence number and send time.
+const HEADER_LEN: usize = 16;
+
+#[derive(Args, Debug)]
+pub struct LoadArgs {
+  /// Concurrent sessions
+  #[arg(long, default_value_t = 10)]
+  pub sessions: u32,
+  /// Messages per second of each session
+  #[arg(long, value_name = "PER_SEC", default_value_t = 10.0)]
+  pub rate: f64,
+  /// Message size in bytes, at least 16
+  #[arg(long, value_name = "BYTES", default_value_t = 64)]
+  pub size: usize,
+  /// How long each session sends
+  #[arg(long, value_name = "SECS", default_value_t = 10)]
+  pub duration: u64,
+  /// How long to wait for echoes after the last message, the ones still
+  /// missing then are counted as lost
+  #[arg(long, value_name = "MILLISEC", default_value_t = 2000)]
+  pub grace: u64,
+}
+
+#[derive(Default)]
+struct Results {
+  sent: u64,
+  errors: u64,
+  // Of every echoed message.
+  latencies: Vec<Duration>,
+}
+
+fn message(seq: u64, sent_micros: u64, size: usize) -> Vec<u8> {
+  let mut data = vec![0u8; size];
+  data[..8].copy_from_slice(&seq.to_le_bytes());
+  data[8..16].copy_from_slice(&sent_micros.to_le_bytes());
+  data
+}
+
+fn sent_micros(message: &[u8]) -> u64 {
+  u64::from_le_bytes(message[8..16].try_into().unwrap())
+}
+
+/// The value at `percent` of `sorted`.
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+  if sorted.is_empty() {
+    return Duration::ZERO;
+  }
+  let index = ((sorted.len() - 1) as f64 * percent / 100.0).round() as usize;
+  sorted[index]
+}
+
+// Sends `count` messages at `args.rate` and reads their echoes until
+// `deadline`.
+async fn session(
+  id: StreamId,
+  args: &LoadArgs,
+  count: u64,
+  start: Instant,
+  deadline: Instant,
+  results: &RefCell<Results>,
+) {
+  let write = async {
+    let mut interval = time::interval(Duration::from_secs_f64(1.0 / args.rate));
+    // Late ticks are sent in a burst, so the rate holds on average.
+    interval.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    for seq in 0..count {
+      interval.tick().await;
+      let data = message(seq, start.elapsed().as_micros() as u64, args.size);
+      match bindings::write_stream(id, data).await {
+        Ok(()) => results.borrow_mut().sent += 1,
+        Err(_) => {
+          results.borrow_mut().errors += 1;
+          return;
+        }
+      }
+    }
+  };
+
+  let read = async {
+    for _ in 0..count {
+      let echo = bindings::read_exact_stream(id, args.size as u32);
+      match time::timeout_at(deadline.into(), echo).await {
+        Ok(Ok(echo)) => {
+          let sent = Duration::from_micros(sent_micros(&echo));
+          let latency = start.elapsed().saturating_sub(sent);
+          results.borrow_mut().latencies.push(latency);
+        }
+        Ok(Err(_)) => {
+          results.borrow_mut().errors += 1;
+          return;
+        }
+        Err(_) => return,
+      }
+    }
+  };
+
+  futures::join!(write, read);
+}
+
+/// Runs sessions against the echo server at `addr` and prints a report.
+pub fn run(addr: String, params: KcpConfigParams, args: LoadArgs) -> Result<()> {
+  if args.size < HEADER_LEN {
+    bail!("--size must be at least {}", HEADER_LEN);
+  }
+  if args.rate <= 0.0 {
+    bail!("--rate must be positive");
+  }
+  if args.duration == 0 {
+    bail!("--duration must be positive");
+  }
+
+  let rt = tokio::runtime::Builder::new_current_thread()
+    .enable_time()
+    .build()?;
+  let count = (args.rate * args.duration as f64).round() as u64;
+  let results = RefCell::new(Results::default());
+
+  rt.block_on(async {
+    let mut ids = Vec::new();
+    for _ in 0..args.sessions {
+      match bindings::new_stream(addr.clone(), params.clone()).await {
+        Ok(id) => ids.push(id),
+        Err(e) => {
+          eprintln!("[kcpcat] can't connect: {}", e);
+          results.borrow_mut().errors += 1;
+        }
+      }
+    }
+    eprintln!(
+      "[kcpcat] {} sessions sending {} messages of {} bytes each",
+      ids.len(),
+      count,
+      args.size
+    );
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.duration) + Duration::from_millis(args.grace);
+    let sessions = ids
+      .iter()
+      .map(|id| session(*id, &args, count, start, deadline, &results));
+    futures::future::join_all(sessions).await;
+
+    for id in ids {
+      let _ = bindings::remove_stream(id).await;
+    }
+  });
+
+  let mut results = results.into_inner();
+  results.latencies.sort();
+  let echoed = results.latencies.len() as u64;
+  let lost = results.sent - echoed;
+  let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+  println!(
+    "messages: sent {}, echoed {}, lost {} ({:.2}%), errors {}",
+    results.sent,
+    echoed,
+    lost,
+    match results.sent {
+      0 => 0.0,
+      sent => lost as f64 * 100.0 / sent as f64,
+    },
+    results.errors
+  );
+  println!(
+    "latency ms: min {:.2}, p50 {:.2}, p90 {:.2}, p99 {:.2}, max {:.2}",
+    ms(percentile(&results.latencies, 0.0)),
+    ms(percentile(&results.latencies, 50.0)),
+    ms(percentile(&results.latencies, 90.0)),
+    ms(percentile(&results.latencies, 99.0)),
+    ms(percentile(&results.latencies, 100.0)),
+  );
+  println!(
+    "throughput: {:.0} messages/s, {:.0} B/s echoed",
+    echoed as f64 / args.duration as f64,
+    (echoed * args.size as u64) as f64 / args.duration as f64
+  );
+
+  Ok(())
+}
+
+#[test]
+fn test_message_and_percentile() {
+  let data = message(7, 1234, 64);
+  assert_eq!(data.len(), 64);
+  assert_eq!(sent_micros(&data), 1234);
+
+  let sorted: Vec<_> = (1..=100).map(Duration::from_millis).collect();
+  assert_eq!(percentile(&sorted, 0.0), Duration::from_millis(1));
+  assert_eq!(percentile(&sorted, 50.0), Duration::from_millis(51));
+  assert_eq!(percentile(&sorted, 99.0), Duration::from_millis(99));
+  assert_eq!(percentile(&sorted, 100.0), Duration::from_millis(100));
+  assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+}
@@ -0,0 +1,231 @@
+mod config;
+mod load;
+mod serve;
+
+use anyhow::Result;
+use bindings::StreamId;
+use clap::{Args, Parser, Subcommand};
+use config::ConfigArgs;
+use futures::executor::block_on;
+use load::LoadArgs;
+use serve::ServeMode;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const STDIN_BUF: usize = 64 * 1024;
+
+/// Talks KCP over stdin and stdout, like netcat.
+#[derive(Parser, Debug)]
+#[command(name = "kcpcat", version)]
+struct Cli {
+  #[command(subcommand)]
+  command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+  /// Connect to a kcp server
+  Connect {
+    /// Server address, e.g. 127.0.0.1:3100 or example.com:3100
+    addr: String,
+    #[command(flatten)]
+    options: Options,
+    #[command(flatten)]
+    config: ConfigArgs,
+  },
+  /// Accept one session, or serve every session with --serve
+  Listen {
+    /// Local address, e.g. 0.0.0.0:3100
+    addr: String,
+    /// Serve every session instead of piping one, for smoke and load tests
+    #[arg(long, value_enum, value_name = "MODE")]
+    serve: Option<ServeMode>,
+    #[command(flatten)]
+    options: Options,
+    #[command(flatten)]
+    config: ConfigArgs,
+  },
+  /// Open many sessions to an echo server and report latency and loss
+  Load {
+    /// Address of an echo server, e.g. one started by `listen --serve echo`.
+    /// An echo server on localhost is started if unset
+    addr: Option<String>,
+    #[command(flatten)]
+    load: LoadArgs,
+    #[command(flatten)]
+    config: ConfigArgs,
+  },
+}
+
+#[derive(Args, Debug)]
+struct Options {
+  /// Print transfer statistics to stderr every this many seconds
+  #[arg(long, value_name = "SECS")]
+  stats: Option<u64>,
+  /// Keep reading this long after stdin is closed. Kcp has no half close, so
+  /// replies in flight would be lost otherwise
+  #[arg(long, value_name = "MILLISEC", default_value_t = 1000)]
+  linger: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+  sent: AtomicU64,
+  received: AtomicU64,
+  sessions: AtomicU64,
+}
+
+// Prints what was transferred since the last line.
+fn print_stats(counters: Arc<Counters>, interval: Duration) {
+  thread::spawn(move || {
+    let (mut sent, mut received) = (0, 0);
+    let mut last = Instant::now();
+    loop {
+      thread::sleep(interval);
+      let secs = last.elapsed().as_secs_f64();
+      last = Instant::now();
+      let (total_sent, total_received) = (
+        counters.sent.load(Ordering::Relaxed),
+        counters.received.load(Ordering::Relaxed),
+      );
+      eprintln!(
+        "[kcpcat] sent {} B ({:.0} B/s), received {} B ({:.0} B/s), sessions {}",
+        total_sent,
+        (total_sent - sent) as f64 / secs,
+        total_received,
+        (total_received - received) as f64 / secs,
+        counters.sessions.load(Ordering::Relaxed),
+      );
+      (sent, received) = (total_sent, total_received);
+    }
+  });
+}
+
+enum PipeEvent {
+  StdinClosed(Result<()>),
+  ReadEnded(Result<()>),
+}
+
+// Copies stdin to the stream and the stream to stdout until the session ends,
+// or `linger` after stdin is closed.
+fn pipe(id: StreamId, counters: Arc<Counters>, linger: Duration) -> Result<()> {
+  let (tx, rx) = mpsc::channel();
+
+  {
+    let (tx, counters) = (tx.clone(), counters.clone());
+    thread::spawn(move || {
+      let copy = || -> Result<()> {
+        let mut stdout = io::stdout().lock();
+        loop {
+          let data = block_on(bindings::read_stream(id))?;
+          if data.is_empty() {
+            return Ok(());
+          }
+          counters
+            .received
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+          stdout.write_all(&data)?;
+          stdout.flush()?;
+        }
+      };
+      let _ = tx.send(PipeEvent::ReadEnded(copy()));
+    });
+  }
+
+  thread::spawn(move || {
+    let copy = || -> Result<()> {
+      let mut stdin = io::stdin().lock();
+      let mut buf = vec![0u8; STDIN_BUF];
+      loop {
+        let n = stdin.read(&mut buf)?;
+        if n == 0 {
+          return Ok(block_on(bindings::flush_stream(id))?);
+        }
+        block_on(bindings::write_stream(id, buf[..n].to_vec()))?;
+        counters.sent.fetch_add(n as u64, Ordering::Relaxed);
+      }
+    };
+    let _ = tx.send(PipeEvent::StdinClosed(copy()));
+  });
+
+  match rx.recv()? {
+    PipeEvent::ReadEnded(ret) => ret,
+    PipeEvent::StdinClosed(Err(e)) => Err(e),
+    PipeEvent::StdinClosed(Ok(())) => match rx.recv_timeout(linger) {
+      Ok(PipeEvent::ReadEnded(ret)) => ret,
+      _ => Ok(()),
+    },
+  }
+}
+
+fn run(command: Command) -> Result<()> {
+  let counters = Arc::new(Counters::default());
+
+  match command {
+    Command::Connect {
+      addr,
+      options,
+      config,
+    } => {
+      let params = config.params()?;
+      if let Some(secs) = options.stats {
+        print_stats(counters.clone(), Duration::from_secs(secs));
+      }
+
+      let id = block_on(bindings::new_stream(addr.clone(), params))?;
+      eprintln!("[kcpcat] connected to {}", addr);
+      counters.sessions.fetch_add(1, Ordering::Relaxed);
+      pipe(id, counters, Duration::from_millis(options.linger))
+    }
+    Command::Listen {
+      addr,
+      serve,
+      options,
+      config,
+    } => {
+      let params = config.params()?;
+      if let Some(secs) = options.stats {
+        print_stats(counters.clone(), Duration::from_secs(secs));
+      }
+
+      let listener = block_on(bindings::new_listener(addr, params))?;
+      eprintln!(
+        "[kcpcat] listening on {}",
+        block_on(bindings::local_addr(listener))?
+      );
+
+      if let Some(mode) = serve {
+        return serve::serve(listener, mode, counters);
+      }
+      let accepted = block_on(bindings::accepet(listener))?;
+      eprintln!("[kcpcat] accepted {}", accepted.addr);
+      counters.sessions.fetch_add(1, Ordering::Relaxed);
+      pipe(accepted.id, counters, Duration::from_millis(options.linger))
+    }
+    Command::Load { addr, load, config } => {
+      let params = config.params()?;
+      let addr = match addr {
+        Some(addr) => addr,
+        None => {
+          let listener = block_on(bindings::new_listener(
+            "127.0.0.1:0".to_string(),
+            params.clone(),
+          ))?;
+          thread::spawn(move || serve::serve(listener, ServeMode::Echo, counters));
+          block_on(bindings::local_addr(listener))?
+        }
+      };
+      load::run(addr, params, load)
+    }
+  }
+}
+
+fn main() -> Result<()> {
+  let cli = Cli::parse();
+  block_on(bindings::init_runtime())?;
+
+  run(cli.command)
+}
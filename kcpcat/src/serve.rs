@@ -0,0 +1,110 @@
+use crate::Counters;
+use anyhow::Result;
+use bindings::StreamId;
+use clap::ValueEnum;
+use futures::executor::block_on;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+
+// RFC 864 sends lines of 72 printable characters, each starting one later.
+const CHARGEN_LINE: usize = 72;
+const CHARGEN_CHARS: u8 = 95;
+// Lines per write.
+const CHARGEN_LINES: usize = 32;
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServeMode {
+  /// Send everything received back to its sender
+  Echo,
+  /// Read and drop everything
+  Discard,
+  /// Send lines of characters as fast as the session allows. Like any kcp
+  /// session, it starts once the client sent something
+  Chargen,
+}
+
+fn echo(id: StreamId, counters: &Counters) -> Result<()> {
+  loop {
+    let data = block_on(bindings::read_stream(id))?;
+    if data.is_empty() {
+      return Ok(());
+    }
+    let n = data.len() as u64;
+    counters.received.fetch_add(n, Ordering::Relaxed);
+    block_on(bindings::write_stream(id, data))?;
+    counters.sent.fetch_add(n, Ordering::Relaxed);
+  }
+}
+
+fn discard(id: StreamId, counters: &Counters) -> Result<()> {
+  loop {
+    let data = block_on(bindings::read_stream(id))?;
+    if data.is_empty() {
+      return Ok(());
+    }
+    counters
+      .received
+      .fetch_add(data.len() as u64, Ordering::Relaxed);
+  }
+}
+
+fn chargen_lines(first: usize) -> Vec<u8> {
+  let mut data = Vec::with_capacity((CHARGEN_LINE + 2) * CHARGEN_LINES);
+  for line in first..first + CHARGEN_LINES {
+    for i in line..line + CHARGEN_LINE {
+      data.push(b' ' + (i % CHARGEN_CHARS as usize) as u8);
+    }
+    data.extend_from_slice(b"\r\n");
+  }
+  data
+}
+
+// Writes until the peer goes away, a write then fails or the session expires.
+fn chargen(id: StreamId, counters: &Counters) -> Result<()> {
+  let mut first = 0;
+  loop {
+    let data = chargen_lines(first);
+    let n = data.len() as u64;
+    block_on(bindings::write_stream(id, data))?;
+    counters.sent.fetch_add(n, Ordering::Relaxed);
+    first = (first + CHARGEN_LINES) % CHARGEN_CHARS as usize;
+  }
+}
+
+/// Serves every session accepted by `listener` on a thread of its own, until
+/// accepting fails.
+pub fn serve(listener: StreamId, mode: ServeMode, counters: Arc<Counters>) -> Result<()> {
+  loop {
+    let accepted = block_on(bindings::accepet(listener))?;
+    eprintln!("[kcpcat] accepted {}", accepted.addr);
+    counters.sessions.fetch_add(1, Ordering::Relaxed);
+
+    let counters = counters.clone();
+    thread::spawn(move || {
+      let ret = match mode {
+        ServeMode::Echo => echo(accepted.id, &counters),
+        ServeMode::Discard => discard(accepted.id, &counters),
+        ServeMode::Chargen => chargen(accepted.id, &counters),
+      };
+      if let Err(e) = ret {
+        eprintln!("[kcpcat] {}: {}", accepted.addr, e);
+      }
+      let _ = block_on(bindings::remove_stream(accepted.id));
+      counters.sessions.fetch_sub(1, Ordering::Relaxed);
+      eprintln!("[kcpcat] closed {}", accepted.addr);
+    });
+  }
+}
+
+#[test]
+fn test_chargen_lines() {
+  let data = chargen_lines(0);
+  let lines: Vec<_> = data.split(|b| *b == b'\n').collect();
+  assert_eq!(&lines[0][..3], b" !\"");
+  assert_eq!(&lines[1][..3], b"!\"#");
+  assert_eq!(lines[0].len(), CHARGEN_LINE + 1);
+
+  // Line 94 wraps from '~' back to ' '.
+  assert_eq!(&chargen_lines(94)[..2], b"~ ");
+}
@@ -69,6 +69,12 @@ public class KcpStream {
         modifyFastestConfig(&config)
     }
 
+    // Apply a named preset, e.g. "fast3". Fields already set in `config` take
+    // precedence over the preset.
+    public func setPreset(_ name: String) throws {
+        config = try kcpConfigPresetWithOverrides(name: name, overrides: config)
+    }
+
     // Create tokio kcp stream.
     // `connect()` should be invoked only once or and error will be thrown.
     public func connect() async throws {
@@ -149,6 +155,12 @@ public class KcpListener {
         modifyFastestConfig(&config)
     }
 
+    // Apply a named preset, e.g. "fast3". Fields already set in `config` take
+    // precedence over the preset.
+    public func setPreset(_ name: String) throws {
+        config = try kcpConfigPresetWithOverrides(name: name, overrides: config)
+    }
+
     // Bind the listener.
     public func bind() async throws {
         if listenerId != nil {